@@ -0,0 +1,81 @@
+use bevy::prelude::*;
+use bevy_aabb_instancing::{Cuboid, CuboidMaterial, Cuboids, VertexPullingRenderPlugin};
+use smooth_bevy_cameras::{controllers::fps::*, LookTransformPlugin};
+
+/// A city of small blocks, one entity per block, that all share a material.
+///
+/// With batching, the instances of all blocks are drawn with a handful of draw
+/// calls instead of one per entity.
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .insert_resource(Msaa::Off)
+        .add_plugins((
+            VertexPullingRenderPlugin {
+                outlines: true,
+                batching: true,
+                ..default()
+            },
+            LookTransformPlugin,
+            FpsCameraPlugin::default(),
+        ))
+        .add_systems(Startup, setup)
+        .add_systems(Update, toggle_fps_controller)
+        .run();
+}
+
+fn setup(mut commands: Commands, mut materials: ResMut<Assets<CuboidMaterial>>) {
+    let material = materials.add(CuboidMaterial::default());
+
+    const BLOCKS_PER_DIM: usize = 100;
+    const BUILDINGS_PER_DIM: usize = 4;
+    const BLOCK_SIZE: f32 = 20.0;
+    const BUILDING_SIZE: f32 = 4.0;
+
+    let half_city = 0.5 * BLOCKS_PER_DIM as f32 * BLOCK_SIZE;
+    for x_block in 0..BLOCKS_PER_DIM {
+        for z_block in 0..BLOCKS_PER_DIM {
+            let mut instances = Vec::with_capacity(BUILDINGS_PER_DIM * BUILDINGS_PER_DIM);
+            for x in 0..BUILDINGS_PER_DIM {
+                for z in 0..BUILDINGS_PER_DIM {
+                    let x = x_block as f32 * BLOCK_SIZE + x as f32 * BUILDING_SIZE - half_city;
+                    let z = z_block as f32 * BLOCK_SIZE + z as f32 * BUILDING_SIZE - half_city;
+                    let d = (x * x + z * z).sqrt() / half_city;
+                    let noise = 0.5 + 0.5 * (0.37 * x).sin() * (0.53 * z).cos();
+                    let height = 2.0 + 60.0 * (1.0 - d).max(0.0) * noise;
+                    let min = Vec3::new(x, 0.0, z);
+                    let max = min + Vec3::new(0.8 * BUILDING_SIZE, height, 0.8 * BUILDING_SIZE);
+                    let color = Color::hsl(200.0 + 40.0 * noise, 0.3, 0.3 + 0.4 * noise);
+                    instances.push(Cuboid::new(min, max, color.as_rgba_u32()));
+                }
+            }
+            let cuboids = Cuboids::new(instances);
+            let aabb = cuboids.aabb();
+            commands
+                .spawn(SpatialBundle::default())
+                .insert((cuboids, aabb, material.clone()));
+        }
+    }
+
+    commands
+        .spawn(Camera3dBundle::default())
+        .insert(FpsCameraBundle::new(
+            FpsCameraController {
+                translate_sensitivity: 200.0,
+                enabled: false,
+                ..Default::default()
+            },
+            Vec3::new(0.0, 150.0, -half_city),
+            Vec3::ZERO,
+            Vec3::Y,
+        ));
+}
+
+fn toggle_fps_controller(
+    mouse_button_input: Res<Input<MouseButton>>,
+    mut controller: Query<&mut FpsCameraController>,
+) {
+    if mouse_button_input.just_pressed(MouseButton::Left) {
+        controller.single_mut().enabled = true;
+    }
+}
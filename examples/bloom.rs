@@ -6,7 +6,7 @@ use bevy::{
     input::mouse,
     prelude::*,
 };
-use bevy_aabb_instancing::{Cuboid, CuboidMaterialId, Cuboids, VertexPullingRenderPlugin};
+use bevy_aabb_instancing::{Cuboid, CuboidMaterial, Cuboids, VertexPullingRenderPlugin};
 use smooth_bevy_cameras::{controllers::fps::*, LookTransformPlugin};
 
 fn main() {
@@ -15,7 +15,10 @@ fn main() {
         .insert_resource(ClearColor(Color::BLACK))
         .insert_resource(Msaa::Off)
         .add_plugins((
-            VertexPullingRenderPlugin { outlines: true },
+            VertexPullingRenderPlugin {
+                outlines: true,
+                ..default()
+            },
             LookTransformPlugin,
             FpsCameraPlugin::default(),
         ))
@@ -49,9 +52,11 @@ fn setup(mut commands: Commands) {
 
     let cuboids = Cuboids::new(cuboids);
     let aabb = cuboids.aabb();
-    commands
-        .spawn(SpatialBundle::default())
-        .insert((cuboids, aabb, CuboidMaterialId(0)));
+    commands.spawn(SpatialBundle::default()).insert((
+        cuboids,
+        aabb,
+        Handle::<CuboidMaterial>::default(),
+    ));
 
     commands
         .spawn((
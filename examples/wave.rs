@@ -1,7 +1,6 @@
 use bevy::prelude::*;
 use bevy_aabb_instancing::{
-    Cuboid, CuboidMaterial, CuboidMaterialId, CuboidMaterialMap, Cuboids,
-    VertexPullingRenderPlugin, COLOR_MODE_SCALAR_HUE,
+    Cuboid, CuboidMaterial, Cuboids, VertexPullingRenderPlugin, COLOR_MODE_SCALAR_HUE,
 };
 use smooth_bevy_cameras::{controllers::fps::*, LookTransformPlugin};
 
@@ -10,7 +9,10 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .insert_resource(Msaa::Off)
         .add_plugins((
-            VertexPullingRenderPlugin { outlines: true },
+            VertexPullingRenderPlugin {
+                outlines: true,
+                ..default()
+            },
             LookTransformPlugin,
             FpsCameraPlugin::default(),
         ))
@@ -19,8 +21,11 @@ fn main() {
         .run();
 }
 
-fn setup(mut commands: Commands, mut material_map: ResMut<CuboidMaterialMap>) {
-    let material_id = material_map.push(CuboidMaterial {
+#[derive(Resource)]
+struct WaveMaterial(Handle<CuboidMaterial>);
+
+fn setup(mut commands: Commands, mut materials: ResMut<Assets<CuboidMaterial>>) {
+    let material = materials.add(CuboidMaterial {
         color_mode: COLOR_MODE_SCALAR_HUE,
         ..default()
     });
@@ -53,7 +58,7 @@ fn setup(mut commands: Commands, mut material_map: ResMut<CuboidMaterialMap>) {
             let aabb = cuboids.aabb();
             commands
                 .spawn(SpatialBundle::default())
-                .insert((cuboids, aabb, material_id));
+                .insert((cuboids, aabb, material.clone()));
         }
     }
 
@@ -69,10 +74,16 @@ fn setup(mut commands: Commands, mut material_map: ResMut<CuboidMaterialMap>) {
             Vec3::new(100.0, 0.0, 100.0),
             Vec3::Y,
         ));
+
+    commands.insert_resource(WaveMaterial(material));
 }
 
-fn update_scalar_hue_options(time: Res<Time>, mut material_map: ResMut<CuboidMaterialMap>) {
-    let material = material_map.get_mut(CuboidMaterialId(1));
+fn update_scalar_hue_options(
+    time: Res<Time>,
+    wave_material: Res<WaveMaterial>,
+    mut materials: ResMut<Assets<CuboidMaterial>>,
+) {
+    let material = materials.get_mut(&wave_material.0).unwrap();
     let tv = 1000.0 * (time.elapsed_seconds().sin() + 1.0);
     material.scalar_hue.max_visible = tv;
     material.scalar_hue.clamp_max = tv;
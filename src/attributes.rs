@@ -0,0 +1,111 @@
+use bevy::prelude::*;
+
+/// Index of an attribute in [`CuboidAttributes`].
+pub type AttributeIndex = u32;
+
+/// Selects `cuboid.color` instead of an attribute, see
+/// [`CuboidMaterial::color_attribute`](crate::CuboidMaterial::color_attribute)
+/// and
+/// [`CuboidMaterial::visibility_attribute`](crate::CuboidMaterial::visibility_attribute).
+pub const ATTRIBUTE_NONE: AttributeIndex = u32::MAX;
+
+/// Scalar attributes of each instance of the [`Cuboids`](crate::Cuboids) or
+/// [`OrientedCuboids`](crate::OrientedCuboids) on the same entity, e.g. the
+/// grade, density and rock type of each block of a block model.
+///
+/// The attributes are uploaded to a storage buffer next to the instances, and
+/// the entity's [`CuboidMaterial`](crate::CuboidMaterial) selects which of
+/// them colors the cuboids and which clips them. Switching attributes only
+/// takes a material change, while any change to this component re-uploads all
+/// attributes.
+///
+/// The attributes are ignored unless there are `num_attributes` values for
+/// every instance. Entities with attributes are neither batched nor GPU culled,
+/// and their coarser levels of detail are colored by `cuboid.color`.
+#[derive(Clone, Component, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidAttributes {
+    num_attributes: u32,
+    /// `num_attributes` values per instance, instance by instance.
+    values: Vec<f32>,
+}
+
+impl CuboidAttributes {
+    /// Creates attributes from `num_attributes` values per instance, stored
+    /// instance by instance.
+    ///
+    /// # Panics
+    ///
+    /// If the length of `values` isn't a multiple of `num_attributes`.
+    pub fn new(num_attributes: u32, values: Vec<f32>) -> Self {
+        let fits = match num_attributes {
+            0 => values.is_empty(),
+            n => values.chunks_exact(n as usize).remainder().is_empty(),
+        };
+        assert!(
+            fits,
+            "{} values don't fit {num_attributes} attributes per instance",
+            values.len()
+        );
+        Self {
+            num_attributes,
+            values,
+        }
+    }
+
+    /// Creates attributes from one column of values per attribute.
+    ///
+    /// # Panics
+    ///
+    /// If the columns have different lengths.
+    pub fn from_columns(columns: &[&[f32]]) -> Self {
+        let num_instances = columns.first().map_or(0, |column| column.len());
+        assert!(
+            columns.iter().all(|column| column.len() == num_instances),
+            "attribute columns have different lengths"
+        );
+        let values = (0..num_instances)
+            .flat_map(|i| columns.iter().map(move |column| column[i]))
+            .collect();
+        Self::new(columns.len() as u32, values)
+    }
+
+    pub fn num_attributes(&self) -> u32 {
+        self.num_attributes
+    }
+
+    pub fn num_instances(&self) -> usize {
+        match self.num_attributes {
+            0 => 0,
+            n => self.values.len() / n as usize,
+        }
+    }
+
+    /// All values, instance by instance.
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Mutably borrow all values, instance by instance.
+    pub fn values_mut(&mut self) -> &mut [f32] {
+        &mut self.values
+    }
+
+    /// The attributes of a single instance.
+    pub fn instance(&self, index: usize) -> &[f32] {
+        let n = self.num_attributes as usize;
+        &self.values[index * n..(index + 1) * n]
+    }
+
+    /// Mutably borrow the attributes of a single instance.
+    pub fn instance_mut(&mut self, index: usize) -> &mut [f32] {
+        let n = self.num_attributes as usize;
+        &mut self.values[index * n..(index + 1) * n]
+    }
+
+    /// Returns `true` if there are attributes for exactly `num_instances`
+    /// instances.
+    pub(crate) fn fits(&self, num_instances: usize) -> bool {
+        self.num_attributes > 0 && self.values.len() == num_instances * self.num_attributes as usize
+    }
+}
@@ -0,0 +1,485 @@
+use crate::picking::{ray_aabb_intersection, ray_cuboid_entry};
+use crate::{Cuboid, Cuboids};
+
+use bevy::{ecs::component::Tick, math::Ray, prelude::*};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// A bounding volume hierarchy over the instances of the [`Cuboids`] on the
+/// same entity.
+///
+/// Insert `CuboidsBvh::default()` next to a [`Cuboids`] component to opt in.
+/// The hierarchy is automatically rebuilt when the number of instances changes,
+/// and refit to the new bounds when instances are modified in place. This
+/// happens in [`PostUpdate`], so until then a hierarchy can be behind edits
+/// made earlier in the frame; see [`Self::is_up_to_date`].
+///
+/// All queries are performed in the entity's local space, and they must be
+/// given the same [`Cuboids`] that the hierarchy was built from.
+#[derive(Clone, Component, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsBvh {
+    nodes: Vec<BvhNode>,
+    /// Instance indices, ordered such that every leaf covers a contiguous range.
+    indices: Vec<u32>,
+    /// The last change of the [`Cuboids`] that the hierarchy was built or refit
+    /// from, if it was built by [`update_cuboids_bvh`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    built_from: Option<Tick>,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct BvhNode {
+    min: Vec3,
+    max: Vec3,
+    /// For leaves, the start of the leaf's range in `indices`. For internal
+    /// nodes, the index of the right child (the left child immediately follows
+    /// its parent).
+    offset: u32,
+    /// Number of instances in a leaf, or 0 for internal nodes.
+    count: u32,
+}
+
+impl BvhNode {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    fn leaf_range(&self) -> std::ops::Range<usize> {
+        self.offset as usize..(self.offset + self.count) as usize
+    }
+
+    fn distance_squared_to(&self, point: Vec3) -> f32 {
+        aabb_distance_squared(self.min, self.max, point)
+    }
+}
+
+impl CuboidsBvh {
+    /// Maximum number of instances in a leaf.
+    const LEAF_SIZE: usize = 4;
+
+    pub fn new(cuboids: &Cuboids) -> Self {
+        let mut bvh = Self::default();
+        bvh.rebuild(cuboids);
+        bvh
+    }
+
+    /// The number of instances covered by the hierarchy.
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Returns `true` if the hierarchy covers the latest bounds of `cuboids`.
+    ///
+    /// This is `false` from the moment `cuboids` are changed until the
+    /// hierarchy is updated in [`PostUpdate`], and after every manual
+    /// [`Self::rebuild`] or [`Self::refit`]. [`CuboidsRaycast`](crate::CuboidsRaycast)
+    /// tests every instance of an out of date hierarchy instead.
+    pub fn is_up_to_date(&self, cuboids: &Ref<Cuboids>) -> bool {
+        self.built_from == Some(cuboids.last_changed())
+    }
+
+    /// Builds a new hierarchy from scratch by recursively splitting instances
+    /// at the median centroid along the longest axis.
+    pub fn rebuild(&mut self, cuboids: &Cuboids) {
+        self.built_from = None;
+        self.nodes.clear();
+        self.indices = (0..cuboids.instances().len() as u32).collect();
+        if self.indices.is_empty() {
+            return;
+        }
+        self.nodes.reserve(2 * self.indices.len() / Self::LEAF_SIZE);
+        let num_indices = self.indices.len();
+        self.build_node(cuboids.instances(), 0, num_indices);
+    }
+
+    fn build_node(&mut self, instances: &[Cuboid], start: usize, end: usize) -> usize {
+        let node_index = self.nodes.len();
+        let indices = &mut self.indices[start..end];
+
+        let (mut min, mut max) = (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN));
+        let (mut centroid_min, mut centroid_max) = (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN));
+        for &i in indices.iter() {
+            let cuboid = &instances[i as usize];
+            min = min.min(cuboid.minimum);
+            max = max.max(cuboid.maximum);
+            centroid_min = centroid_min.min(cuboid.center());
+            centroid_max = centroid_max.max(cuboid.center());
+        }
+        self.nodes.push(BvhNode {
+            min,
+            max,
+            offset: start as u32,
+            count: indices.len() as u32,
+        });
+
+        if indices.len() <= Self::LEAF_SIZE {
+            return node_index;
+        }
+
+        let extents = centroid_max - centroid_min;
+        let axis = if extents.x >= extents.y && extents.x >= extents.z {
+            0
+        } else if extents.y >= extents.z {
+            1
+        } else {
+            2
+        };
+        let mid = indices.len() / 2;
+        indices.select_nth_unstable_by(mid, |&a, &b| {
+            let a = instances[a as usize].center()[axis];
+            let b = instances[b as usize].center()[axis];
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        });
+
+        self.build_node(instances, start, start + mid);
+        let right = self.build_node(instances, start + mid, end);
+        let node = &mut self.nodes[node_index];
+        node.offset = right as u32;
+        node.count = 0;
+        node_index
+    }
+
+    /// Recomputes the bounds of every node without changing the tree
+    /// structure.
+    ///
+    /// This is much cheaper than [`CuboidsBvh::rebuild`], but query performance
+    /// degrades if instances move far from their original positions.
+    pub fn refit(&mut self, cuboids: &Cuboids) {
+        assert_eq!(self.indices.len(), cuboids.instances().len());
+        self.built_from = None;
+
+        // Children are always stored after their parent.
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_index];
+            let (min, max) = if node.is_leaf() {
+                self.indices[node.leaf_range()].iter().fold(
+                    (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                    |(min, max), &i| {
+                        let cuboid = &cuboids.instances()[i as usize];
+                        (min.min(cuboid.minimum), max.max(cuboid.maximum))
+                    },
+                )
+            } else {
+                let left = &self.nodes[node_index + 1];
+                let right = &self.nodes[node.offset as usize];
+                (left.min.min(right.min), left.max.max(right.max))
+            };
+            let node = &mut self.nodes[node_index];
+            node.min = min;
+            node.max = max;
+        }
+    }
+
+    /// Visits every instance whose bounds pass `node_test`.
+    fn for_each_candidate(
+        &self,
+        mut node_test: impl FnMut(Vec3, Vec3) -> bool,
+        mut visit: impl FnMut(usize),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node_test(node.min, node.max) {
+                continue;
+            }
+            if node.is_leaf() {
+                for &i in &self.indices[node.leaf_range()] {
+                    visit(i as usize);
+                }
+            } else {
+                stack.push(node.offset as usize);
+                stack.push(node_index + 1);
+            }
+        }
+    }
+
+    /// Calls `visit` with the instance index, entry ray parameter and entry
+    /// face axis of every instance that `ray` enters.
+    pub(crate) fn for_each_ray_hit(
+        &self,
+        cuboids: &Cuboids,
+        ray: &Ray,
+        mut visit: impl FnMut(usize, f32, usize),
+    ) {
+        self.for_each_candidate(
+            |min, max| {
+                ray_aabb_intersection(ray, min, max).is_some_and(|(_, t_exit, _)| t_exit >= 0.0)
+            },
+            |i| {
+                let cuboid = &cuboids.instances()[i];
+                if let Some((t, axis)) = ray_cuboid_entry(ray, cuboid) {
+                    visit(i, t, axis);
+                }
+            },
+        );
+    }
+
+    /// Returns the index and entry ray parameter of every instance that `ray`
+    /// enters, sorted by increasing ray parameter.
+    pub fn ray_intersections(&self, cuboids: &Cuboids, ray: Ray) -> Vec<(usize, f32)> {
+        let mut hits = Vec::new();
+        self.for_each_ray_hit(cuboids, &ray, |i, t, _| hits.push((i, t)));
+        hits.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        hits
+    }
+
+    /// Returns the indices of all instances overlapping the box from `min` to
+    /// `max`.
+    pub fn aabb_overlaps(&self, cuboids: &Cuboids, min: Vec3, max: Vec3) -> Vec<usize> {
+        let overlaps =
+            |node_min: Vec3, node_max: Vec3| node_min.cmple(max).all() && node_max.cmpge(min).all();
+        let mut result = Vec::new();
+        self.for_each_candidate(overlaps, |i| {
+            let cuboid = &cuboids.instances()[i];
+            if overlaps(cuboid.minimum, cuboid.maximum) {
+                result.push(i);
+            }
+        });
+        result
+    }
+
+    /// Returns the indices of all instances overlapping the sphere at `center`
+    /// with `radius`.
+    pub fn sphere_overlaps(&self, cuboids: &Cuboids, center: Vec3, radius: f32) -> Vec<usize> {
+        let radius_squared = radius * radius;
+        let overlaps =
+            |min: Vec3, max: Vec3| aabb_distance_squared(min, max, center) <= radius_squared;
+        let mut result = Vec::new();
+        self.for_each_candidate(overlaps, |i| {
+            let cuboid = &cuboids.instances()[i];
+            if overlaps(cuboid.minimum, cuboid.maximum) {
+                result.push(i);
+            }
+        });
+        result
+    }
+
+    /// Returns the index of the instance closest to `point` and its distance.
+    ///
+    /// The distance is zero for instances containing `point`.
+    pub fn nearest(&self, cuboids: &Cuboids, point: Vec3) -> Option<(usize, f32)> {
+        let root = self.nodes.first()?;
+
+        // Best-first traversal, ordered by increasing distance to the node.
+        let mut queue = BinaryHeap::new();
+        queue.push(NodeDistance {
+            distance_squared: root.distance_squared_to(point),
+            node_index: 0,
+        });
+        let mut best: Option<(usize, f32)> = None;
+        while let Some(NodeDistance {
+            distance_squared,
+            node_index,
+        }) = queue.pop()
+        {
+            if best.is_some_and(|(_, best_d2)| best_d2 <= distance_squared) {
+                break;
+            }
+            let node = &self.nodes[node_index];
+            if node.is_leaf() {
+                for &i in &self.indices[node.leaf_range()] {
+                    let cuboid = &cuboids.instances()[i as usize];
+                    let d2 = aabb_distance_squared(cuboid.minimum, cuboid.maximum, point);
+                    if !best.is_some_and(|(_, best_d2)| best_d2 <= d2) {
+                        best = Some((i as usize, d2));
+                    }
+                }
+            } else {
+                for child in [node_index + 1, node.offset as usize] {
+                    queue.push(NodeDistance {
+                        distance_squared: self.nodes[child].distance_squared_to(point),
+                        node_index: child,
+                    });
+                }
+            }
+        }
+        best.map(|(i, d2)| (i, d2.sqrt()))
+    }
+}
+
+/// Min-heap entry for nearest neighbor search.
+struct NodeDistance {
+    distance_squared: f32,
+    node_index: usize,
+}
+
+impl PartialEq for NodeDistance {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NodeDistance {}
+
+impl PartialOrd for NodeDistance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NodeDistance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so the closest node is popped first.
+        other.distance_squared.total_cmp(&self.distance_squared)
+    }
+}
+
+fn aabb_distance_squared(min: Vec3, max: Vec3, point: Vec3) -> f32 {
+    (min - point)
+        .max(point - max)
+        .max(Vec3::ZERO)
+        .length_squared()
+}
+
+pub(crate) fn update_cuboids_bvh(mut cuboids: Query<(Ref<Cuboids>, &mut CuboidsBvh)>) {
+    for (cuboids, mut bvh) in cuboids.iter_mut() {
+        if bvh.is_up_to_date(&cuboids) {
+            continue;
+        }
+        if bvh.len() == cuboids.instances().len() && !bvh.is_empty() {
+            bvh.refit(&cuboids);
+        } else {
+            bvh.rebuild(&cuboids);
+        }
+        bvh.built_from = Some(cuboids.last_changed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    /// Unit cubes spaced along the x axis, with instance `i` at `x = 2 * i`.
+    fn row(len: usize) -> Cuboids {
+        Cuboids::new(
+            (0..len)
+                .map(|i| {
+                    let minimum = Vec3::new(2.0 * i as f32, 0.0, 0.0);
+                    Cuboid::new(minimum, minimum + Vec3::ONE, 0)
+                })
+                .collect(),
+        )
+    }
+
+    fn sorted(mut indices: Vec<usize>) -> Vec<usize> {
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn ray_intersections_are_sorted_by_distance() {
+        let cuboids = row(20);
+        let bvh = CuboidsBvh::new(&cuboids);
+        assert_eq!(bvh.len(), 20);
+
+        let ray = Ray {
+            origin: Vec3::new(100.0, 0.5, 0.5),
+            direction: Vec3::NEG_X,
+        };
+        let hits = bvh.ray_intersections(&cuboids, ray);
+        let indices: Vec<_> = hits.iter().map(|&(i, _)| i).collect();
+        assert_eq!(indices, (0..20).rev().collect::<Vec<_>>());
+        assert_eq!(hits[0].1, 100.0 - 39.0);
+
+        let miss = Ray {
+            origin: Vec3::new(100.0, 2.0, 0.5),
+            direction: Vec3::NEG_X,
+        };
+        assert!(bvh.ray_intersections(&cuboids, miss).is_empty());
+    }
+
+    #[test]
+    fn overlaps_match_the_instance_bounds() {
+        let cuboids = row(20);
+        let bvh = CuboidsBvh::new(&cuboids);
+
+        let in_box =
+            bvh.aabb_overlaps(&cuboids, Vec3::new(3.5, 0.5, 0.5), Vec3::new(8.0, 2.0, 2.0));
+        assert_eq!(sorted(in_box), [2, 3, 4]);
+
+        let in_sphere = bvh.sphere_overlaps(&cuboids, Vec3::new(10.5, 0.5, 0.5), 1.6);
+        assert_eq!(sorted(in_sphere), [4, 5, 6]);
+    }
+
+    #[test]
+    fn nearest_finds_the_closest_instance() {
+        let cuboids = row(20);
+        let bvh = CuboidsBvh::new(&cuboids);
+
+        assert_eq!(
+            bvh.nearest(&cuboids, Vec3::new(12.5, 0.5, 0.5)),
+            Some((6, 0.0))
+        );
+        let (index, distance) = bvh.nearest(&cuboids, Vec3::new(13.75, 0.5, 3.0)).unwrap();
+        assert_eq!(index, 7);
+        assert_eq!(distance, (0.25f32 * 0.25 + 2.0 * 2.0).sqrt());
+        assert_eq!(CuboidsBvh::default().nearest(&row(0), Vec3::ZERO), None);
+    }
+
+    #[test]
+    fn refit_follows_moved_instances() {
+        let mut cuboids = row(20);
+        let mut bvh = CuboidsBvh::new(&cuboids);
+
+        let moved = cuboids.get_mut(3);
+        moved.minimum.y += 10.0;
+        moved.maximum.y += 10.0;
+        bvh.refit(&cuboids);
+
+        let ray = Ray {
+            origin: Vec3::new(6.5, 20.0, 0.5),
+            direction: Vec3::NEG_Y,
+        };
+        let hits = bvh.ray_intersections(&cuboids, ray);
+        assert_eq!(hits, [(3, 9.0)]);
+        assert!(bvh
+            .aabb_overlaps(&cuboids, Vec3::new(6.0, 0.0, 0.0), Vec3::new(7.0, 1.0, 1.0))
+            .is_empty());
+    }
+
+    #[test]
+    fn hierarchy_is_out_of_date_until_updated() {
+        let mut world = World::new();
+        let entity = world.spawn((row(20), CuboidsBvh::default())).id();
+        let is_up_to_date = |world: &mut World| {
+            let mut query = world.query::<(Ref<Cuboids>, &CuboidsBvh)>();
+            let (cuboids, bvh) = query.get(world, entity).unwrap();
+            bvh.is_up_to_date(&cuboids)
+        };
+        assert!(!is_up_to_date(&mut world));
+
+        world.run_system_once(update_cuboids_bvh);
+        assert!(is_up_to_date(&mut world));
+        assert_eq!(world.get::<CuboidsBvh>(entity).unwrap().len(), 20);
+
+        world
+            .get_mut::<Cuboids>(entity)
+            .unwrap()
+            .get_mut(0)
+            .maximum
+            .x = 1.5;
+        assert!(!is_up_to_date(&mut world));
+
+        world.run_system_once(update_cuboids_bvh);
+        assert!(is_up_to_date(&mut world));
+
+        world
+            .get_mut::<Cuboids>(entity)
+            .unwrap()
+            .instances_mut()
+            .pop();
+        world.run_system_once(update_cuboids_bvh);
+        assert!(is_up_to_date(&mut world));
+        assert_eq!(world.get::<CuboidsBvh>(entity).unwrap().len(), 19);
+    }
+}
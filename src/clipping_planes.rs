@@ -1,10 +1,11 @@
-use bevy::{prelude::*, render::render_resource::ShaderType};
+use bevy::{math::Ray, prelude::*, render::render_resource::ShaderType};
 
 /// The range of signed distances from the plane that don't get clipped.
 ///
 /// The plane origin and normal will be extracted from the [`GlobalTransform`],
 /// assuming normal axis is pointing
 #[derive(Clone, Component, Debug, ShaderType)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClippingPlaneRange {
     /// The minimum (signed) distance from a visible cuboid's centroid to the plane.
     pub min_sdist: f32,
@@ -27,19 +28,403 @@ pub struct ClippingPlaneBundle {
     pub transform: TransformBundle,
 }
 
+/// Scopes clipping volumes to specific entities, like
+/// [`RenderLayers`](bevy::render::view::RenderLayers) do for cameras.
+///
+/// A [`ClippingPlaneRange`], [`ClippingBox`] or [`ClippingSphere`] only clips
+/// the [`Cuboids`](crate::Cuboids) and
+/// [`OrientedCuboids`](crate::OrientedCuboids) whose groups intersect its own.
+/// Entities without this component belong to group 0 only, so by default every
+/// volume clips every cuboid.
+#[derive(Clone, Copy, Component, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClippingGroups(u32);
+
+impl Default for ClippingGroups {
+    fn default() -> Self {
+        Self::group(0)
+    }
+}
+
+impl ClippingGroups {
+    /// The total number of groups supported.
+    pub const TOTAL_GROUPS: usize = std::mem::size_of::<u32>() * 8;
+
+    /// Creates a mask belonging to only the given group.
+    pub const fn group(n: u8) -> Self {
+        Self(0).with(n)
+    }
+
+    /// Creates a mask belonging to all groups.
+    pub const fn all() -> Self {
+        Self(u32::MAX)
+    }
+
+    /// Creates a mask belonging to no groups, e.g. to exempt cuboids from all
+    /// clipping.
+    pub const fn none() -> Self {
+        Self(0)
+    }
+
+    /// Adds the given group.
+    ///
+    /// # Panics
+    /// Panics when called with a group greater than `TOTAL_GROUPS - 1`.
+    #[must_use]
+    pub const fn with(mut self, group: u8) -> Self {
+        assert!((group as usize) < Self::TOTAL_GROUPS);
+        self.0 |= 1 << group;
+        self
+    }
+
+    /// Removes the given group.
+    ///
+    /// # Panics
+    /// Panics when called with a group greater than `TOTAL_GROUPS - 1`.
+    #[must_use]
+    pub const fn without(mut self, group: u8) -> Self {
+        assert!((group as usize) < Self::TOTAL_GROUPS);
+        self.0 &= !(1 << group);
+        self
+    }
+
+    /// Returns `true` if the masks share any group.
+    pub fn intersects(&self, other: &ClippingGroups) -> bool {
+        (self.0 & other.0) > 0
+    }
+
+    /// Returns the groups in both masks.
+    #[must_use]
+    pub const fn intersection(self, other: ClippingGroups) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Which side of a [`ClippingBox`] or [`ClippingSphere`] stays visible.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClippingMode {
+    /// Clip cuboids whose centroid is outside of the volume.
+    #[default]
+    KeepInside,
+    /// Clip cuboids whose centroid is inside of the volume.
+    KeepOutside,
+}
+
+/// An oriented box that clips cuboids by their centroid.
+///
+/// The box is centered on the origin of the [`GlobalTransform`] and spans
+/// `half_extents` along its local axes, so it is rotated and scaled along with
+/// the transform.
+#[derive(Clone, Component, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClippingBox {
+    pub half_extents: Vec3,
+    pub mode: ClippingMode,
+}
+
+impl Default for ClippingBox {
+    fn default() -> Self {
+        Self {
+            half_extents: Vec3::ONE,
+            mode: ClippingMode::default(),
+        }
+    }
+}
+
+#[derive(Bundle, Default)]
+pub struct ClippingBoxBundle {
+    pub clipping_box: ClippingBox,
+    pub transform: TransformBundle,
+}
+
+/// A sphere that clips cuboids by their centroid.
+///
+/// The sphere is centered on the translation of the [`GlobalTransform`], and
+/// its `radius` is in world units.
+#[derive(Clone, Component, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClippingSphere {
+    pub radius: f32,
+    pub mode: ClippingMode,
+}
+
+impl Default for ClippingSphere {
+    fn default() -> Self {
+        Self {
+            radius: 1.0,
+            mode: ClippingMode::default(),
+        }
+    }
+}
+
+#[derive(Bundle, Default)]
+pub struct ClippingSphereBundle {
+    pub sphere: ClippingSphere,
+    pub transform: TransformBundle,
+}
+
 #[derive(Clone, Component, Debug, Default, ShaderType)]
 pub(crate) struct GpuClippingPlaneRange {
     pub origin: Vec3,
     pub unit_normal: Vec3,
     pub min_sdist: f32,
     pub max_sdist: f32,
+    pub groups: u32,
 }
 
+impl GpuClippingPlaneRange {
+    pub fn new(
+        range: &ClippingPlaneRange,
+        transform: &GlobalTransform,
+        groups: Option<&ClippingGroups>,
+    ) -> Self {
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        Self {
+            origin: translation,
+            unit_normal: rotation * Vec3::X,
+            min_sdist: range.min_sdist,
+            max_sdist: range.max_sdist,
+            groups: groups.copied().unwrap_or_default().bits(),
+        }
+    }
+
+    /// Whether `point` lies outside the slab between the range's two
+    /// distances along the normal.
+    pub fn clips(&self, point: Vec3) -> bool {
+        let sdist_to_plane = (point - self.origin).dot(self.unit_normal);
+        sdist_to_plane < self.min_sdist || sdist_to_plane > self.max_sdist
+    }
+}
+
+#[derive(Clone, Debug, Default, ShaderType)]
+pub(crate) struct GpuClippingBox {
+    pub world_to_box: Mat4,
+    pub half_extents: Vec3,
+    pub keep_inside: u32,
+    pub groups: u32,
+}
+
+impl GpuClippingBox {
+    pub fn new(
+        clipping_box: &ClippingBox,
+        transform: &GlobalTransform,
+        groups: Option<&ClippingGroups>,
+    ) -> Self {
+        Self {
+            world_to_box: transform.compute_matrix().inverse(),
+            half_extents: clipping_box.half_extents,
+            keep_inside: (clipping_box.mode == ClippingMode::KeepInside) as u32,
+            groups: groups.copied().unwrap_or_default().bits(),
+        }
+    }
+
+    /// Whether `point` lies on the clipped side of the oriented box: outside
+    /// it if it keeps the inside, inside it otherwise.
+    pub fn clips(&self, point: Vec3) -> bool {
+        let local = self.world_to_box.transform_point3(point);
+        let inside = local.abs().cmple(self.half_extents).all();
+        inside != (self.keep_inside != 0)
+    }
+}
+
+#[derive(Clone, Debug, Default, ShaderType)]
+pub(crate) struct GpuClippingSphere {
+    pub center: Vec3,
+    pub radius: f32,
+    pub keep_inside: u32,
+    pub groups: u32,
+}
+
+impl GpuClippingSphere {
+    pub fn new(
+        sphere: &ClippingSphere,
+        transform: &GlobalTransform,
+        groups: Option<&ClippingGroups>,
+    ) -> Self {
+        Self {
+            center: transform.translation(),
+            radius: sphere.radius,
+            keep_inside: (sphere.mode == ClippingMode::KeepInside) as u32,
+            groups: groups.copied().unwrap_or_default().bits(),
+        }
+    }
+
+    /// Whether `point` lies on the clipped side of the sphere: outside it if
+    /// it keeps the inside, inside it otherwise.
+    pub fn clips(&self, point: Vec3) -> bool {
+        let inside = point.distance(self.center) <= self.radius;
+        inside != (self.keep_inside != 0)
+    }
+}
+
+/// The [`CuboidsViewOverrides::clipping_groups`](crate::CuboidsViewOverrides::clipping_groups)
+/// of a view.
+#[derive(Clone, Debug, ShaderType)]
+pub(crate) struct GpuViewClippingGroups {
+    pub groups: u32,
+}
+
+impl Default for GpuViewClippingGroups {
+    fn default() -> Self {
+        Self {
+            groups: ClippingGroups::all().bits(),
+        }
+    }
+}
+
+/// Components of a single clipping volume entity.
+pub(crate) type ClippingVolumeItem<'a, T> =
+    (&'a T, &'a GlobalTransform, Option<&'a ClippingGroups>);
+
+/// All clipping volumes in the world.
+#[derive(Debug, Default)]
+pub(crate) struct ClippingVolumes {
+    pub planes: Vec<GpuClippingPlaneRange>,
+    pub boxes: Vec<GpuClippingBox>,
+    pub spheres: Vec<GpuClippingSphere>,
+}
+
+impl ClippingVolumes {
+    pub fn new<'a>(
+        planes: impl IntoIterator<Item = ClippingVolumeItem<'a, ClippingPlaneRange>>,
+        boxes: impl IntoIterator<Item = ClippingVolumeItem<'a, ClippingBox>>,
+        spheres: impl IntoIterator<Item = ClippingVolumeItem<'a, ClippingSphere>>,
+    ) -> Self {
+        Self {
+            planes: planes
+                .into_iter()
+                .map(|(range, transform, groups)| {
+                    GpuClippingPlaneRange::new(range, transform, groups)
+                })
+                .collect(),
+            boxes: boxes
+                .into_iter()
+                .map(|(clipping_box, transform, groups)| {
+                    GpuClippingBox::new(clipping_box, transform, groups)
+                })
+                .collect(),
+            spheres: spheres
+                .into_iter()
+                .map(|(sphere, transform, groups)| {
+                    GpuClippingSphere::new(sphere, transform, groups)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.planes.is_empty() && self.boxes.is_empty() && self.spheres.is_empty()
+    }
+
+    /// Same test as the vertex shader, applied to a cuboid centroid in world
+    /// space, only considering the volumes in any of `groups`.
+    pub fn clips(&self, point: Vec3, groups: ClippingGroups) -> bool {
+        self.planes
+            .iter()
+            .any(|plane| plane.groups & groups.bits() != 0 && plane.clips(point))
+            || self.boxes_or_spheres_clip(point, groups)
+    }
+
+    /// Like [`Self::clips`], ignoring the planes.
+    pub fn boxes_or_spheres_clip(&self, point: Vec3, groups: ClippingGroups) -> bool {
+        let groups = groups.bits();
+        self.boxes
+            .iter()
+            .any(|clipping_box| clipping_box.groups & groups != 0 && clipping_box.clips(point))
+            || self
+                .spheres
+                .iter()
+                .any(|sphere| sphere.groups & groups != 0 && sphere.clips(point))
+    }
+
+    /// Same cut as the fragment shader with
+    /// [`CuboidMaterial::precise_clipping`](crate::CuboidMaterial::precise_clipping):
+    /// intersects the part of `ray` between `t_enter` and `t_exit` with the
+    /// range of every plane in any of `groups`.
+    ///
+    /// Returns the new entry parameter along with the unit normal of the cap
+    /// it lies on, if a plane moved it, or `None` if nothing is left.
+    pub fn cut_ray(
+        &self,
+        ray: &Ray,
+        mut t_enter: f32,
+        mut t_exit: f32,
+        groups: ClippingGroups,
+    ) -> Option<(f32, Option<Vec3>)> {
+        let mut cap_normal = None;
+        for plane in &self.planes {
+            if plane.groups & groups.bits() == 0 {
+                continue;
+            }
+            let sdist = (ray.origin - plane.origin).dot(plane.unit_normal);
+            let rate = ray.direction.dot(plane.unit_normal);
+            if rate == 0.0 {
+                if sdist < plane.min_sdist || sdist > plane.max_sdist {
+                    return None;
+                }
+                continue;
+            }
+            let t_min = (plane.min_sdist - sdist) / rate;
+            let t_max = (plane.max_sdist - sdist) / rate;
+            let t_near = t_min.min(t_max);
+            if t_near > t_enter {
+                t_enter = t_near;
+                cap_normal = Some(-rate.signum() * plane.unit_normal);
+            }
+            t_exit = t_exit.min(t_min.max(t_max));
+        }
+        (t_enter <= t_exit).then_some((t_enter, cap_normal))
+    }
+}
+
+/// Clipping boxes and spheres, along with the clipping planes when they don't
+/// fit in a storage buffer.
 #[derive(Debug, Default, ShaderType)]
 pub(crate) struct GpuClippingPlaneRanges {
+    /// Unused when the planes are in a storage buffer.
     pub ranges: [GpuClippingPlaneRange; MAX_CLIPPING_PLANES],
     pub num_ranges: u32,
+    pub boxes: [GpuClippingBox; MAX_CLIPPING_BOXES],
+    pub num_boxes: u32,
+    pub spheres: [GpuClippingSphere; MAX_CLIPPING_SPHERES],
+    pub num_spheres: u32,
+}
+
+impl GpuClippingPlaneRanges {
+    /// Packs as many of the `volumes` as fit into the uniform, returning
+    /// `false` if some had to be left out.
+    ///
+    /// With `planes_in_storage`, only the number of planes is written.
+    pub fn pack(&mut self, volumes: &ClippingVolumes, planes_in_storage: bool) -> bool {
+        let fit_planes = if planes_in_storage {
+            self.num_ranges = volumes.planes.len() as u32;
+            true
+        } else {
+            pack_array(&mut self.ranges, &mut self.num_ranges, &volumes.planes)
+        };
+        let fit_boxes = pack_array(&mut self.boxes, &mut self.num_boxes, &volumes.boxes);
+        let fit_spheres = pack_array(&mut self.spheres, &mut self.num_spheres, &volumes.spheres);
+        fit_planes && fit_boxes && fit_spheres
+    }
+}
+
+fn pack_array<T: Clone, const N: usize>(array: &mut [T; N], len: &mut u32, items: &[T]) -> bool {
+    let n = items.len().min(N);
+    array[..n].clone_from_slice(&items[..n]);
+    *len = n as u32;
+    n == items.len()
 }
 
-/// The clipping shader is `O(planes * cuboids)`, so we set a reasonable limit.
+/// The number of clipping planes supported on devices without storage buffer
+/// support. Otherwise, the number of planes is unlimited, but clipping is still
+/// `O(planes * cuboids)`.
 pub const MAX_CLIPPING_PLANES: usize = 16;
+/// Maximum number of [`ClippingBox`]es applied at once.
+pub const MAX_CLIPPING_BOXES: usize = 8;
+/// Maximum number of [`ClippingSphere`]s applied at once.
+pub const MAX_CLIPPING_SPHERES: usize = 8;
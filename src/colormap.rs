@@ -0,0 +1,305 @@
+use bevy::{
+    asset::AssetId,
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+};
+
+/// Index of a gradient in [`CuboidColormaps`], used by
+/// [`CuboidMaterial::colormap`](crate::CuboidMaterial::colormap).
+pub type ColormapIndex = u32;
+
+/// Perceptually uniform blue-green-yellow gradient.
+pub const COLORMAP_VIRIDIS: ColormapIndex = 0;
+/// Perceptually uniform black-purple-orange-white gradient.
+pub const COLORMAP_MAGMA: ColormapIndex = 1;
+/// High contrast rainbow gradient, a smoother alternative to "jet".
+pub const COLORMAP_TURBO: ColormapIndex = 2;
+/// Black to white gradient.
+pub const COLORMAP_GRAYSCALE: ColormapIndex = 3;
+
+/// Number of texels sampled along each gradient.
+const COLORMAP_RESOLUTION: usize = 256;
+
+pub(crate) const COLORMAP_ATLAS_HANDLE: Handle<Image> =
+    Handle::weak_from_u128(12871532049617722935);
+
+/// A key on a [`Colormap::Stops`] gradient.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColormapStop {
+    pub key: f32,
+    pub color: Color,
+}
+
+impl ColormapStop {
+    pub fn new(key: f32, color: Color) -> Self {
+        Self { key, color }
+    }
+}
+
+/// A 1D gradient sampled by [`COLOR_MODE_SCALAR_COLORMAP`](crate::COLOR_MODE_SCALAR_COLORMAP).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Colormap {
+    /// Linear interpolation between stops, sorted by increasing key.
+    ///
+    /// The smallest key maps to the start of the gradient and the largest key
+    /// to the end.
+    Stops(Vec<ColormapStop>),
+    /// The first row of an `Rgba8Unorm` or `Rgba8UnormSrgb` image, from left to
+    /// right.
+    ///
+    /// Empty images and images in other formats map everything to black.
+    ///
+    /// Image handles can't be serialized, so with the `serde` feature this
+    /// variant is skipped.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Image(Handle<Image>),
+}
+
+/// Resource holding all gradients available to
+/// [`COLOR_MODE_SCALAR_COLORMAP`](crate::COLOR_MODE_SCALAR_COLORMAP).
+///
+/// The built-in gradients are always present at the indices given by
+/// [`COLORMAP_VIRIDIS`], [`COLORMAP_MAGMA`], [`COLORMAP_TURBO`] and
+/// [`COLORMAP_GRAYSCALE`].
+#[derive(Clone, Debug, Default, Resource)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidColormaps {
+    /// User-supplied gradients, following the built-in ones.
+    colormaps: Vec<Colormap>,
+}
+
+impl CuboidColormaps {
+    const NUM_BUILT_IN: u32 = 4;
+
+    pub fn push(&mut self, colormap: Colormap) -> ColormapIndex {
+        self.colormaps.push(colormap);
+        Self::NUM_BUILT_IN + self.colormaps.len() as u32 - 1
+    }
+
+    /// Returns `None` for built-in gradients.
+    pub fn get(&self, index: ColormapIndex) -> Option<&Colormap> {
+        self.colormaps
+            .get(index.checked_sub(Self::NUM_BUILT_IN)? as usize)
+    }
+
+    /// Returns `None` for built-in gradients.
+    pub fn get_mut(&mut self, index: ColormapIndex) -> Option<&mut Colormap> {
+        self.colormaps
+            .get_mut(index.checked_sub(Self::NUM_BUILT_IN)? as usize)
+    }
+
+    fn uses_image(&self, id: AssetId<Image>) -> bool {
+        self.colormaps
+            .iter()
+            .any(|colormap| matches!(colormap, Colormap::Image(handle) if handle.id() == id))
+    }
+
+    /// Creates an image with one row of RGBA texels per gradient.
+    fn build_atlas(&self, images: &Assets<Image>) -> Image {
+        let num_rows = Self::NUM_BUILT_IN as usize + self.colormaps.len();
+        let mut data = Vec::with_capacity(num_rows * COLORMAP_RESOLUTION * 4);
+        write_row(&mut data, viridis);
+        write_row(&mut data, magma);
+        write_row(&mut data, turbo);
+        write_row(&mut data, |t| [t; 3]);
+        for colormap in &self.colormaps {
+            match colormap {
+                Colormap::Stops(stops) => write_row(&mut data, |t| sample_stops(stops, t)),
+                Colormap::Image(handle) => {
+                    if let Some(image) = images.get(handle) {
+                        write_row(&mut data, |t| sample_image(image, t));
+                    } else {
+                        // Filled in once the image is loaded.
+                        write_row(&mut data, |_| [0.0; 3]);
+                    }
+                }
+            }
+        }
+
+        let mut atlas = Image::new(
+            Extent3d {
+                width: COLORMAP_RESOLUTION as u32,
+                height: num_rows as u32,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            // Colors are already nonlinear, like all other color modes.
+            TextureFormat::Rgba8Unorm,
+        );
+        atlas.sampler = ImageSampler::linear();
+        atlas
+    }
+}
+
+fn write_row(data: &mut Vec<u8>, f: impl Fn(f32) -> [f32; 3]) {
+    for i in 0..COLORMAP_RESOLUTION {
+        let rgb = f(i as f32 / (COLORMAP_RESOLUTION - 1) as f32);
+        for c in rgb {
+            data.push((c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        }
+        data.push(255);
+    }
+}
+
+fn sample_stops(stops: &[ColormapStop], t: f32) -> [f32; 3] {
+    let rgb = |stop: &ColormapStop| {
+        let [r, g, b, _] = stop.color.as_rgba_f32();
+        Vec3::new(r, g, b)
+    };
+    let (Some(first), Some(last)) = (stops.first(), stops.last()) else {
+        return [0.0; 3];
+    };
+    let key = first.key + t * (last.key - first.key);
+    let next = stops.partition_point(|stop| stop.key < key);
+    if next == 0 {
+        return rgb(first).into();
+    }
+    if next == stops.len() {
+        return rgb(last).into();
+    }
+    let (a, b) = (&stops[next - 1], &stops[next]);
+    let s = (key - a.key) / (b.key - a.key);
+    rgb(a).lerp(rgb(b), s).into()
+}
+
+fn sample_image(image: &Image, t: f32) -> [f32; 3] {
+    if !matches!(
+        image.texture_descriptor.format,
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb
+    ) {
+        return [0.0; 3];
+    }
+    let width = (image.texture_descriptor.size.width as usize).min(image.data.len() / 4);
+    if width == 0 {
+        return [0.0; 3];
+    }
+    let texel = |x: usize| {
+        let p = &image.data[4 * x..4 * x + 3];
+        Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32) / 255.0
+    };
+    let x = t * (width - 1) as f32;
+    let x0 = x.floor() as usize;
+    let x1 = (x0 + 1).min(width - 1);
+    texel(x0).lerp(texel(x1), x - x0 as f32).into()
+}
+
+/// Polynomial fit of matplotlib's viridis.
+#[allow(clippy::excessive_precision)]
+fn viridis(t: f32) -> [f32; 3] {
+    polynomial(
+        t,
+        [
+            Vec3::new(0.27772733, 0.005407345, 0.33409980),
+            Vec3::new(0.10509304, 1.4046135, 1.3845902),
+            Vec3::new(-0.33086183, 0.21484756, 0.095095163),
+            Vec3::new(-4.6342305, -5.7991010, -19.332441),
+            Vec3::new(6.2282699, 14.179933, 56.690553),
+            Vec3::new(4.7763850, -13.745145, -65.353033),
+            Vec3::new(-5.4354559, 4.6458526, 26.312435),
+        ],
+    )
+}
+
+/// Polynomial fit of matplotlib's magma.
+#[allow(clippy::excessive_precision)]
+fn magma(t: f32) -> [f32; 3] {
+    polynomial(
+        t,
+        [
+            Vec3::new(-0.0021364851, -0.00074965505, -0.0053861279),
+            Vec3::new(0.25166054, 0.67752324, 2.4940266),
+            Vec3::new(8.3537173, -3.5777195, 0.31446790),
+            Vec3::new(-27.668733, 14.264731, -13.649213),
+            Vec3::new(52.176140, -27.943606, 12.944169),
+            Vec3::new(-50.768525, 29.046583, 4.2341530),
+            Vec3::new(18.655705, -11.489774, -5.6019615),
+        ],
+    )
+}
+
+/// Polynomial fit of Google's turbo.
+#[allow(clippy::excessive_precision)]
+fn turbo(t: f32) -> [f32; 3] {
+    polynomial(
+        t,
+        [
+            Vec3::new(0.13572138, 0.09140261, 0.10667330),
+            Vec3::new(4.61539260, 2.19418839, 12.64194608),
+            Vec3::new(-42.66032258, 4.84296658, -60.58204836),
+            Vec3::new(132.13108234, -14.18503333, 110.36276771),
+            Vec3::new(-152.94239396, 4.27729857, -89.90310912),
+            Vec3::new(59.28637943, 2.82956604, 27.34824973),
+            Vec3::ZERO,
+        ],
+    )
+}
+
+fn polynomial(t: f32, coefficients: [Vec3; 7]) -> [f32; 3] {
+    // Horner's method.
+    coefficients
+        .iter()
+        .rev()
+        .fold(Vec3::ZERO, |acc, &c| acc * t + c)
+        .into()
+}
+
+/// Rebuilds the gradient texture when [`CuboidColormaps`] or any of its images
+/// change.
+pub(crate) fn update_colormap_atlas(
+    colormaps: Res<CuboidColormaps>,
+    mut images: ResMut<Assets<Image>>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+) {
+    let images_changed = image_events.read().any(|event| match event {
+        AssetEvent::Added { id }
+        | AssetEvent::Modified { id }
+        | AssetEvent::LoadedWithDependencies { id } => colormaps.uses_image(*id),
+        AssetEvent::Removed { .. } => false,
+    });
+    if !colormaps.is_changed() && !images_changed {
+        return;
+    }
+    let atlas = colormaps.build_atlas(&images);
+    images.insert(COLORMAP_ATLAS_HANDLE, atlas);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, data: Vec<u8>) -> Image {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; 4],
+            TextureFormat::Rgba8Unorm,
+        );
+        image.texture_descriptor.size.width = width;
+        image.data = data;
+        image
+    }
+
+    #[test]
+    fn images_are_interpolated_along_the_first_row() {
+        let image = image(2, vec![0, 0, 0, 255, 255, 51, 0, 255]);
+        assert_eq!(sample_image(&image, 0.0), [0.0, 0.0, 0.0]);
+        assert_eq!(sample_image(&image, 0.5), [0.5, 0.1, 0.0]);
+        assert_eq!(sample_image(&image, 1.0), [1.0, 0.2, 0.0]);
+    }
+
+    #[test]
+    fn empty_images_are_black() {
+        assert_eq!(sample_image(&image(0, Vec::new()), 0.5), [0.0; 3]);
+        assert_eq!(sample_image(&image(4, Vec::new()), 1.0), [0.0; 3]);
+    }
+}
@@ -0,0 +1,634 @@
+use crate::{Cuboid, Cuboids, CuboidsAsset};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::{BoxedFuture, HashMap},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read};
+
+/// Which columns of a block model table hold the geometry and color of each
+/// block, for [`read_cuboids_csv`].
+///
+/// Column names are case-insensitive and may appear in any order. As the
+/// settings of [`CuboidsCsvLoader`], the mapping can be given per file in a
+/// `.meta` file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct CsvColumnMapping {
+    /// Columns holding the X, Y and Z coordinates of each block's centroid.
+    pub centroid: [String; 3],
+    pub size: CsvBlockSize,
+    pub color: CsvColorSource,
+    /// Value of empty attribute fields, e.g. for blocks without an estimate.
+    /// Rows with empty attributes are malformed if this is `None`.
+    pub missing_value: Option<f32>,
+    /// Field delimiter, `b','` by default.
+    pub delimiter: u8,
+    /// Leave out malformed rows and report them in
+    /// [`CsvImport::skipped_rows`], instead of failing the import.
+    pub skip_malformed_rows: bool,
+}
+
+impl Default for CsvColumnMapping {
+    fn default() -> Self {
+        Self {
+            centroid: ["x".into(), "y".into(), "z".into()],
+            size: default(),
+            color: default(),
+            missing_value: None,
+            delimiter: b',',
+            skip_malformed_rows: false,
+        }
+    }
+}
+
+impl CsvColumnMapping {
+    pub fn with_centroid(mut self, x: &str, y: &str, z: &str) -> Self {
+        self.centroid = [x.into(), y.into(), z.into()];
+        self
+    }
+
+    pub fn with_size(mut self, size: CsvBlockSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_color(mut self, color: CsvColorSource) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_missing_value(mut self, missing_value: f32) -> Self {
+        self.missing_value = Some(missing_value);
+        self
+    }
+
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn with_skip_malformed_rows(mut self, skip_malformed_rows: bool) -> Self {
+        self.skip_malformed_rows = skip_malformed_rows;
+        self
+    }
+}
+
+/// The size of each block in a block model table.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum CsvBlockSize {
+    /// Columns holding the X, Y and Z extents of each block, `dx`, `dy` and
+    /// `dz` by default.
+    Columns([String; 3]),
+    /// The same extents for every block of a regular model.
+    Fixed([f32; 3]),
+}
+
+impl Default for CsvBlockSize {
+    fn default() -> Self {
+        Self::Columns(["dx".into(), "dy".into(), "dz".into()])
+    }
+}
+
+/// The attribute that determines the [`Color`](crate::Color) of each block.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum CsvColorSource {
+    /// Every block gets color 0.
+    #[default]
+    None,
+    /// A numeric column, encoded as expected by
+    /// [`COLOR_MODE_SCALAR_HUE`](crate::COLOR_MODE_SCALAR_HUE) and
+    /// [`COLOR_MODE_SCALAR_COLORMAP`](crate::COLOR_MODE_SCALAR_COLORMAP).
+    Scalar(String),
+    /// Red, green and blue columns from 0 to 255, for
+    /// [`COLOR_MODE_RGB`](crate::COLOR_MODE_RGB).
+    Rgb([String; 3]),
+    /// A column of category names, numbered for
+    /// [`COLOR_MODE_CATEGORICAL`](crate::COLOR_MODE_CATEGORICAL).
+    ///
+    /// Names in `categories` keep their index, and other names are appended in
+    /// the order they appear, see [`CsvImport::categories`].
+    Category {
+        column: String,
+        categories: Vec<String>,
+    },
+}
+
+/// The result of [`read_cuboids_csv`].
+#[derive(Clone, Debug, Default)]
+pub struct CsvImport {
+    pub cuboids: Cuboids,
+    /// The category of each index with [`CsvColorSource::Category`], e.g. to
+    /// label the entries of a [`CuboidPalette`](crate::CuboidPalette).
+    pub categories: Vec<String>,
+    /// Malformed rows that were left out with
+    /// [`CsvColumnMapping::skip_malformed_rows`].
+    pub skipped_rows: Vec<CsvRowError>,
+}
+
+/// Reads a block model table with a header row and one block per row,
+/// according to `mapping`.
+///
+/// Rows with missing or non-numeric geometry, NaN or infinite coordinates or
+/// sizes, or negative sizes are malformed.
+pub fn read_cuboids_csv(
+    reader: impl Read,
+    mapping: &CsvColumnMapping,
+) -> Result<CsvImport, CsvImportError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(mapping.delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let columns = CsvColumns::new(&headers, mapping)?;
+
+    let mut import = CsvImport::default();
+    let mut category_indices = HashMap::default();
+    if let CsvColorSource::Category { categories, .. } = &mapping.color {
+        for category in categories {
+            category_indices.insert(category.clone(), import.categories.len() as u32);
+            import.categories.push(category.clone());
+        }
+    }
+
+    let mut instances = Vec::new();
+    let mut record = csv::StringRecord::new();
+    loop {
+        let row = match reader.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => {
+                let line = record.position().map_or(0, |position| position.line());
+                let row = CsvRow {
+                    record: &record,
+                    headers: &headers,
+                    line,
+                    missing_value: mapping.missing_value,
+                };
+                row.to_cuboid(&columns, |name| {
+                    *category_indices.entry(name.to_owned()).or_insert_with(|| {
+                        import.categories.push(name.to_owned());
+                        import.categories.len() as u32 - 1
+                    })
+                })
+            }
+            Err(error) => match error.kind() {
+                csv::ErrorKind::Utf8 { pos, .. } => Err(CsvRowError {
+                    line: pos.as_ref().map_or(0, |position| position.line()),
+                    kind: CsvRowErrorKind::InvalidUtf8,
+                }),
+                _ => return Err(error.into()),
+            },
+        };
+        match row {
+            Ok(cuboid) => instances.push(cuboid),
+            Err(error) if mapping.skip_malformed_rows => import.skipped_rows.push(error),
+            Err(error) => return Err(CsvImportError::Row(error)),
+        }
+    }
+    import.cuboids = Cuboids::new(instances);
+    Ok(import)
+}
+
+/// The indices of the mapped columns.
+struct CsvColumns {
+    centroid: [usize; 3],
+    size: CsvSizeColumns,
+    color: CsvColorColumns,
+}
+
+enum CsvSizeColumns {
+    Columns([usize; 3]),
+    Fixed(Vec3),
+}
+
+enum CsvColorColumns {
+    None,
+    Scalar(usize),
+    Rgb([usize; 3]),
+    Category(usize),
+}
+
+impl CsvColumns {
+    fn new(
+        headers: &csv::StringRecord,
+        mapping: &CsvColumnMapping,
+    ) -> Result<Self, CsvImportError> {
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.eq_ignore_ascii_case(name))
+                .ok_or_else(|| CsvImportError::MissingColumn(name.into()))
+        };
+        let columns =
+            |[x, y, z]: &[String; 3]| Ok::<_, CsvImportError>([column(x)?, column(y)?, column(z)?]);
+        Ok(Self {
+            centroid: columns(&mapping.centroid)?,
+            size: match &mapping.size {
+                CsvBlockSize::Columns(names) => CsvSizeColumns::Columns(columns(names)?),
+                CsvBlockSize::Fixed(size) => {
+                    if !size
+                        .iter()
+                        .all(|extent| extent.is_finite() && *extent >= 0.0)
+                    {
+                        return Err(CsvImportError::InvalidBlockSize(*size));
+                    }
+                    CsvSizeColumns::Fixed(Vec3::from_array(*size))
+                }
+            },
+            color: match &mapping.color {
+                CsvColorSource::None => CsvColorColumns::None,
+                CsvColorSource::Scalar(name) => CsvColorColumns::Scalar(column(name)?),
+                CsvColorSource::Rgb(names) => CsvColorColumns::Rgb(columns(names)?),
+                CsvColorSource::Category { column: name, .. } => {
+                    CsvColorColumns::Category(column(name)?)
+                }
+            },
+        })
+    }
+}
+
+struct CsvRow<'a> {
+    record: &'a csv::StringRecord,
+    headers: &'a csv::StringRecord,
+    line: u64,
+    missing_value: Option<f32>,
+}
+
+impl CsvRow<'_> {
+    fn to_cuboid(
+        &self,
+        columns: &CsvColumns,
+        category_index: impl FnOnce(&str) -> u32,
+    ) -> Result<Cuboid, CsvRowError> {
+        let centroid = self.vec3(columns.centroid)?;
+        let size = match columns.size {
+            CsvSizeColumns::Columns(size) => self.size(size)?,
+            CsvSizeColumns::Fixed(size) => size,
+        };
+        let color = match columns.color {
+            CsvColorColumns::None => 0,
+            CsvColorColumns::Scalar(i) => self.attribute(i)?.to_bits(),
+            CsvColorColumns::Rgb([r, g, b]) => {
+                let channel = |value: f32| value.clamp(0.0, 255.0) as u8;
+                Color::rgb_u8(
+                    channel(self.attribute(r)?),
+                    channel(self.attribute(g)?),
+                    channel(self.attribute(b)?),
+                )
+                .as_rgba_u32()
+            }
+            CsvColorColumns::Category(i) => category_index(self.field(i)?),
+        };
+        Ok(Cuboid::new(
+            centroid - size / 2.0,
+            centroid + size / 2.0,
+            color,
+        ))
+    }
+
+    fn field(&self, i: usize) -> Result<&str, CsvRowError> {
+        self.record
+            .get(i)
+            .filter(|field| !field.is_empty())
+            .ok_or_else(|| self.error(CsvRowErrorKind::MissingField(self.headers[i].into())))
+    }
+
+    fn number(&self, i: usize) -> Result<f32, CsvRowError> {
+        let field = self.field(i)?;
+        field.parse().map_err(|_| {
+            self.error(CsvRowErrorKind::InvalidNumber {
+                column: self.headers[i].into(),
+                field: field.into(),
+            })
+        })
+    }
+
+    /// A number that may be missing, unlike the geometry of the block.
+    fn attribute(&self, i: usize) -> Result<f32, CsvRowError> {
+        match (self.record.get(i), self.missing_value) {
+            (None | Some(""), Some(missing_value)) => Ok(missing_value),
+            _ => self.number(i),
+        }
+    }
+
+    /// A number that may be part of the geometry of the block.
+    fn finite(&self, i: usize) -> Result<f32, CsvRowError> {
+        let value = self.number(i)?;
+        if !value.is_finite() {
+            return Err(self.error(CsvRowErrorKind::NotFinite {
+                column: self.headers[i].into(),
+                field: self.record[i].into(),
+            }));
+        }
+        Ok(value)
+    }
+
+    fn vec3(&self, [x, y, z]: [usize; 3]) -> Result<Vec3, CsvRowError> {
+        Ok(Vec3::new(self.finite(x)?, self.finite(y)?, self.finite(z)?))
+    }
+
+    fn size(&self, columns: [usize; 3]) -> Result<Vec3, CsvRowError> {
+        let size = self.vec3(columns)?;
+        if let Some(i) = (0..3).find(|&axis| size[axis] < 0.0) {
+            return Err(self.error(CsvRowErrorKind::NegativeSize {
+                column: self.headers[columns[i]].into(),
+                field: self.record[columns[i]].into(),
+            }));
+        }
+        Ok(size)
+    }
+
+    fn error(&self, kind: CsvRowErrorKind) -> CsvRowError {
+        CsvRowError {
+            line: self.line,
+            kind,
+        }
+    }
+}
+
+/// A malformed row of a CSV table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvRowError {
+    /// 1-based line number of the row.
+    pub line: u64,
+    pub kind: CsvRowErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsvRowErrorKind {
+    /// The field of the given column is missing or empty.
+    MissingField(String),
+    /// The field of a numeric column isn't a number.
+    InvalidNumber { column: String, field: String },
+    /// A coordinate or size is NaN or infinite.
+    NotFinite { column: String, field: String },
+    /// A block size is negative.
+    NegativeSize { column: String, field: String },
+    /// The row isn't valid UTF-8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for CsvRowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            CsvRowErrorKind::MissingField(column) => write!(f, "column `{column}` is empty"),
+            CsvRowErrorKind::InvalidNumber { column, field } => {
+                write!(f, "column `{column}` is not a number: `{field}`")
+            }
+            CsvRowErrorKind::NotFinite { column, field } => {
+                write!(f, "column `{column}` is not finite: `{field}`")
+            }
+            CsvRowErrorKind::NegativeSize { column, field } => {
+                write!(f, "column `{column}` is a negative size: `{field}`")
+            }
+            CsvRowErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for CsvRowError {}
+
+/// An error encountered while importing a CSV table.
+#[derive(Debug)]
+pub enum CsvImportError {
+    Io(io::Error),
+    Csv(csv::Error),
+    /// The header row lacks a mapped column.
+    MissingColumn(String),
+    /// The [`CsvBlockSize::Fixed`] extents are negative, NaN or infinite.
+    InvalidBlockSize([f32; 3]),
+    Row(CsvRowError),
+}
+
+impl fmt::Display for CsvImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "failed to read CSV: {error}"),
+            Self::Csv(error) => write!(f, "invalid CSV: {error}"),
+            Self::MissingColumn(column) => write!(f, "CSV has no column `{column}`"),
+            Self::InvalidBlockSize(size) => write!(f, "invalid fixed block size {size:?}"),
+            Self::Row(error) => write!(f, "malformed CSV row, {error}"),
+        }
+    }
+}
+
+impl std::error::Error for CsvImportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Csv(error) => Some(error),
+            Self::Row(error) => Some(error),
+            Self::MissingColumn(_) | Self::InvalidBlockSize(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for CsvImportError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<csv::Error> for CsvImportError {
+    fn from(error: csv::Error) -> Self {
+        Self::Csv(error)
+    }
+}
+
+/// Loads [`CuboidsAsset`]s from CSV tables with [`read_cuboids_csv`], using
+/// the [`CsvColumnMapping`] of the file's settings.
+///
+/// Skipped rows are logged as warnings.
+#[derive(Default)]
+pub struct CuboidsCsvLoader;
+
+impl AssetLoader for CuboidsCsvLoader {
+    type Asset = CuboidsAsset;
+    type Settings = CsvColumnMapping;
+    type Error = CsvImportError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        mapping: &'a CsvColumnMapping,
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<CuboidsAsset, CsvImportError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let import = read_cuboids_csv(bytes.as_slice(), mapping)?;
+            if let Some(first) = import.skipped_rows.first() {
+                warn!(
+                    "Skipped {} malformed rows of {}, the first on {first}",
+                    import.skipped_rows.len(),
+                    load_context.path().display(),
+                );
+            }
+            Ok(CuboidsAsset {
+                cuboids: import.cuboids,
+                materials: Vec::new(),
+                categories: import.categories,
+            })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["csv"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(csv: &str, mapping: &CsvColumnMapping) -> Result<CsvImport, CsvImportError> {
+        read_cuboids_csv(csv.as_bytes(), mapping)
+    }
+
+    fn row_error(csv: &str) -> CsvRowError {
+        match read(csv, &default()) {
+            Err(CsvImportError::Row(error)) => error,
+            result => panic!("expected a malformed row, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn default_columns() {
+        let import = read("Z,y,x,dx,dy,dz\n3,2,1,2,4,6\n", &default()).unwrap();
+        let [cuboid] = import.cuboids.instances() else {
+            panic!("expected one cuboid");
+        };
+        assert_eq!(cuboid.minimum, Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(cuboid.maximum, Vec3::new(2.0, 4.0, 6.0));
+        assert_eq!(cuboid.color, 0);
+    }
+
+    #[test]
+    fn mapped_columns() {
+        let csv = "east;north;rl;grade\n1;2;3;0.5\n4;5;6;\n";
+        let mapping = CsvColumnMapping::default()
+            .with_centroid("east", "north", "rl")
+            .with_size(CsvBlockSize::Fixed([2.0; 3]))
+            .with_color(CsvColorSource::Scalar("grade".into()))
+            .with_missing_value(-1.0)
+            .with_delimiter(b';');
+        let import = read(csv, &mapping).unwrap();
+        let instances = import.cuboids.instances();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].minimum, Vec3::new(0.0, 1.0, 2.0));
+        assert_eq!(instances[1].maximum, Vec3::new(5.0, 6.0, 7.0));
+        assert_eq!(f32::from_bits(instances[0].color), 0.5);
+        assert_eq!(f32::from_bits(instances[1].color), -1.0);
+    }
+
+    #[test]
+    fn rgb_and_category_colors() {
+        let csv = "x,y,z,dx,dy,dz,r,g,b,rock\n\
+                   0,0,0,1,1,1,255,300,-5,granite\n\
+                   0,0,0,1,1,1,0,0,0,basalt\n\
+                   0,0,0,1,1,1,0,0,0,granite\n";
+        let rgb = CsvColumnMapping::default().with_color(CsvColorSource::Rgb([
+            "r".into(),
+            "g".into(),
+            "b".into(),
+        ]));
+        let import = read(csv, &rgb).unwrap();
+        assert_eq!(
+            import.cuboids.instances()[0].color,
+            Color::rgb_u8(255, 255, 0).as_rgba_u32()
+        );
+
+        let category = CsvColumnMapping::default().with_color(CsvColorSource::Category {
+            column: "rock".into(),
+            categories: vec!["basalt".into()],
+        });
+        let import = read(csv, &category).unwrap();
+        let colors: Vec<_> = import.cuboids.instances().iter().map(|c| c.color).collect();
+        assert_eq!(colors, [1, 0, 1]);
+        assert_eq!(import.categories, ["basalt", "granite"]);
+    }
+
+    #[test]
+    fn malformed_rows() {
+        let error = row_error("x,y,z,dx,dy,dz\n0,0,0,1,1,1\n0,0,,1,1,1\n");
+        assert_eq!(error.line, 3);
+        assert_eq!(error.kind, CsvRowErrorKind::MissingField("z".into()));
+
+        let error = row_error("x,y,z,dx,dy,dz\n0,0,0,1,one,1\n");
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            CsvRowErrorKind::InvalidNumber {
+                column: "dy".into(),
+                field: "one".into()
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "line 2: column `dy` is not a number: `one`"
+        );
+
+        let result = read("x,y,dx,dy,dz\n0,0,1,1,1\n", &default());
+        assert!(matches!(result, Err(CsvImportError::MissingColumn(column)) if column == "z"));
+    }
+
+    #[test]
+    fn non_finite_and_negative_values() {
+        let error = row_error("x,y,z,dx,dy,dz\n0,0,0,1,1,1\n0,NaN,0,1,1,1\n");
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.kind,
+            CsvRowErrorKind::NotFinite {
+                column: "y".into(),
+                field: "NaN".into()
+            }
+        );
+
+        let error = row_error("x,y,z,dx,dy,dz\n0,0,0,1,1,inf\n");
+        assert_eq!(error.line, 2);
+        assert!(matches!(error.kind, CsvRowErrorKind::NotFinite { column, .. } if column == "dz"));
+
+        let error = row_error("x,y,z,dx,dy,dz\n-1,-1,-1,1,-2,1\n");
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.kind,
+            CsvRowErrorKind::NegativeSize {
+                column: "dy".into(),
+                field: "-2".into()
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "line 2: column `dy` is a negative size: `-2`"
+        );
+
+        let mapping = CsvColumnMapping::default().with_size(CsvBlockSize::Fixed([1.0, -1.0, 1.0]));
+        let result = read("x,y,z\n0,0,0\n", &mapping);
+        assert!(matches!(result, Err(CsvImportError::InvalidBlockSize(_))));
+    }
+
+    #[test]
+    fn skip_malformed_rows() {
+        let csv = b"x,y,z,dx,dy,dz\n0,0,0,1,1,1\n0,0,0,1,1,-inf\n\xff,0,0,1,1,1\n1,1,1,1,1,1\n";
+        let mapping = CsvColumnMapping::default().with_skip_malformed_rows(true);
+        let import = read_cuboids_csv(csv.as_slice(), &mapping).unwrap();
+        assert_eq!(import.cuboids.instances().len(), 2);
+        let kinds: Vec<_> = import
+            .skipped_rows
+            .iter()
+            .map(|error| (error.line, &error.kind))
+            .collect();
+        assert!(matches!(
+            kinds[..],
+            [
+                (3, CsvRowErrorKind::NotFinite { .. }),
+                (4, CsvRowErrorKind::InvalidUtf8)
+            ]
+        ));
+    }
+}
@@ -1,9 +1,12 @@
 use bevy::{
+    core::{Pod, Zeroable},
+    ecs::component::Tick,
     prelude::*,
     render::{primitives::Aabb, render_resource::ShaderType},
 };
+use std::ops::Range;
 
-use crate::CuboidMaterialId;
+use crate::{CuboidMaterial, OrientedCuboids};
 
 /// Value that determines the color of a [`Cuboid`] based on the associated
 /// [`CuboidMaterial`](crate::CuboidMaterial).
@@ -23,6 +26,7 @@ pub type MetaBits = u32;
 
 /// An axis-aligned box, extending from `minimum` to `maximum`.
 #[derive(Clone, Copy, Debug, ShaderType)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct Cuboid {
     pub minimum: Vec3,
@@ -31,6 +35,11 @@ pub struct Cuboid {
     pub color: Color,
 }
 
+// SAFETY: `Cuboid` is `repr(C)` and only contains 4-byte scalars, so it has no
+// padding and every bit pattern is valid.
+unsafe impl Zeroable for Cuboid {}
+unsafe impl Pod for Cuboid {}
+
 impl Cuboid {
     pub fn new(minimum: Vec3, maximum: Vec3, color: u32) -> Self {
         assert_eq!(std::mem::size_of::<Cuboid>(), 32);
@@ -42,6 +51,16 @@ impl Cuboid {
         }
     }
 
+    #[inline]
+    pub fn center(&self) -> Vec3 {
+        (self.minimum + self.maximum) / 2.0
+    }
+
+    #[inline]
+    pub fn is_invisible(&self) -> bool {
+        self.meta_bits & 1 != 0
+    }
+
     #[inline]
     pub fn make_visible(&mut self) -> &mut Self {
         self.meta_bits &= !1;
@@ -76,14 +95,86 @@ impl Cuboid {
 
 /// A set of cuboids to be extracted for rendering.
 #[derive(Clone, Component, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cuboids {
-    /// Instances to be rendered.
-    pub instances: Vec<Cuboid>,
+    instances: Vec<Cuboid>,
+    /// Instances modified since the last extraction.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    dirty_ranges: DirtyRanges,
+    /// The last change before `dirty_ranges` were cleared.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    dirty_ranges_after: Option<Tick>,
 }
 
 impl Cuboids {
     pub fn new(instances: Vec<Cuboid>) -> Self {
-        Self { instances }
+        Self {
+            instances,
+            dirty_ranges: default(),
+            dirty_ranges_after: None,
+        }
+    }
+
+    /// Instances to be rendered.
+    pub fn instances(&self) -> &[Cuboid] {
+        &self.instances
+    }
+
+    /// Mutably borrow all instances, e.g. to add or remove some, marking the
+    /// whole instance buffer for upload.
+    ///
+    /// Use [`Cuboids::get_mut`] or [`Cuboids::range_mut`] to only upload the
+    /// modified instances.
+    pub fn instances_mut(&mut self) -> &mut Vec<Cuboid> {
+        self.dirty_ranges.insert_all();
+        &mut self.instances
+    }
+
+    pub fn into_instances(self) -> Vec<Cuboid> {
+        self.instances
+    }
+
+    /// Mutably borrow a single instance, marking it for a partial upload.
+    pub fn get_mut(&mut self, index: usize) -> &mut Cuboid {
+        self.range_mut(index..index + 1).first_mut().unwrap()
+    }
+
+    /// Mutably borrow a contiguous range of instances, marking it for a partial
+    /// upload.
+    ///
+    /// Only the ranges borrowed this way are written to the GPU, unless
+    /// [`Cuboids::instances_mut`] was also called or the number of instances
+    /// has changed since the last extraction, in which case the whole buffer
+    /// is re-uploaded.
+    pub fn range_mut(&mut self, range: Range<usize>) -> &mut [Cuboid] {
+        let slice = &mut self.instances[range.clone()];
+        self.dirty_ranges.insert(range);
+        slice
+    }
+
+    /// Ranges of instances modified with [`Cuboids::get_mut`] or
+    /// [`Cuboids::range_mut`] since the last extraction.
+    ///
+    /// An empty slice means any change to this component requires a full
+    /// upload, e.g. after [`Cuboids::instances_mut`].
+    pub fn dirty_ranges(&self) -> &[Range<usize>] {
+        &self.dirty_ranges.ranges
+    }
+
+    /// Returns the dirty ranges if they hold every instance modified since the
+    /// change at `last_seen`, e.g. to update data derived from the instances
+    /// at that change.
+    pub(crate) fn dirty_ranges_since(&self, last_seen: Tick) -> Option<&[Range<usize>]> {
+        let complete = self.dirty_ranges_after == Some(last_seen);
+        (complete && !self.dirty_ranges.ranges.is_empty()).then_some(&self.dirty_ranges.ranges)
+    }
+
+    pub(crate) fn clear_dirty_ranges(&mut self) {
+        self.dirty_ranges.clear();
+    }
+
+    pub(crate) fn has_dirty_ranges(&self) -> bool {
+        !self.dirty_ranges.is_clear()
     }
 
     /// Automatically creates an [`Aabb`] that bounds all `instances`.
@@ -98,19 +189,114 @@ impl Cuboids {
     }
 }
 
+/// Sorted, non-overlapping ranges of modified instances.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DirtyRanges {
+    /// Always empty while `all` is set.
+    pub ranges: Vec<Range<usize>>,
+    /// All instances were borrowed at once, so all of them must be uploaded.
+    pub all: bool,
+}
+
+impl DirtyRanges {
+    /// Past this many disjoint ranges, we merge everything into a single range
+    /// to bound the number of buffer writes.
+    const MAX_RANGES: usize = 64;
+
+    pub fn insert(&mut self, range: Range<usize>) {
+        if self.all || range.is_empty() {
+            return;
+        }
+
+        // Find the first range that ends at or after `range.start`, then merge
+        // every range that touches `range`.
+        let first = self.ranges.partition_point(|r| r.end < range.start);
+        let mut last = first;
+        let mut merged = range;
+        while last < self.ranges.len() && self.ranges[last].start <= merged.end {
+            merged.start = merged.start.min(self.ranges[last].start);
+            merged.end = merged.end.max(self.ranges[last].end);
+            last += 1;
+        }
+        self.ranges.splice(first..last, [merged]);
+
+        if self.ranges.len() > Self::MAX_RANGES {
+            let start = self.ranges.first().unwrap().start;
+            let end = self.ranges.last().unwrap().end;
+            self.ranges.clear();
+            self.ranges.push(start..end);
+        }
+    }
+
+    pub fn insert_all(&mut self) {
+        self.all = true;
+        self.ranges.clear();
+    }
+
+    pub fn is_clear(&self) -> bool {
+        !self.all && self.ranges.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.all = false;
+        self.ranges.clear();
+    }
+}
+
+pub(crate) fn clear_cuboids_dirty_ranges(
+    mut cuboids: Query<&mut Cuboids>,
+    mut oriented_cuboids: Query<&mut OrientedCuboids>,
+) {
+    // Don't trigger change detection, the ranges have already been extracted.
+    for mut cuboids in cuboids.iter_mut() {
+        let last_changed = cuboids.last_changed();
+        let cuboids = cuboids.bypass_change_detection();
+        cuboids.dirty_ranges_after = Some(last_changed);
+        if cuboids.has_dirty_ranges() {
+            cuboids.clear_dirty_ranges();
+        }
+    }
+    for mut cuboids in oriented_cuboids.iter_mut() {
+        if cuboids.has_dirty_ranges() {
+            cuboids.bypass_change_detection().clear_dirty_ranges();
+        }
+    }
+}
+
 #[derive(Clone, ShaderType)]
 pub(crate) struct CuboidsTransform {
     pub matrix: Mat4,
     pub inv_matrix: Mat4,
+    /// [`Entity::to_bits`] split into low and high words, written by GPU
+    /// picking.
+    pub entity: UVec2,
+    /// [`ClippingGroups`](crate::ClippingGroups) bits of the entity.
+    pub clipping_groups: u32,
+    /// Number of [`CuboidAttributes`](crate::CuboidAttributes) per instance,
+    /// or 0 if they aren't bound.
+    pub num_attributes: u32,
 }
 
 impl CuboidsTransform {
-    pub fn new(matrix: Mat4, inv_matrix: Mat4) -> Self {
-        Self { matrix, inv_matrix }
+    pub fn new(
+        matrix: Mat4,
+        inv_matrix: Mat4,
+        entity: Entity,
+        clipping_groups: u32,
+        num_attributes: u32,
+    ) -> Self {
+        let bits = entity.to_bits();
+        Self {
+            matrix,
+            inv_matrix,
+            entity: UVec2::new(bits as u32, (bits >> 32) as u32),
+            clipping_groups,
+            num_attributes,
+        }
     }
 
-    pub fn from_matrix(m: Mat4) -> Self {
-        Self::new(m, m.inverse())
+    pub fn from_matrix(m: Mat4, entity: Entity, clipping_groups: u32, num_attributes: u32) -> Self {
+        Self::new(m, m.inverse(), entity, clipping_groups, num_attributes)
     }
 
     pub fn position(&self) -> Vec3 {
@@ -120,7 +306,105 @@ impl CuboidsTransform {
 
 #[derive(Bundle)]
 pub struct CuboidsBundle {
-    pub material_id: CuboidMaterialId,
+    pub material: Handle<CuboidMaterial>,
     pub cuboids: Cuboids,
     pub spatial: SpatialBundle,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(ranges: &[Range<usize>]) -> Vec<(usize, usize)> {
+        ranges
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    fn cuboids(len: usize) -> Cuboids {
+        Cuboids::new(vec![Cuboid::new(Vec3::ZERO, Vec3::ONE, 0); len])
+    }
+
+    #[test]
+    fn dirty_ranges_stay_sorted_and_disjoint() {
+        let mut dirty = DirtyRanges::default();
+        dirty.insert(30..40);
+        dirty.insert(0..5);
+        dirty.insert(10..20);
+        dirty.insert(7..7);
+        assert_eq!(spans(&dirty.ranges), [(0, 5), (10, 20), (30, 40)]);
+    }
+
+    #[test]
+    fn dirty_ranges_merge_touching_ranges() {
+        let mut dirty = DirtyRanges::default();
+        dirty.insert(0..5);
+        dirty.insert(10..20);
+        dirty.insert(30..40);
+
+        // Adjacent on both sides.
+        dirty.insert(5..10);
+        assert_eq!(spans(&dirty.ranges), [(0, 20), (30, 40)]);
+
+        // Overlapping several ranges.
+        dirty.insert(15..35);
+        assert_eq!(spans(&dirty.ranges), [(0, 40)]);
+
+        // Contained in an existing range.
+        dirty.insert(2..3);
+        assert_eq!(spans(&dirty.ranges), [(0, 40)]);
+    }
+
+    #[test]
+    fn dirty_ranges_collapse_past_the_limit() {
+        let mut dirty = DirtyRanges::default();
+        for i in 0..DirtyRanges::MAX_RANGES {
+            dirty.insert(2 * i..2 * i + 1);
+        }
+        assert_eq!(dirty.ranges.len(), DirtyRanges::MAX_RANGES);
+
+        dirty.insert(1000..1001);
+        assert_eq!(spans(&dirty.ranges), [(0, 1001)]);
+    }
+
+    #[test]
+    fn dirty_ranges_insert_all_overrides_ranges() {
+        let mut dirty = DirtyRanges::default();
+        dirty.insert(0..5);
+        dirty.insert_all();
+        dirty.insert(10..20);
+        assert!(dirty.ranges.is_empty());
+        assert!(!dirty.is_clear());
+
+        dirty.clear();
+        assert!(dirty.is_clear());
+        dirty.insert(10..20);
+        assert_eq!(spans(&dirty.ranges), [(10, 20)]);
+    }
+
+    #[test]
+    fn tracked_edits_mark_ranges() {
+        let mut cuboids = cuboids(10);
+        cuboids.get_mut(3).color = 1;
+        cuboids.range_mut(6..8)[0].color = 2;
+        assert_eq!(spans(cuboids.dirty_ranges()), [(3, 4), (6, 8)]);
+        assert!(cuboids.has_dirty_ranges());
+
+        cuboids.clear_dirty_ranges();
+        assert!(cuboids.dirty_ranges().is_empty());
+        assert!(!cuboids.has_dirty_ranges());
+    }
+
+    #[test]
+    fn direct_edits_are_not_hidden_by_tracked_edits() {
+        let mut cuboids = cuboids(10);
+        cuboids.get_mut(3).color = 1;
+        cuboids.instances_mut()[8].color = 2;
+        cuboids.get_mut(5).color = 3;
+
+        // An empty slice requests a full upload.
+        assert!(cuboids.dirty_ranges().is_empty());
+        assert!(cuboids.has_dirty_ranges());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{read_cuboids_file, CuboidMaterial, Cuboids, CuboidsFileError};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::{BoxedFuture, HashSet},
+};
+
+/// [`Cuboids`] loaded through the [`AssetServer`], from a cuboids file (see
+/// [`CuboidsFileHeader`](crate::CuboidsFileHeader)) with the `.cuboids`
+/// extension or, with the `csv` feature, from a `.csv` block model table (see
+/// [`CuboidsCsvLoader`](crate::CuboidsCsvLoader)).
+///
+/// Entities with a `Handle<CuboidsAsset>` get a copy of the [`Cuboids`] and
+/// their [`Aabb`](bevy::render::primitives::Aabb) once the asset is loaded,
+/// which are replaced whenever the asset or the handle change, e.g. when the
+/// file is hot reloaded. Entities without a `Handle<CuboidMaterial>` also get
+/// the first material of the file, or the default material if it has none.
+#[derive(Asset, Clone, Debug, TypePath)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsAsset {
+    pub cuboids: Cuboids,
+    /// The materials stored in the file, as the labeled assets `Material0`,
+    /// `Material1`, etc.
+    ///
+    /// Left out with the `serde` feature, since handles can't be serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub materials: Vec<Handle<CuboidMaterial>>,
+    /// The name of each category in
+    /// [`COLOR_MODE_CATEGORICAL`](crate::COLOR_MODE_CATEGORICAL), by index, if
+    /// the source has them.
+    pub categories: Vec<String>,
+}
+
+#[derive(Bundle, Default)]
+pub struct CuboidsAssetBundle {
+    pub asset: Handle<CuboidsAsset>,
+    pub spatial: SpatialBundle,
+}
+
+/// Loads [`CuboidsAsset`]s from cuboids files.
+#[derive(Default)]
+pub struct CuboidsFileLoader;
+
+impl AssetLoader for CuboidsFileLoader {
+    type Asset = CuboidsAsset;
+    type Settings = ();
+    type Error = CuboidsFileError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<CuboidsAsset, CuboidsFileError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let (cuboids, materials) = read_cuboids_file(bytes.as_slice())?;
+            let materials = materials
+                .into_iter()
+                .enumerate()
+                .map(|(i, material)| {
+                    load_context.add_labeled_asset(format!("Material{i}"), material)
+                })
+                .collect();
+            Ok(CuboidsAsset {
+                cuboids,
+                materials,
+                categories: Vec::new(),
+            })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cuboids"]
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn sync_cuboids_assets(
+    mut commands: Commands,
+    assets: Res<Assets<CuboidsAsset>>,
+    mut asset_events: EventReader<AssetEvent<CuboidsAsset>>,
+    entities: Query<(
+        Entity,
+        Ref<Handle<CuboidsAsset>>,
+        Has<Handle<CuboidMaterial>>,
+    )>,
+) {
+    let changed_assets: HashSet<AssetId<CuboidsAsset>> = asset_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+    for (entity, handle, has_material) in entities.iter() {
+        if !handle.is_changed() && !changed_assets.contains(&handle.id()) {
+            continue;
+        }
+        let Some(asset) = assets.get(handle.as_ref()) else {
+            continue;
+        };
+        let mut entity = commands.entity(entity);
+        entity.insert((asset.cuboids.clone(), asset.cuboids.aabb()));
+        if !has_material {
+            entity.insert(asset.materials.first().cloned().unwrap_or_default());
+        }
+    }
+}
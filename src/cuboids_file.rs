@@ -0,0 +1,539 @@
+use crate::{Cuboid, CuboidMaterial, Cuboids};
+
+use bevy::{
+    core::cast_slice,
+    prelude::*,
+    render::render_resource::{encase::StorageBuffer, ShaderType},
+};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Version of the cuboids file format written by this crate.
+///
+/// Bump this whenever the layout of [`Cuboid`] or [`CuboidMaterial`] changes.
+pub const CUBOIDS_FILE_VERSION: u32 = 2;
+
+const MAGIC: [u8; 8] = *b"AABBCUBS";
+
+/// Number of [`Cuboid`]s decoded at once by [`CuboidsReader`].
+const READ_CHUNK: usize = 64 * 1024;
+
+/// The fixed-size header at the start of a cuboids file.
+///
+/// A cuboids file is a compact container for a block model, laid out as:
+///
+/// - the 32 byte header: the magic bytes `AABBCUBS`, then the `u32` version,
+///   the `u32` size of a material record, the `u64` number of cuboids, the
+///   `u32` number of materials and 4 reserved bytes
+/// - `num_cuboids` raw 32 byte [`Cuboid`] records
+/// - `num_materials` [`CuboidMaterial`] records in their GPU storage layout
+///
+/// All values are little-endian. Since the records follow the 32 byte header
+/// directly, a memory-mapped file can be used as a `&[Cuboid]` without copying,
+/// see [`MappedCuboidsFile`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsFileHeader {
+    pub version: u32,
+    pub num_cuboids: u64,
+    pub num_materials: u32,
+}
+
+impl CuboidsFileHeader {
+    /// Size of the header in bytes.
+    pub const SIZE: usize = 32;
+
+    fn new(num_cuboids: u64, num_materials: u32) -> Self {
+        Self {
+            version: CUBOIDS_FILE_VERSION,
+            num_cuboids,
+            num_materials,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&material_size().to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.num_cuboids.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.num_materials.to_le_bytes());
+        bytes
+    }
+
+    fn parse(bytes: &[u8; Self::SIZE]) -> Result<Self, CuboidsFileError> {
+        if bytes[0..8] != MAGIC {
+            return Err(CuboidsFileError::NotACuboidsFile);
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let version = u32_at(8);
+        if version != CUBOIDS_FILE_VERSION {
+            return Err(CuboidsFileError::UnsupportedVersion(version));
+        }
+        let found = u32_at(12);
+        if found != material_size() {
+            return Err(CuboidsFileError::MaterialLayout {
+                expected: material_size(),
+                found,
+            });
+        }
+        Ok(Self {
+            version,
+            num_cuboids: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            num_materials: u32_at(24),
+        })
+    }
+
+    /// Size of the cuboid records in bytes.
+    fn cuboids_size(&self) -> Result<usize, CuboidsFileError> {
+        usize::try_from(self.num_cuboids)
+            .ok()
+            .and_then(|n| n.checked_mul(std::mem::size_of::<Cuboid>()))
+            .ok_or(CuboidsFileError::TooLarge)
+    }
+
+    /// Size of the material records in bytes.
+    fn materials_size(&self) -> Result<usize, CuboidsFileError> {
+        usize::try_from(self.num_materials)
+            .ok()
+            .and_then(|n| n.checked_mul(material_size() as usize))
+            .ok_or(CuboidsFileError::TooLarge)
+    }
+}
+
+/// An error encountered while reading a cuboids file.
+#[derive(Debug)]
+pub enum CuboidsFileError {
+    Io(io::Error),
+    /// The file doesn't start with the magic bytes of a cuboids file.
+    NotACuboidsFile,
+    /// The file was written by an incompatible version of this crate.
+    UnsupportedVersion(u32),
+    /// The materials in the file don't match the layout of [`CuboidMaterial`].
+    MaterialLayout {
+        expected: u32,
+        found: u32,
+    },
+    /// The file ends before all records declared by its header.
+    Truncated,
+    /// The cuboid records don't fit into the address space.
+    TooLarge,
+}
+
+impl fmt::Display for CuboidsFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "failed to read cuboids file: {error}"),
+            Self::NotACuboidsFile => write!(f, "not a cuboids file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported cuboids file version {version}, expected {CUBOIDS_FILE_VERSION}"
+            ),
+            Self::MaterialLayout { expected, found } => write!(
+                f,
+                "cuboids file has {found} byte materials, expected {expected} bytes"
+            ),
+            Self::Truncated => write!(f, "cuboids file is truncated"),
+            Self::TooLarge => write!(f, "cuboids file is too large for this platform"),
+        }
+    }
+}
+
+impl std::error::Error for CuboidsFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CuboidsFileError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(error)
+        }
+    }
+}
+
+/// Writes a complete cuboids file, e.g. to a `BufWriter<File>`.
+///
+/// Use [`CuboidsWriter`] instead to write instances that don't fit into memory
+/// at once.
+pub fn write_cuboids_file(
+    mut writer: impl Write,
+    cuboids: &[Cuboid],
+    materials: &[CuboidMaterial],
+) -> io::Result<()> {
+    let header = CuboidsFileHeader::new(cuboids.len() as u64, materials.len() as u32);
+    writer.write_all(&header.to_bytes())?;
+    write_cuboid_records(&mut writer, cuboids)?;
+    write_material_records(&mut writer, materials)
+}
+
+/// Reads a complete cuboids file, e.g. from a `BufReader<File>` or a `&[u8]`.
+pub fn read_cuboids_file(
+    reader: impl Read,
+) -> Result<(Cuboids, Vec<CuboidMaterial>), CuboidsFileError> {
+    let mut reader = CuboidsReader::new(reader)?;
+    // All instances must fit into memory.
+    reader.header().cuboids_size()?;
+    let mut instances = Vec::new();
+    while reader.read(&mut instances, usize::MAX)? > 0 {}
+    let materials = reader.materials()?;
+    Ok((Cuboids::new(instances), materials))
+}
+
+/// Writes a cuboids file incrementally, so that the instances never need to be
+/// in memory at once.
+///
+/// The counts in the header are filled in by [`Self::finish`], which is why the
+/// writer must be seekable.
+pub struct CuboidsWriter<W: Write + Seek> {
+    inner: W,
+    start: u64,
+    num_cuboids: u64,
+}
+
+impl<W: Write + Seek> CuboidsWriter<W> {
+    /// Starts a cuboids file at the current position of `inner`.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let start = inner.stream_position()?;
+        inner.write_all(&CuboidsFileHeader::new(0, 0).to_bytes())?;
+        Ok(Self {
+            inner,
+            start,
+            num_cuboids: 0,
+        })
+    }
+
+    /// Appends `cuboids` to the file.
+    pub fn write(&mut self, cuboids: &[Cuboid]) -> io::Result<()> {
+        write_cuboid_records(&mut self.inner, cuboids)?;
+        self.num_cuboids += cuboids.len() as u64;
+        Ok(())
+    }
+
+    /// Number of cuboids written so far.
+    pub fn num_cuboids(&self) -> u64 {
+        self.num_cuboids
+    }
+
+    /// Appends the `materials` and completes the header, returning the inner
+    /// writer positioned at the end of the file.
+    pub fn finish(mut self, materials: &[CuboidMaterial]) -> io::Result<W> {
+        write_material_records(&mut self.inner, materials)?;
+        let end = self.inner.stream_position()?;
+        let header = CuboidsFileHeader::new(self.num_cuboids, materials.len() as u32);
+        self.inner.seek(SeekFrom::Start(self.start))?;
+        self.inner.write_all(&header.to_bytes())?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a cuboids file incrementally, e.g. to filter the instances of a file
+/// that doesn't fit into memory.
+pub struct CuboidsReader<R: Read> {
+    inner: R,
+    header: CuboidsFileHeader,
+    remaining: u64,
+    scratch: Vec<u8>,
+}
+
+impl<R: Read> CuboidsReader<R> {
+    /// Reads and validates the header.
+    pub fn new(mut inner: R) -> Result<Self, CuboidsFileError> {
+        let mut bytes = [0; CuboidsFileHeader::SIZE];
+        inner.read_exact(&mut bytes)?;
+        let header = CuboidsFileHeader::parse(&bytes)?;
+        Ok(Self {
+            inner,
+            header,
+            remaining: header.num_cuboids,
+            scratch: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &CuboidsFileHeader {
+        &self.header
+    }
+
+    /// Appends up to `max` of the remaining cuboids to `instances`, returning
+    /// how many were read. Returns 0 once all cuboids have been read.
+    pub fn read(
+        &mut self,
+        instances: &mut Vec<Cuboid>,
+        max: usize,
+    ) -> Result<usize, CuboidsFileError> {
+        let count = self.remaining.min(max.min(READ_CHUNK) as u64) as usize;
+        self.scratch
+            .resize(count * std::mem::size_of::<Cuboid>(), 0);
+        self.inner.read_exact(&mut self.scratch)?;
+        instances.extend(self.scratch.chunks_exact(32).map(decode_cuboid));
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+
+    /// Skips any cuboids that haven't been read and reads the materials.
+    pub fn materials(mut self) -> Result<Vec<CuboidMaterial>, CuboidsFileError> {
+        let unread = self
+            .remaining
+            .saturating_mul(std::mem::size_of::<Cuboid>() as u64);
+        let skipped = io::copy(&mut (&mut self.inner).take(unread), &mut io::sink())?;
+        if skipped < unread {
+            return Err(CuboidsFileError::Truncated);
+        }
+        // Only allocate what the file actually holds, whatever its header says.
+        let size = self.header.materials_size()?;
+        let mut bytes = Vec::new();
+        (&mut self.inner)
+            .take(size as u64)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() < size {
+            return Err(CuboidsFileError::Truncated);
+        }
+        Ok(decode_materials(&bytes))
+    }
+}
+
+/// A cuboids file mapped into memory, whose instances are read lazily by the
+/// OS as they're accessed.
+///
+/// This is the fastest way to load multi-gigabyte files, since the instances
+/// can be copied straight from the page cache into a [`Cuboids`], or filtered
+/// without reading the whole file.
+#[cfg(feature = "mmap")]
+pub struct MappedCuboidsFile {
+    mmap: memmap2::Mmap,
+    header: CuboidsFileHeader,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedCuboidsFile {
+    /// Maps the file at `path` and validates its header and size.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it's mapped, see
+    /// [`memmap2::Mmap::map`].
+    pub unsafe fn open(path: impl AsRef<std::path::Path>) -> Result<Self, CuboidsFileError> {
+        let file = std::fs::File::open(path)?;
+        let mmap = memmap2::Mmap::map(&file)?;
+        let bytes: &[u8; CuboidsFileHeader::SIZE] = mmap
+            .get(..CuboidsFileHeader::SIZE)
+            .ok_or(CuboidsFileError::Truncated)?
+            .try_into()
+            .unwrap();
+        let header = CuboidsFileHeader::parse(bytes)?;
+        let size = CuboidsFileHeader::SIZE
+            .checked_add(header.cuboids_size()?)
+            .and_then(|size| size.checked_add(header.materials_size().ok()?))
+            .ok_or(CuboidsFileError::TooLarge)?;
+        if mmap.len() < size {
+            return Err(CuboidsFileError::Truncated);
+        }
+        Ok(Self { mmap, header })
+    }
+
+    pub fn header(&self) -> &CuboidsFileHeader {
+        &self.header
+    }
+
+    /// The cuboid records, borrowed from the mapping on little-endian
+    /// platforms.
+    pub fn cuboids(&self) -> std::borrow::Cow<'_, [Cuboid]> {
+        let start = CuboidsFileHeader::SIZE;
+        // The sizes were validated when the file was opened.
+        let bytes = &self.mmap[start..start + self.header.cuboids_size().unwrap()];
+        cuboids_from_bytes(bytes)
+    }
+
+    pub fn materials(&self) -> Vec<CuboidMaterial> {
+        let start = CuboidsFileHeader::SIZE + self.header.cuboids_size().unwrap();
+        decode_materials(&self.mmap[start..start + self.header.materials_size().unwrap()])
+    }
+}
+
+/// Reinterprets little-endian records as [`Cuboid`]s, only copying them if
+/// they're misaligned or the platform is big-endian.
+#[cfg(feature = "mmap")]
+fn cuboids_from_bytes(bytes: &[u8]) -> std::borrow::Cow<'_, [Cuboid]> {
+    use std::borrow::Cow;
+
+    let aligned = bytes.as_ptr().align_offset(std::mem::align_of::<Cuboid>()) == 0;
+    if cfg!(target_endian = "little") && aligned {
+        Cow::Borrowed(cast_slice(bytes))
+    } else {
+        Cow::Owned(bytes.chunks_exact(32).map(decode_cuboid).collect())
+    }
+}
+
+fn decode_cuboid(bytes: &[u8]) -> Cuboid {
+    let word = |i: usize| u32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+    let vec3 = |i: usize| {
+        Vec3::new(
+            f32::from_bits(word(i)),
+            f32::from_bits(word(i + 1)),
+            f32::from_bits(word(i + 2)),
+        )
+    };
+    Cuboid {
+        minimum: vec3(0),
+        meta_bits: word(3),
+        maximum: vec3(4),
+        color: word(7),
+    }
+}
+
+fn write_cuboid_records(writer: &mut impl Write, cuboids: &[Cuboid]) -> io::Result<()> {
+    if cfg!(target_endian = "little") {
+        return writer.write_all(cast_slice(cuboids));
+    }
+    for cuboid in cuboids {
+        let words = [
+            cuboid.minimum.x.to_bits(),
+            cuboid.minimum.y.to_bits(),
+            cuboid.minimum.z.to_bits(),
+            cuboid.meta_bits,
+            cuboid.maximum.x.to_bits(),
+            cuboid.maximum.y.to_bits(),
+            cuboid.maximum.z.to_bits(),
+            cuboid.color,
+        ];
+        for word in words {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Size of a [`CuboidMaterial`] in its GPU storage layout.
+fn material_size() -> u32 {
+    CuboidMaterial::min_size().get() as u32
+}
+
+fn write_material_records(writer: &mut impl Write, materials: &[CuboidMaterial]) -> io::Result<()> {
+    for material in materials {
+        let mut buffer = StorageBuffer::new(Vec::new());
+        buffer.write(material).unwrap();
+        writer.write_all(&buffer.into_inner())?;
+    }
+    Ok(())
+}
+
+fn decode_materials(bytes: &[u8]) -> Vec<CuboidMaterial> {
+    bytes
+        .chunks_exact(material_size() as usize)
+        .map(|record| StorageBuffer::new(record).create().unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cuboids() -> Vec<Cuboid> {
+        (0..5)
+            .map(|i| {
+                let min = Vec3::splat(i as f32);
+                Cuboid::new(min, min + Vec3::new(0.5, 1.0, 2.0), 0xff00_0000 | i)
+            })
+            .collect()
+    }
+
+    fn materials() -> Vec<CuboidMaterial> {
+        vec![
+            CuboidMaterial::default(),
+            CuboidMaterial {
+                wireframe: 1,
+                opacity: 0.5,
+                emissive_gain: Vec3::new(1.0, 2.0, 3.0),
+                ..default()
+            },
+        ]
+    }
+
+    fn assert_same_materials(actual: &[CuboidMaterial], expected: &[CuboidMaterial]) {
+        assert_eq!(format!("{actual:?}"), format!("{expected:?}"));
+    }
+
+    fn bytes(cuboids: &[Cuboid]) -> &[u8] {
+        cast_slice(cuboids)
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let mut file = Vec::new();
+        write_cuboids_file(&mut file, &cuboids(), &materials()).unwrap();
+        assert_eq!(
+            file.len(),
+            CuboidsFileHeader::SIZE + 5 * 32 + 2 * material_size() as usize
+        );
+
+        let (read, read_materials) = read_cuboids_file(file.as_slice()).unwrap();
+        assert_eq!(bytes(read.instances()), bytes(&cuboids()));
+        assert_same_materials(&read_materials, &materials());
+    }
+
+    #[test]
+    fn incremental_round_trip() {
+        let mut writer = CuboidsWriter::new(io::Cursor::new(Vec::new())).unwrap();
+        writer.write(&cuboids()[..2]).unwrap();
+        writer.write(&cuboids()[2..]).unwrap();
+        assert_eq!(writer.num_cuboids(), 5);
+        let file = writer.finish(&materials()).unwrap().into_inner();
+
+        let mut reader = CuboidsReader::new(file.as_slice()).unwrap();
+        assert_eq!(
+            *reader.header(),
+            CuboidsFileHeader::new(5, materials().len() as u32)
+        );
+        let mut instances = Vec::new();
+        assert_eq!(reader.read(&mut instances, 3).unwrap(), 3);
+        assert_eq!(bytes(&instances), bytes(&cuboids()[..3]));
+        // The unread instances are skipped.
+        assert_same_materials(&reader.materials().unwrap(), &materials());
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut file = Vec::new();
+        write_cuboids_file(&mut file, &cuboids(), &materials()).unwrap();
+
+        let result = read_cuboids_file(&file[..file.len() - 1]);
+        assert!(matches!(result, Err(CuboidsFileError::Truncated)));
+
+        let mut not_cuboids = file.clone();
+        not_cuboids[0] = b'X';
+        let result = read_cuboids_file(not_cuboids.as_slice());
+        assert!(matches!(result, Err(CuboidsFileError::NotACuboidsFile)));
+
+        let mut future = file.clone();
+        future[8..12].copy_from_slice(&(CUBOIDS_FILE_VERSION + 1).to_le_bytes());
+        let result = read_cuboids_file(future.as_slice());
+        assert!(matches!(
+            result,
+            Err(CuboidsFileError::UnsupportedVersion(v)) if v == CUBOIDS_FILE_VERSION + 1
+        ));
+
+        let mut huge = file.clone();
+        huge[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        let result = read_cuboids_file(huge.as_slice());
+        assert!(matches!(
+            result,
+            Err(CuboidsFileError::Truncated | CuboidsFileError::TooLarge)
+        ));
+
+        let mut other_layout = file;
+        other_layout[12..16].copy_from_slice(&(material_size() + 16).to_le_bytes());
+        let result = read_cuboids_file(other_layout.as_slice());
+        assert!(matches!(
+            result,
+            Err(CuboidsFileError::MaterialLayout { .. })
+        ));
+    }
+}
@@ -14,11 +14,31 @@
 //! # Features
 //!
 //! - vertex pulling renderer
+//! - optional GPU-driven frustum culling with indirect draws
+//! - optional batching of entities that share a material into a handful of draw calls
 //! - cuboid edge shading
+//! - optional directional and ambient lighting
+//! - shadow casting into Bevy's shadow maps with the `pbr` feature
+//! - depth, normal and motion vector prepass support for SSAO, TAA and depth of field
 //! - edge-only wireframes
-//! - clipping planes
-//! - multiple color modes: RGB and Linear-Range Scalar
+//! - clipping planes, boxes and spheres, scoped to entities with clipping groups
+//! - optional precise clipping that cuts cuboids along clipping planes and caps the cross-sections
+//! - per-camera material and clipping overrides, with render layers per entity
+//! - optional per-instance rotations
+//! - materials as Bevy assets, optionally loaded from RON files, with hot reloading
+//! - persistent material and transform uniforms that are only re-uploaded when they change
+//! - multiple color modes: RGB, Linear-Range Scalar, Scalar Colormap and Categorical
+//! - transparency with per-material opacity or per-instance alpha
 //! - depth jitter to counteract z-fighting of coplanar cuboids
+//! - partial instance buffer updates for sparse edits
+//! - CPU ray picking of individual cuboid instances
+//! - optional GPU ID-buffer picking of individual cuboid instances
+//! - optional bounding volume hierarchy for ray, region and nearest-neighbor queries
+//! - optional levels of detail that merge distant blocks into coarser ones
+//! - a compact binary file format for cuboids, with optional memory mapping, and optional `serde` support
+//! - loading cuboids as Bevy assets from that format, with hot reloading
+//! - optional CSV block model import, also as Bevy assets, with configurable column mapping and per-row error reporting
+//! - multiple scalar attributes per cuboid, with the colored and filtered attributes switched by the material
 //!
 //! # License
 //!
@@ -34,12 +54,42 @@
 //! src="https://user-images.githubusercontent.com/2632925/151242316-db3455d1-4934-4374-8369-1818daf512dd.png"
 //! alt="Foresight Mining Software Corporation" width="480">
 
+mod attributes;
+mod bvh;
 mod clipping_planes;
+mod colormap;
+#[cfg(feature = "csv")]
+mod csv_import;
 mod cuboids;
+mod cuboids_asset;
+mod cuboids_file;
+mod lighting;
+mod lod;
 mod material;
+#[cfg(feature = "serde")]
+mod material_loader;
+mod oriented_cuboids;
+mod palette;
+mod picking;
 mod vertex_pulling;
+mod view_overrides;
 
+pub use attributes::*;
+pub use bvh::*;
 pub use clipping_planes::*;
+pub use colormap::*;
+#[cfg(feature = "csv")]
+pub use csv_import::*;
 pub use cuboids::*;
+pub use cuboids_asset::*;
+pub use cuboids_file::*;
+pub use lighting::*;
+pub use lod::*;
 pub use material::*;
+#[cfg(feature = "serde")]
+pub use material_loader::*;
+pub use oriented_cuboids::*;
+pub use palette::*;
+pub use picking::*;
 pub use vertex_pulling::plugin::*;
+pub use view_overrides::*;
@@ -0,0 +1,73 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// Scene lighting applied to cuboids whose
+/// [`CuboidMaterial::lighting`](crate::CuboidMaterial::lighting) is enabled.
+///
+/// Each visible face is shaded as `color * (ambient + light * max(n·l, 0))`.
+/// Emissive cuboids are never shaded.
+///
+/// With the `pbr` feature, this resource follows the first
+/// [`DirectionalLight`](bevy::pbr::DirectionalLight) and the
+/// [`AmbientLight`](bevy::pbr::AmbientLight) in the world, if there are any.
+/// Cuboids aren't exposure corrected like PBR meshes, so the light's color is
+/// scaled by its illuminance relative to the default of 100000 lux, which
+/// maps to `light.color` unchanged.
+#[derive(Clone, Debug, PartialEq, Resource)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidLighting {
+    /// World space direction that the light travels.
+    pub direction: Vec3,
+    pub color: Color,
+    pub ambient: Color,
+}
+
+impl Default for CuboidLighting {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::new(-1.0, -2.0, -1.5).normalize(),
+            color: Color::rgb(0.7, 0.7, 0.7),
+            ambient: Color::rgb(0.3, 0.3, 0.3),
+        }
+    }
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuCuboidLighting {
+    pub direction: Vec3,
+    pub color: Vec3,
+    pub ambient: Vec3,
+}
+
+impl From<&CuboidLighting> for GpuCuboidLighting {
+    fn from(lighting: &CuboidLighting) -> Self {
+        let [r, g, b, _] = lighting.color.as_rgba_f32();
+        let [ar, ag, ab, _] = lighting.ambient.as_rgba_f32();
+        Self {
+            direction: lighting.direction.normalize_or_zero(),
+            color: Vec3::new(r, g, b),
+            ambient: Vec3::new(ar, ag, ab),
+        }
+    }
+}
+
+/// The [`DirectionalLight::illuminance`](bevy::pbr::DirectionalLight::illuminance)
+/// that doesn't change the brightness of the light's color.
+#[cfg(feature = "pbr")]
+const REFERENCE_ILLUMINANCE: f32 = 100_000.0;
+
+#[cfg(feature = "pbr")]
+pub(crate) fn sync_pbr_lighting(
+    mut lighting: ResMut<CuboidLighting>,
+    directional_lights: Query<(&bevy::pbr::DirectionalLight, &GlobalTransform)>,
+    ambient_light: Option<Res<bevy::pbr::AmbientLight>>,
+) {
+    let mut synced = lighting.clone();
+    if let Some((light, transform)) = directional_lights.iter().next() {
+        synced.direction = transform.forward();
+        synced.color = light.color * (light.illuminance / REFERENCE_ILLUMINANCE);
+    }
+    if let Some(ambient_light) = ambient_light {
+        synced.ambient = ambient_light.color * ambient_light.brightness;
+    }
+    lighting.set_if_neq(synced);
+}
@@ -0,0 +1,589 @@
+use crate::{Cuboid, Cuboids};
+
+use bevy::{
+    ecs::component::Tick,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use std::ops::Range;
+
+/// Coarser versions of the [`Cuboids`] on the same entity, which are drawn
+/// instead of the full resolution instances when those would be too small to
+/// make out.
+///
+/// Insert `CuboidsLod::new(block_size)` next to a [`Cuboids`] component of a
+/// regular block model to opt in. Each level merges the blocks in 2x2x2 cells
+/// of the previous level, starting with the full resolution blocks of
+/// `cell_size`. The levels are updated whenever the [`Cuboids`] or this
+/// component change. Instances modified with [`Cuboids::get_mut`] or
+/// [`Cuboids::range_mut`] only update the cells containing them, while any
+/// other change rebuilds all levels.
+///
+/// The level is chosen per view and entity by [`LodSelection`]. Shadow maps
+/// use the level of the camera they're rendered for, while GPU picking always
+/// uses the full resolution instances so that hits refer to them.
+///
+/// Entities with levels of detail are neither batched nor GPU culled while a
+/// coarser level is drawn.
+#[derive(Clone, Component, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsLod {
+    /// Size of the full resolution blocks, in the entity's local space.
+    pub cell_size: Vec3,
+    /// Maximum number of coarser levels. Fewer levels are built when a level
+    /// already consists of a single block.
+    pub max_levels: u32,
+    pub aggregation: LodAggregation,
+    pub selection: LodSelection,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    levels: Vec<Vec<Cuboid>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    aabb_min: Vec3,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    aabb_max: Vec3,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cells: LodCells,
+}
+
+/// The cells behind [`CuboidsLod::levels`], kept to update only the cells of
+/// modified instances.
+#[derive(Clone, Debug, Default)]
+struct LodCells {
+    /// Corner of the finest cell at index zero.
+    origin: Vec3,
+    /// The finest cell of each full resolution instance, unless it's
+    /// invisible.
+    instance_cells: Vec<Option<IVec3>>,
+    /// The full resolution instances in each of the finest cells.
+    members: HashMap<IVec3, Vec<u32>>,
+    /// The combined blocks of each cell, by level.
+    levels: Vec<HashMap<IVec3, CellAggregate>>,
+    /// The settings and the last change of the [`Cuboids`] that the cells
+    /// were built from.
+    built_from: Option<(LodSettings, Tick)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LodSettings {
+    cell_size: Vec3,
+    max_levels: u32,
+    aggregation: LodAggregation,
+}
+
+/// How the colors of the blocks in a cell are combined into the color of the
+/// merged block.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LodAggregation {
+    /// The most common color, weighted by the number of full resolution
+    /// blocks. Works for every color mode, and keeps categories intact.
+    #[default]
+    Majority,
+    /// The mean of the scalars, for
+    /// [`COLOR_MODE_SCALAR_HUE`](crate::COLOR_MODE_SCALAR_HUE) and
+    /// [`COLOR_MODE_SCALAR_COLORMAP`](crate::COLOR_MODE_SCALAR_COLORMAP).
+    MeanScalar,
+    /// The maximum of the scalars, e.g. to keep high grades visible from
+    /// afar.
+    MaxScalar,
+}
+
+/// Chooses the level of detail of an entity in a view.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LodSelection {
+    /// Switch to level `i + 1` once the camera is further than `distances[i]`
+    /// from the entity's bounds, in world units.
+    Distance(Vec<f32>),
+    /// Use the finest level whose blocks still cover at least this many
+    /// pixels vertically, at the point of the entity's bounds closest to the
+    /// camera.
+    ProjectedSize { min_cell_pixels: f32 },
+}
+
+impl Default for LodSelection {
+    fn default() -> Self {
+        Self::ProjectedSize {
+            min_cell_pixels: 2.0,
+        }
+    }
+}
+
+impl LodSelection {
+    /// Returns the level of detail to draw, where 0 is full resolution.
+    ///
+    /// `distance` is the distance from the camera to the entity's bounds, and
+    /// `pixels_per_unit` the projected size of a world unit at that distance.
+    pub fn level(&self, distance: f32, pixels_per_unit: f32, cell_size: f32) -> usize {
+        match self {
+            Self::Distance(distances) => distances.iter().take_while(|&&d| distance > d).count(),
+            Self::ProjectedSize { min_cell_pixels } => {
+                // Each level doubles the size of the blocks.
+                let cell_pixels = cell_size * pixels_per_unit;
+                let level = (min_cell_pixels / cell_pixels).log2().ceil();
+                if level > 0.0 {
+                    level as usize
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+impl CuboidsLod {
+    const DEFAULT_MAX_LEVELS: u32 = 6;
+
+    pub fn new(cell_size: Vec3) -> Self {
+        Self {
+            cell_size,
+            max_levels: Self::DEFAULT_MAX_LEVELS,
+            aggregation: default(),
+            selection: default(),
+            levels: Vec::new(),
+            aabb_min: Vec3::ZERO,
+            aabb_max: Vec3::ZERO,
+            cells: default(),
+        }
+    }
+
+    pub fn with_max_levels(mut self, max_levels: u32) -> Self {
+        self.max_levels = max_levels;
+        self
+    }
+
+    pub fn with_aggregation(mut self, aggregation: LodAggregation) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    pub fn with_selection(mut self, selection: LodSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// The merged instances of each coarser level, starting with the 2x2x2
+    /// cells of full resolution blocks.
+    pub fn levels(&self) -> &[Vec<Cuboid>] {
+        &self.levels
+    }
+
+    /// The bounds of all visible full resolution instances, in the entity's
+    /// local space.
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        (self.aabb_min, self.aabb_max)
+    }
+
+    /// Rebuilds all levels from the full resolution instances. Invisible
+    /// instances are left out.
+    pub fn rebuild(&mut self, cuboids: &Cuboids) {
+        let instances = cuboids.instances();
+        let (min, max) = instances.iter().filter(|c| !c.is_invisible()).fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), c| (min.min(c.minimum), max.max(c.maximum)),
+        );
+        // Cells are aligned to the bounds, which usually puts full resolution
+        // blocks in exactly one cell.
+        self.cells = LodCells {
+            origin: if min.cmple(max).all() {
+                min
+            } else {
+                Vec3::ZERO
+            },
+            instance_cells: vec![None; instances.len()],
+            ..default()
+        };
+        self.update_cells(instances, 0..instances.len());
+    }
+
+    /// Updates the cells containing the instances in `ranges`, before and
+    /// after they were modified.
+    fn update(&mut self, cuboids: &Cuboids, ranges: &[Range<usize>]) {
+        let instances = cuboids.instances();
+        self.update_cells(instances, ranges.iter().flat_map(|range| range.clone()));
+    }
+
+    fn settings(&self) -> LodSettings {
+        LodSettings {
+            cell_size: self.cell_size,
+            max_levels: self.max_levels,
+            aggregation: self.aggregation,
+        }
+    }
+
+    fn update_cells(&mut self, instances: &[Cuboid], modified: impl Iterator<Item = usize>) {
+        let cell_size = 2.0 * self.cell_size;
+        let cells = &mut self.cells;
+
+        // Move the modified instances between the finest cells.
+        let mut dirty = HashSet::default();
+        for index in modified {
+            let cuboid = &instances[index];
+            let new_cell = (!cuboid.is_invisible()).then(|| {
+                ((cuboid.center() - cells.origin) / cell_size)
+                    .floor()
+                    .as_ivec3()
+            });
+            let old_cell = std::mem::replace(&mut cells.instance_cells[index], new_cell);
+            if old_cell != new_cell {
+                if let Some(old_cell) = old_cell {
+                    let members = cells.members.get_mut(&old_cell).unwrap();
+                    let position = members.iter().position(|&i| i as usize == index);
+                    members.swap_remove(position.unwrap());
+                    if members.is_empty() {
+                        cells.members.remove(&old_cell);
+                    }
+                }
+                if let Some(new_cell) = new_cell {
+                    let members = cells.members.entry(new_cell).or_default();
+                    members.push(index as u32);
+                }
+            }
+            dirty.extend(old_cell);
+            dirty.extend(new_cell);
+        }
+
+        // Recombine the dirty cells of each level, and their parents in the
+        // next level. Levels that didn't exist yet are combined entirely.
+        for level in 0..self.max_levels.max(1) as usize {
+            if level == cells.levels.len() {
+                if let Some(finer) = cells.levels.last() {
+                    dirty = finer
+                        .keys()
+                        .map(|cell| cell.div_euclid(IVec3::splat(2)))
+                        .collect();
+                }
+                cells.levels.push(default());
+            }
+            let (finer, this_and_coarser) = cells.levels.split_at_mut(level);
+            let this = &mut this_and_coarser[0];
+            for &cell in &dirty {
+                let aggregate = match finer.last() {
+                    None => cells.members.get(&cell).map(|members| {
+                        members.iter().fold(CellAggregate::empty(), |mut sum, &i| {
+                            sum.add(&CellAggregate::from_cuboid(&instances[i as usize]));
+                            sum
+                        })
+                    }),
+                    // Cells of the next level contain 2x2x2 cells of this one.
+                    Some(finer) => CHILD_OFFSETS
+                        .iter()
+                        .filter_map(|&offset| finer.get(&(2 * cell + offset)))
+                        .fold(None, |sum: Option<CellAggregate>, child| {
+                            let mut sum = sum.unwrap_or_else(CellAggregate::empty);
+                            sum.add(&child.clone().collapse());
+                            Some(sum)
+                        }),
+                };
+                match aggregate {
+                    Some(aggregate) => this.insert(cell, aggregate),
+                    None => this.remove(&cell),
+                };
+            }
+            if this.len() <= 1 {
+                cells.levels.truncate(level + 1);
+                break;
+            }
+            dirty = dirty
+                .iter()
+                .map(|cell| cell.div_euclid(IVec3::splat(2)))
+                .collect();
+        }
+
+        // The coarsest cells cover the same bounds as the instances.
+        let coarsest = cells
+            .levels
+            .last()
+            .into_iter()
+            .flat_map(|level| level.values());
+        let (min, max) = coarsest.fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), cell| (min.min(cell.min), max.max(cell.max)),
+        );
+        (self.aabb_min, self.aabb_max) = if min.cmple(max).all() {
+            (min, max)
+        } else {
+            (Vec3::ZERO, Vec3::ZERO)
+        };
+
+        let levels = if cells.levels.first().is_some_and(|cells| !cells.is_empty()) {
+            &cells.levels[..cells.levels.len().min(self.max_levels as usize)]
+        } else {
+            &[]
+        };
+        self.levels = levels
+            .iter()
+            .map(|cells| {
+                cells
+                    .values()
+                    .map(|cell| cell.to_cuboid(self.aggregation))
+                    .collect()
+            })
+            .collect();
+    }
+}
+
+const CHILD_OFFSETS: [IVec3; 8] = [
+    IVec3::new(0, 0, 0),
+    IVec3::new(1, 0, 0),
+    IVec3::new(0, 1, 0),
+    IVec3::new(1, 1, 0),
+    IVec3::new(0, 0, 1),
+    IVec3::new(1, 0, 1),
+    IVec3::new(0, 1, 1),
+    IVec3::new(1, 1, 1),
+];
+
+/// The combined blocks of a cell.
+#[derive(Clone, Debug)]
+struct CellAggregate {
+    min: Vec3,
+    max: Vec3,
+    /// Number of full resolution blocks.
+    count: u32,
+    scalar_sum: f64,
+    scalar_max: f32,
+    /// Colors and the number of full resolution blocks with them.
+    votes: Vec<(u32, u32)>,
+    all_emissive: bool,
+}
+
+impl CellAggregate {
+    fn empty() -> Self {
+        Self {
+            min: Vec3::splat(f32::MAX),
+            max: Vec3::splat(f32::MIN),
+            count: 0,
+            scalar_sum: 0.0,
+            scalar_max: f32::MIN,
+            votes: Vec::new(),
+            all_emissive: true,
+        }
+    }
+
+    fn from_cuboid(cuboid: &Cuboid) -> Self {
+        let scalar = f32::from_bits(cuboid.color);
+        Self {
+            min: cuboid.minimum,
+            max: cuboid.maximum,
+            count: 1,
+            scalar_sum: scalar as f64,
+            scalar_max: scalar,
+            votes: vec![(cuboid.color, 1)],
+            all_emissive: cuboid.meta_bits & 0x02 != 0,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.scalar_sum += other.scalar_sum;
+        self.scalar_max = self.scalar_max.max(other.scalar_max);
+        for &(color, count) in &other.votes {
+            match self.votes.iter_mut().find(|(c, _)| *c == color) {
+                Some((_, votes)) => *votes += count,
+                None => self.votes.push((color, count)),
+            }
+        }
+        self.all_emissive &= other.all_emissive;
+    }
+
+    /// Only keeps the winning vote, so coarser levels don't track every color
+    /// of the model. Their majority is a majority of majorities.
+    fn collapse(mut self) -> Self {
+        let winner = self.majority();
+        self.votes = vec![(winner, self.count)];
+        self
+    }
+
+    /// Ties go to the smallest color, so that the result doesn't depend on
+    /// the order of the votes.
+    fn majority(&self) -> u32 {
+        self.votes
+            .iter()
+            .max_by_key(|&&(color, count)| (count, std::cmp::Reverse(color)))
+            .map_or(0, |(color, _)| *color)
+    }
+
+    fn to_cuboid(&self, aggregation: LodAggregation) -> Cuboid {
+        let color = match aggregation {
+            LodAggregation::Majority => self.majority(),
+            LodAggregation::MeanScalar => ((self.scalar_sum / self.count as f64) as f32).to_bits(),
+            LodAggregation::MaxScalar => self.scalar_max.to_bits(),
+        };
+        let mut cuboid = Cuboid::new(self.min, self.max, color);
+        if self.all_emissive {
+            cuboid.make_emissive();
+        }
+        cuboid
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_cuboids_lod(
+    mut cuboids: Query<
+        (Ref<Cuboids>, &mut CuboidsLod),
+        Or<(Changed<Cuboids>, Changed<CuboidsLod>)>,
+    >,
+) {
+    for (cuboids, mut lod) in cuboids.iter_mut() {
+        let settings = lod.settings();
+        let last_changed = cuboids.last_changed();
+        match lod.cells.built_from {
+            // Only the selection changed.
+            Some(built_from) if built_from == (settings, last_changed) => continue,
+            Some((built_settings, last_seen)) if built_settings == settings => {
+                let num_instances = lod.cells.instance_cells.len();
+                match cuboids.dirty_ranges_since(last_seen) {
+                    Some(ranges) if num_instances == cuboids.instances().len() => {
+                        lod.update(&cuboids, ranges)
+                    }
+                    _ => lod.rebuild(&cuboids),
+                }
+            }
+            _ => lod.rebuild(&cuboids),
+        }
+        lod.cells.built_from = Some((settings, last_changed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cuboids::clear_cuboids_dirty_ranges;
+    use bevy::ecs::system::RunSystemOnce;
+
+    /// Unit blocks filling a cube with `len` blocks per side, colored by
+    /// `color(x, y, z)`.
+    fn grid(len: i32, color: impl Fn(i32, i32, i32) -> u32) -> Cuboids {
+        let mut instances = Vec::new();
+        for x in 0..len {
+            for y in 0..len {
+                for z in 0..len {
+                    let minimum = Vec3::new(x as f32, y as f32, z as f32);
+                    instances.push(Cuboid::new(minimum, minimum + Vec3::ONE, color(x, y, z)));
+                }
+            }
+        }
+        Cuboids::new(instances)
+    }
+
+    /// The bounds and colors of the merged blocks of each level, in a stable
+    /// order.
+    fn sorted_levels(lod: &CuboidsLod) -> Vec<Vec<([i32; 6], u32)>> {
+        lod.levels()
+            .iter()
+            .map(|level| {
+                let mut blocks: Vec<_> = level
+                    .iter()
+                    .map(|c| {
+                        let (min, max) = (c.minimum.as_ivec3(), c.maximum.as_ivec3());
+                        ([min.x, min.y, min.z, max.x, max.y, max.z], c.color)
+                    })
+                    .collect();
+                blocks.sort_unstable();
+                blocks
+            })
+            .collect()
+    }
+
+    #[test]
+    fn levels_merge_cells_of_eight_blocks() {
+        let cuboids = grid(4, |x, _, _| x as u32);
+        let mut lod = CuboidsLod::new(Vec3::ONE);
+        lod.rebuild(&cuboids);
+
+        let levels = sorted_levels(&lod);
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0].len(), 8);
+        assert_eq!(levels[0][0], ([0, 0, 0, 2, 2, 2], 0));
+        assert_eq!(levels[1], [([0, 0, 0, 4, 4, 4], 0)]);
+        assert_eq!(lod.aabb(), (Vec3::ZERO, Vec3::splat(4.0)));
+    }
+
+    #[test]
+    fn majority_ties_go_to_the_smallest_color() {
+        let cuboids = grid(2, |x, _, _| 7 - x as u32);
+        let mut lod = CuboidsLod::new(Vec3::ONE);
+        lod.rebuild(&cuboids);
+        assert_eq!(sorted_levels(&lod), [[([0, 0, 0, 2, 2, 2], 6)]]);
+    }
+
+    #[test]
+    fn scalar_aggregations_combine_all_blocks() {
+        let cuboids = grid(2, |x, y, z| ((x + 2 * y + 4 * z) as f32).to_bits());
+        let mut lod = CuboidsLod::new(Vec3::ONE).with_aggregation(LodAggregation::MeanScalar);
+        lod.rebuild(&cuboids);
+        assert_eq!(lod.levels()[0][0].color, 3.5f32.to_bits());
+
+        lod.aggregation = LodAggregation::MaxScalar;
+        lod.rebuild(&cuboids);
+        assert_eq!(lod.levels()[0][0].color, 7.0f32.to_bits());
+    }
+
+    #[test]
+    fn invisible_blocks_are_left_out() {
+        let mut cuboids = grid(2, |_, _, _| 0);
+        cuboids.get_mut(7).make_invisible();
+        let mut lod = CuboidsLod::new(Vec3::ONE);
+        lod.rebuild(&cuboids);
+        assert_eq!(lod.levels()[0].len(), 1);
+
+        for cuboid in cuboids.instances_mut() {
+            cuboid.make_invisible();
+        }
+        lod.rebuild(&cuboids);
+        assert!(lod.levels().is_empty());
+        assert_eq!(lod.aabb(), (Vec3::ZERO, Vec3::ZERO));
+    }
+
+    #[test]
+    fn tracked_edits_update_the_levels_like_a_rebuild() {
+        let mut world = World::new();
+        let entity = world
+            .spawn((
+                grid(8, |x, y, _| (x + y) as u32 % 3),
+                CuboidsLod::new(Vec3::ONE),
+            ))
+            .id();
+        let next_frame = |world: &mut World, edit: &dyn Fn(&mut Cuboids)| {
+            world.run_system_once(clear_cuboids_dirty_ranges);
+            edit(&mut world.get_mut::<Cuboids>(entity).unwrap());
+            world.run_system_once(update_cuboids_lod);
+
+            let mut rebuilt = world.get::<CuboidsLod>(entity).unwrap().clone();
+            rebuilt.rebuild(world.get::<Cuboids>(entity).unwrap());
+            let lod = world.get::<CuboidsLod>(entity).unwrap();
+            assert_eq!(sorted_levels(lod), sorted_levels(&rebuilt));
+            assert_eq!(lod.aabb(), rebuilt.aabb());
+        };
+
+        next_frame(&mut world, &|_| {});
+        // Recolor a block.
+        next_frame(&mut world, &|cuboids| cuboids.get_mut(0).color = 5);
+        // Move a block into another cell, and one past the bounds.
+        next_frame(&mut world, &|cuboids| {
+            let cuboid = cuboids.get_mut(1);
+            cuboid.minimum.x += 2.0;
+            cuboid.maximum.x += 2.0;
+            let cuboid = cuboids.get_mut(511);
+            cuboid.minimum += Vec3::splat(4.0);
+            cuboid.maximum += Vec3::splat(4.0);
+        });
+        // Hide a whole cell, then show part of it again.
+        next_frame(&mut world, &|cuboids| {
+            for cuboid in cuboids.range_mut(64..128) {
+                cuboid.make_invisible();
+            }
+        });
+        next_frame(&mut world, &|cuboids| {
+            cuboids.get_mut(70).make_visible();
+        });
+        // Untracked edits rebuild everything.
+        next_frame(&mut world, &|cuboids| {
+            cuboids.instances_mut().truncate(100);
+        });
+        next_frame(&mut world, &|cuboids| cuboids.get_mut(99).color = 1);
+    }
+}
@@ -1,11 +1,17 @@
+use crate::{
+    AttributeIndex, Color, ColormapIndex, CuboidPalettes, PaletteIndex, ATTRIBUTE_NONE,
+    COLORMAP_VIRIDIS,
+};
 use bevy::prelude::*;
-use bevy::render::render_resource::{DynamicUniformBuffer, ShaderType};
+use bevy::render::render_resource::ShaderType;
 
 /// Bare enum for toggling shader behavior for [`Color`].
 ///
 /// One of:
 /// - [`COLOR_MODE_RGB`]
 /// - [`COLOR_MODE_SCALAR_HUE`]
+/// - [`COLOR_MODE_SCALAR_COLORMAP`]
+/// - [`COLOR_MODE_CATEGORICAL`]
 pub type ColorMode = u32;
 
 /// "Manual" coloring based on RGB-valued `cuboid.color`.
@@ -18,16 +24,39 @@ pub const COLOR_MODE_RGB: ColorMode = 0;
 /// Encode with `u32::from_le_bytes(f32::to_le_bytes(x))`.
 pub const COLOR_MODE_SCALAR_HUE: ColorMode = 1;
 
-/// Denotes which [`CuboidMaterial`] to use when rendering
-/// [`Cuboids`](crate::Cuboids).
+/// "Automatic" coloring based on scalar-valued `cuboid.color`, sampling the
+/// gradient in [`CuboidColormaps`](crate::CuboidColormaps) selected by [`CuboidMaterial::colormap`].
 ///
-/// When a material is modified, _all_ entities with the corresponding
-/// [`CuboidMaterialId`] will be affected.
-#[derive(Clone, Component, Copy, Eq, Hash, PartialEq)]
-pub struct CuboidMaterialId(pub usize);
+/// The scalar is normalized with the `clamp_min` and `clamp_max` of
+/// [`ScalarHueOptions`], and cuboids are clipped by its `min_visible` and
+/// `max_visible`.
+///
+/// Encode with `u32::from_le_bytes(f32::to_le_bytes(x))`.
+pub const COLOR_MODE_SCALAR_COLORMAP: ColorMode = 2;
+
+/// "Manual" coloring based on a category index in `cuboid.color`, looked up in
+/// the palette of [`CuboidPalettes`](crate::CuboidPalettes) selected by
+/// [`CuboidMaterial::palette`].
+///
+/// Cuboids are clipped if their category is hidden or missing from the
+/// palette.
+pub const COLOR_MODE_CATEGORICAL: ColorMode = 3;
 
 /// Shading options, constant for each draw call.
-#[derive(Clone, Debug, ShaderType)]
+///
+/// Entities select their material with a `Handle<CuboidMaterial>` component.
+/// When a material is modified, _all_ entities with a handle to it will be
+/// affected. Entities whose material doesn't exist (e.g. while it's loading)
+/// are not drawn.
+///
+/// [`Handle::default`] points to a [`CuboidMaterial::default`] inserted by
+/// [`VertexPullingRenderPlugin`](crate::VertexPullingRenderPlugin).
+///
+/// With the `serde` feature, materials can also be loaded from RON files, see
+/// `CuboidMaterialLoader`.
+#[derive(Asset, Clone, Debug, ShaderType, TypePath)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct CuboidMaterial {
     pub color_mode: ColorMode,
     /// Nonzero values imply that _only_ cuboid edges will be shaded.
@@ -40,6 +69,52 @@ pub struct CuboidMaterial {
     /// An extra factor that multiplies a cuboid's color when the "emissive" bit
     /// on [`MetaBits`](crate::cuboids::MetaBits) is set.
     pub emissive_gain: Vec3,
+
+    /// Multiplies the alpha of every cuboid. Values below 1 render the
+    /// cuboids in the transparent phase.
+    pub opacity: f32,
+    /// Nonzero values take each cuboid's alpha from the top byte of its
+    /// [`Color`] in [`COLOR_MODE_RGB`] (or of its palette color in
+    /// [`COLOR_MODE_CATEGORICAL`]), and render the cuboids in the transparent
+    /// phase.
+    pub instance_alpha: u32,
+    /// The gradient used in [`COLOR_MODE_SCALAR_COLORMAP`].
+    pub colormap: ColormapIndex,
+    /// The palette used in [`COLOR_MODE_CATEGORICAL`].
+    pub palette: PaletteIndex,
+
+    /// Nonzero values shade each face by its angle to the light, see
+    /// [`CuboidLighting`](crate::CuboidLighting).
+    pub lighting: u32,
+    /// World space direction that the light travels, overriding
+    /// [`CuboidLighting::direction`](crate::CuboidLighting::direction) unless
+    /// it's zero.
+    pub light_direction: Vec3,
+
+    /// Nonzero values cut cuboids exactly where they cross a
+    /// [`ClippingPlaneRange`](crate::ClippingPlaneRange), instead of clipping
+    /// whole cuboids by their centroid, and fill the exposed cross-sections
+    /// with `cap_color`.
+    ///
+    /// Clipping boxes and spheres still clip whole cuboids, and shadows are
+    /// cast by uncut cuboids.
+    pub precise_clipping: u32,
+    /// Linear RGBA color of the cross-sections exposed by `precise_clipping`.
+    /// With zero alpha, each cross-section takes the color of its cuboid.
+    /// Otherwise it's shaded like a face when `lighting` is on.
+    pub cap_color: Vec4,
+
+    /// The attribute in the entity's [`CuboidAttributes`](crate::CuboidAttributes)
+    /// that replaces `cuboid.color` as the scalar of
+    /// [`COLOR_MODE_SCALAR_HUE`] and [`COLOR_MODE_SCALAR_COLORMAP`], or as the
+    /// category of [`COLOR_MODE_CATEGORICAL`]. [`COLOR_MODE_RGB`] always uses
+    /// `cuboid.color`, as do entities without that attribute.
+    pub color_attribute: AttributeIndex,
+    /// The attribute in the entity's [`CuboidAttributes`](crate::CuboidAttributes)
+    /// whose values outside of `scalar_hue.min_visible..=scalar_hue.max_visible`
+    /// clip cuboids in any color mode, instead of the scalar color. Hidden
+    /// categories are still clipped.
+    pub visibility_attribute: AttributeIndex,
 }
 
 impl Default for CuboidMaterial {
@@ -49,6 +124,70 @@ impl Default for CuboidMaterial {
             wireframe: default(),
             scalar_hue: default(),
             emissive_gain: Vec3::splat(30.0),
+            opacity: 1.0,
+            instance_alpha: 0,
+            colormap: COLORMAP_VIRIDIS,
+            palette: 0,
+            lighting: 0,
+            light_direction: Vec3::ZERO,
+            precise_clipping: 0,
+            cap_color: Vec4::ZERO,
+            color_attribute: ATTRIBUTE_NONE,
+            visibility_attribute: ATTRIBUTE_NONE,
+        }
+    }
+}
+
+impl CuboidMaterial {
+    /// Returns `true` if cuboids with this material are alpha blended.
+    pub fn is_transparent(&self) -> bool {
+        self.opacity < 1.0
+            || (self.instance_alpha != 0
+                && matches!(self.color_mode, COLOR_MODE_RGB | COLOR_MODE_CATEGORICAL))
+    }
+
+    /// Returns `true` if `cuboid.color` holds a scalar value.
+    pub fn uses_scalars(&self) -> bool {
+        matches!(
+            self.color_mode,
+            COLOR_MODE_SCALAR_HUE | COLOR_MODE_SCALAR_COLORMAP
+        )
+    }
+
+    /// Returns the value that the color mode interprets for an instance with
+    /// this `color` and `attributes`, which are empty if it has none.
+    pub fn color_value(&self, color: Color, attributes: &[f32]) -> Color {
+        match attributes.get(self.color_attribute as usize) {
+            Some(&value) if self.color_mode == COLOR_MODE_CATEGORICAL => value as u32,
+            Some(&value) if self.color_mode != COLOR_MODE_RGB => value.to_bits(),
+            _ => color,
+        }
+    }
+
+    /// Returns `true` if the vertex shader discards instances with this `color`
+    /// and `attributes`.
+    pub(crate) fn clips_color(
+        &self,
+        color: Color,
+        attributes: &[f32],
+        palettes: &CuboidPalettes,
+    ) -> bool {
+        let color = self.color_value(color, attributes);
+        let scalar = match attributes.get(self.visibility_attribute as usize) {
+            Some(&value) => Some(value),
+            None if self.uses_scalars() => Some(f32::from_bits(color)),
+            None => None,
+        };
+        if scalar.is_some_and(|scalar| {
+            scalar < self.scalar_hue.min_visible || scalar > self.scalar_hue.max_visible
+        }) {
+            true
+        } else if self.color_mode == COLOR_MODE_CATEGORICAL {
+            !palettes
+                .get(self.palette)
+                .is_some_and(|palette| palette.is_visible(color))
+        } else {
+            false
         }
     }
 }
@@ -78,8 +217,11 @@ impl Default for CuboidMaterial {
 /// let hue = (360.0 + hue_options.hue_zero + s * hue_options.hue_slope) % 360.0;
 /// ```
 ///
-/// These options are only available in [`COLOR_MODE_SCALAR_HUE`].
+/// These options are only available in [`COLOR_MODE_SCALAR_HUE`]. The clamping
+/// and visibility options also apply to [`COLOR_MODE_SCALAR_COLORMAP`].
 #[derive(Clone, Debug, ShaderType)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct ScalarHueOptions {
     /// Cuboids with `cuboid.color < min_visible` will be clipped.
     pub min_visible: f32,
@@ -109,58 +251,3 @@ impl Default for ScalarHueOptions {
         }
     }
 }
-
-/// Resource used to create and modify a set of [`CuboidMaterial`] that are
-/// automatically synced to shader uniforms.
-#[derive(Clone, Debug, Resource)]
-pub struct CuboidMaterialMap {
-    // Consumed every frame during GPU buffering.
-    materials: Vec<CuboidMaterial>,
-}
-
-impl Default for CuboidMaterialMap {
-    fn default() -> Self {
-        Self {
-            materials: vec![default()],
-        }
-    }
-}
-
-impl CuboidMaterialMap {
-    pub fn is_empty(&self) -> bool {
-        self.materials.is_empty()
-    }
-
-    pub fn clear(&mut self) {
-        self.materials.clear();
-    }
-
-    pub fn get(&self, id: CuboidMaterialId) -> &CuboidMaterial {
-        &self.materials[id.0]
-    }
-
-    pub fn get_mut(&mut self, id: CuboidMaterialId) -> &mut CuboidMaterial {
-        &mut self.materials[id.0]
-    }
-
-    pub fn push(&mut self, material: CuboidMaterial) -> CuboidMaterialId {
-        let id = CuboidMaterialId(self.materials.len());
-        self.materials.push(material);
-        id
-    }
-
-    pub(crate) fn write_uniforms(
-        &self,
-        uniforms: &mut DynamicUniformBuffer<CuboidMaterial>,
-    ) -> Vec<CuboidMaterialUniformIndex> {
-        uniforms.clear();
-        let mut indices = Vec::new();
-        for material in self.materials.iter() {
-            indices.push(CuboidMaterialUniformIndex(uniforms.push(material.clone())));
-        }
-        indices
-    }
-}
-
-#[derive(Clone, Copy, Debug, Component)]
-pub(crate) struct CuboidMaterialUniformIndex(pub u32);
@@ -0,0 +1,136 @@
+use crate::CuboidMaterial;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    utils::BoxedFuture,
+};
+use std::fmt;
+use std::io;
+
+/// Loads [`CuboidMaterial`]s from RON files with the `.cuboid_material.ron`
+/// extension, e.g.
+///
+/// ```ron
+/// (
+///     color_mode: 1,
+///     scalar_hue: (clamp_min: 0.5, clamp_max: 2.5),
+///     opacity: 0.5,
+/// )
+/// ```
+///
+/// Fields that are left out keep their [`CuboidMaterial::default`] values.
+/// Entities drawn with the material change with the file when it's hot
+/// reloaded.
+#[derive(Default)]
+pub struct CuboidMaterialLoader;
+
+impl AssetLoader for CuboidMaterialLoader {
+    type Asset = CuboidMaterial;
+    type Settings = ();
+    type Error = CuboidMaterialLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<CuboidMaterial, CuboidMaterialLoaderError>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            Ok(ron::de::from_bytes(&bytes)?)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cuboid_material.ron"]
+    }
+}
+
+/// An error encountered while loading a [`CuboidMaterial`] file.
+#[derive(Debug)]
+pub enum CuboidMaterialLoaderError {
+    Io(io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for CuboidMaterialLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "failed to read cuboid material: {error}"),
+            Self::Ron(error) => write!(f, "invalid cuboid material: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for CuboidMaterialLoaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Ron(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for CuboidMaterialLoaderError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<ron::error::SpannedError> for CuboidMaterialLoaderError {
+    fn from(error: ron::error::SpannedError) -> Self {
+        Self::Ron(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::COLOR_MODE_SCALAR_HUE;
+    use bevy::{asset::LoadState, prelude::*};
+
+    #[test]
+    fn load_material_file() {
+        let dir = std::env::temp_dir().join(format!("cuboid_material_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("grade.cuboid_material.ron"),
+            "(color_mode: 1, scalar_hue: (clamp_max: 2.5), opacity: 0.5)",
+        )
+        .unwrap();
+
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            AssetPlugin {
+                file_path: dir.to_string_lossy().into(),
+                ..default()
+            },
+        ))
+        .init_asset::<CuboidMaterial>()
+        .init_asset_loader::<CuboidMaterialLoader>();
+        let handle: Handle<CuboidMaterial> = app
+            .world
+            .resource::<AssetServer>()
+            .load("grade.cuboid_material.ron");
+        for _ in 0..1000 {
+            app.update();
+            match app.world.resource::<AssetServer>().load_state(&handle) {
+                LoadState::Loaded | LoadState::Failed => break,
+                _ => std::thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let materials = app.world.resource::<Assets<CuboidMaterial>>();
+        let material = materials.get(&handle).expect("material should be loaded");
+        assert_eq!(material.color_mode, COLOR_MODE_SCALAR_HUE);
+        assert_eq!(material.opacity, 0.5);
+        assert_eq!(material.scalar_hue.clamp_max, 2.5);
+        // Left out fields keep their defaults.
+        let default = CuboidMaterial::default();
+        assert_eq!(material.emissive_gain, default.emissive_gain);
+        assert_eq!(material.scalar_hue.hue_zero, default.scalar_hue.hue_zero);
+    }
+}
@@ -0,0 +1,191 @@
+use bevy::{
+    core::{Pod, Zeroable},
+    prelude::*,
+    render::{primitives::Aabb, render_resource::ShaderType},
+};
+use std::ops::Range;
+
+use crate::cuboids::DirtyRanges;
+use crate::{Color, CuboidMaterial, MetaBits};
+
+/// A box with an arbitrary rotation about its center.
+///
+/// Rendered like a [`Cuboid`](crate::Cuboid), with the same meta bits and
+/// colors, but with an additional quaternion per instance.
+#[derive(Clone, Copy, Debug, ShaderType)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct OrientedCuboid {
+    pub center: Vec3,
+    pub meta_bits: MetaBits,
+    pub half_extents: Vec3,
+    pub color: Color,
+    /// Unit quaternion, stored as `(x, y, z, w)`.
+    pub rotation: Vec4,
+}
+
+// SAFETY: `OrientedCuboid` is `repr(C)` and only contains 4-byte scalars, so it
+// has no padding and every bit pattern is valid.
+unsafe impl Zeroable for OrientedCuboid {}
+unsafe impl Pod for OrientedCuboid {}
+
+impl OrientedCuboid {
+    pub fn new(center: Vec3, half_extents: Vec3, rotation: Quat, color: u32) -> Self {
+        assert_eq!(std::mem::size_of::<OrientedCuboid>(), 48);
+        Self {
+            center,
+            meta_bits: 0,
+            half_extents,
+            color,
+            rotation: Vec4::from(rotation),
+        }
+    }
+
+    #[inline]
+    pub fn rotation(&self) -> Quat {
+        Quat::from_vec4(self.rotation)
+    }
+
+    #[inline]
+    pub fn set_rotation(&mut self, rotation: Quat) -> &mut Self {
+        self.rotation = Vec4::from(rotation);
+        self
+    }
+
+    /// Half extents of the axis-aligned box bounding this cuboid.
+    #[inline]
+    pub fn aabb_half_extents(&self) -> Vec3 {
+        let m = Mat3::from_quat(self.rotation());
+        m.x_axis.abs() * self.half_extents.x
+            + m.y_axis.abs() * self.half_extents.y
+            + m.z_axis.abs() * self.half_extents.z
+    }
+
+    #[inline]
+    pub fn is_invisible(&self) -> bool {
+        self.meta_bits & 1 != 0
+    }
+
+    #[inline]
+    pub fn make_visible(&mut self) -> &mut Self {
+        self.meta_bits &= !1;
+        self
+    }
+
+    #[inline]
+    pub fn make_invisible(&mut self) -> &mut Self {
+        self.meta_bits |= 1;
+        self
+    }
+
+    #[inline]
+    pub fn make_emissive(&mut self) -> &mut Self {
+        self.meta_bits |= 0b10;
+        self
+    }
+
+    #[inline]
+    pub fn make_non_emissive(&mut self) -> &mut Self {
+        self.meta_bits &= !0b10;
+        self
+    }
+
+    #[inline]
+    pub fn set_depth_bias(&mut self, bias: u16) -> &mut Self {
+        self.meta_bits &= 0x0000FFFF; // clear
+        self.meta_bits |= (bias as u32) << 16; // set
+        self
+    }
+}
+
+/// A set of oriented cuboids to be extracted for rendering.
+///
+/// This is an alternative to [`Cuboids`](crate::Cuboids) for instances that
+/// aren't aligned with the axes of the entity's transform. Aligned instances
+/// should prefer [`Cuboids`](crate::Cuboids), which use less memory.
+///
+/// An entity should not have both components.
+#[derive(Clone, Component, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OrientedCuboids {
+    instances: Vec<OrientedCuboid>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    dirty_ranges: DirtyRanges,
+}
+
+impl OrientedCuboids {
+    pub fn new(instances: Vec<OrientedCuboid>) -> Self {
+        Self {
+            instances,
+            dirty_ranges: default(),
+        }
+    }
+
+    /// Instances to be rendered.
+    pub fn instances(&self) -> &[OrientedCuboid] {
+        &self.instances
+    }
+
+    /// Mutably borrow all instances, e.g. to add or remove some, marking the
+    /// whole instance buffer for upload.
+    ///
+    /// Use [`OrientedCuboids::get_mut`] or [`OrientedCuboids::range_mut`] to
+    /// only upload the modified instances.
+    pub fn instances_mut(&mut self) -> &mut Vec<OrientedCuboid> {
+        self.dirty_ranges.insert_all();
+        &mut self.instances
+    }
+
+    pub fn into_instances(self) -> Vec<OrientedCuboid> {
+        self.instances
+    }
+
+    /// Mutably borrow a single instance, marking it for a partial upload.
+    pub fn get_mut(&mut self, index: usize) -> &mut OrientedCuboid {
+        self.range_mut(index..index + 1).first_mut().unwrap()
+    }
+
+    /// Mutably borrow a contiguous range of instances, marking it for a partial
+    /// upload.
+    pub fn range_mut(&mut self, range: Range<usize>) -> &mut [OrientedCuboid] {
+        let slice = &mut self.instances[range.clone()];
+        self.dirty_ranges.insert(range);
+        slice
+    }
+
+    /// Ranges of instances modified with [`OrientedCuboids::get_mut`] or
+    /// [`OrientedCuboids::range_mut`] since the last extraction.
+    ///
+    /// An empty slice means any change to this component requires a full
+    /// upload, e.g. after [`OrientedCuboids::instances_mut`].
+    pub fn dirty_ranges(&self) -> &[Range<usize>] {
+        &self.dirty_ranges.ranges
+    }
+
+    pub(crate) fn clear_dirty_ranges(&mut self) {
+        self.dirty_ranges.clear();
+    }
+
+    pub(crate) fn has_dirty_ranges(&self) -> bool {
+        !self.dirty_ranges.is_clear()
+    }
+
+    /// Automatically creates an [`Aabb`] that bounds all `instances`.
+    pub fn aabb(&self) -> Aabb {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for i in self.instances.iter() {
+            let half_extents = i.aabb_half_extents();
+            min = min.min(i.center - half_extents);
+            max = max.max(i.center + half_extents);
+        }
+        Aabb::from_min_max(min, max)
+    }
+}
+
+#[derive(Bundle)]
+pub struct OrientedCuboidsBundle {
+    pub material: Handle<CuboidMaterial>,
+    pub cuboids: OrientedCuboids,
+    pub spatial: SpatialBundle,
+}
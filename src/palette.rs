@@ -0,0 +1,108 @@
+use bevy::{math::UVec2, prelude::*};
+
+/// Index of a palette in [`CuboidPalettes`], used by
+/// [`CuboidMaterial::palette`](crate::CuboidMaterial::palette).
+pub type PaletteIndex = u32;
+
+/// The color and visibility of a single category.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PaletteEntry {
+    pub color: Color,
+    /// Cuboids of invisible categories are clipped.
+    pub visible: bool,
+}
+
+impl PaletteEntry {
+    pub fn new(color: Color) -> Self {
+        Self {
+            color,
+            visible: true,
+        }
+    }
+}
+
+/// Colors indexed by category for
+/// [`COLOR_MODE_CATEGORICAL`](crate::COLOR_MODE_CATEGORICAL).
+///
+/// Cuboids with a category outside of the palette are clipped.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidPalette {
+    pub entries: Vec<PaletteEntry>,
+}
+
+impl CuboidPalette {
+    pub fn new(colors: impl IntoIterator<Item = Color>) -> Self {
+        Self {
+            entries: colors.into_iter().map(PaletteEntry::new).collect(),
+        }
+    }
+
+    /// Shows or hides all cuboids of `category`.
+    pub fn set_visible(&mut self, category: u32, visible: bool) {
+        if let Some(entry) = self.entries.get_mut(category as usize) {
+            entry.visible = visible;
+        }
+    }
+
+    /// Returns `true` if cuboids of `category` are rendered.
+    pub fn is_visible(&self, category: u32) -> bool {
+        self.entries
+            .get(category as usize)
+            .is_some_and(|entry| entry.visible)
+    }
+}
+
+/// Resource holding all palettes available to
+/// [`COLOR_MODE_CATEGORICAL`](crate::COLOR_MODE_CATEGORICAL).
+///
+/// Palettes are uploaded to the GPU whenever this resource is mutated, so
+/// toggling the visibility of a category doesn't require rewriting any
+/// instances.
+#[derive(Clone, Debug, Default, Resource)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidPalettes {
+    palettes: Vec<CuboidPalette>,
+}
+
+impl CuboidPalettes {
+    pub fn push(&mut self, palette: CuboidPalette) -> PaletteIndex {
+        self.palettes.push(palette);
+        self.palettes.len() as u32 - 1
+    }
+
+    pub fn get(&self, index: PaletteIndex) -> Option<&CuboidPalette> {
+        self.palettes.get(index as usize)
+    }
+
+    pub fn get_mut(&mut self, index: PaletteIndex) -> Option<&mut CuboidPalette> {
+        self.palettes.get_mut(index as usize)
+    }
+
+    /// Packs all palettes into a single array of the form:
+    ///
+    /// - `[num_palettes, 0]`
+    /// - `[entries_offset, num_entries]` for each palette
+    /// - `[rgba, visible]` for each entry of each palette
+    pub(crate) fn gpu_data(&self) -> Vec<UVec2> {
+        let num_entries: usize = self.palettes.iter().map(|p| p.entries.len()).sum();
+        let mut data = Vec::with_capacity(1 + self.palettes.len() + num_entries);
+        data.push(UVec2::new(self.palettes.len() as u32, 0));
+        let mut offset = 1 + self.palettes.len() as u32;
+        for palette in &self.palettes {
+            let len = palette.entries.len() as u32;
+            data.push(UVec2::new(offset, len));
+            offset += len;
+        }
+        for palette in &self.palettes {
+            data.extend(
+                palette
+                    .entries
+                    .iter()
+                    .map(|entry| UVec2::new(entry.color.as_rgba_u32(), entry.visible as u32)),
+            );
+        }
+        data
+    }
+}
@@ -0,0 +1,520 @@
+use crate::clipping_planes::{
+    ClippingBox, ClippingGroups, ClippingPlaneRange, ClippingSphere, ClippingVolumes,
+};
+use crate::{
+    Color, Cuboid, CuboidAttributes, CuboidMaterial, CuboidPalettes, Cuboids, CuboidsBvh,
+    CuboidsViewOverrides, OrientedCuboid, OrientedCuboids,
+};
+
+use bevy::{ecs::system::SystemParam, math::Ray, prelude::*, render::view::RenderLayers};
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+
+/// The intersection of a ray with a single [`Cuboid`] or
+/// [`OrientedCuboid`] instance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidHit {
+    /// The entity holding the [`Cuboids`] or [`OrientedCuboids`] that were
+    /// hit.
+    pub entity: Entity,
+    /// Index of the hit instance in [`Cuboids::instances`] or
+    /// [`OrientedCuboids::instances`].
+    pub instance_index: usize,
+    /// World space position where the ray enters the cuboid, or the part of it
+    /// left by [`CuboidMaterial::precise_clipping`](crate::CuboidMaterial::precise_clipping).
+    pub point: Vec3,
+    /// World space unit normal of the face or cap that was hit.
+    pub normal: Vec3,
+    /// World space distance from the ray origin to `point`.
+    pub distance: f32,
+}
+
+/// Casts rays against all [`Cuboids`] and [`OrientedCuboids`] on the CPU.
+///
+/// Only cuboids that would be rendered are hit, i.e. this honors:
+/// - the entity's [`InheritedVisibility`] and, for
+///   [`Self::cast_ray_in_view`] and [`Self::cast_ray_from_cursor`], its
+///   [`RenderLayers`]
+/// - the visibility bit in [`MetaBits`](crate::MetaBits)
+/// - all [`ClippingPlaneRange`]s, [`ClippingBox`]es and [`ClippingSphere`]s
+/// - [`ScalarHueOptions::min_visible`](crate::ScalarHueOptions::min_visible)
+///   and [`ScalarHueOptions::max_visible`](crate::ScalarHueOptions::max_visible),
+///   applied to the attribute selected by the material, if any
+/// - the visibility of categories in [`CuboidPalettes`]
+///
+/// [`Cuboids`] with an up-to-date [`CuboidsBvh`] are traversed in
+/// logarithmic time, otherwise every instance is tested.
+#[allow(clippy::type_complexity)]
+#[derive(SystemParam)]
+pub struct CuboidsRaycast<'w, 's> {
+    cuboids: Query<
+        'w,
+        's,
+        (
+            Ref<'static, Cuboids>,
+            Option<&'static CuboidsBvh>,
+            RaycastEntityQuery,
+        ),
+    >,
+    oriented_cuboids: Query<'w, 's, (&'static OrientedCuboids, RaycastEntityQuery)>,
+    cameras: Query<
+        'w,
+        's,
+        (
+            &'static Camera,
+            &'static GlobalTransform,
+            Option<&'static RenderLayers>,
+            Option<&'static CuboidsViewOverrides>,
+        ),
+    >,
+    clipping_planes: Query<'w, 's, ClippingVolumeQuery<ClippingPlaneRange>>,
+    clipping_boxes: Query<'w, 's, ClippingVolumeQuery<ClippingBox>>,
+    clipping_spheres: Query<'w, 's, ClippingVolumeQuery<ClippingSphere>>,
+    materials: Res<'w, Assets<CuboidMaterial>>,
+    palettes: Res<'w, CuboidPalettes>,
+}
+
+type ClippingVolumeQuery<T> = (
+    &'static T,
+    &'static GlobalTransform,
+    Option<&'static ClippingGroups>,
+);
+
+/// The components of an entity with instances that decide which of them rays
+/// can hit.
+type RaycastEntityQuery = (
+    Entity,
+    &'static GlobalTransform,
+    &'static Handle<CuboidMaterial>,
+    Option<&'static InheritedVisibility>,
+    Option<&'static ClippingGroups>,
+    Option<&'static RenderLayers>,
+    Option<&'static CuboidAttributes>,
+);
+
+type RaycastEntityItem<'a> = (
+    Entity,
+    &'a GlobalTransform,
+    &'a Handle<CuboidMaterial>,
+    Option<&'a InheritedVisibility>,
+    Option<&'a ClippingGroups>,
+    Option<&'a RenderLayers>,
+    Option<&'a CuboidAttributes>,
+);
+
+impl<'w, 's> CuboidsRaycast<'w, 's> {
+    /// Returns all cuboids hit by `ray`, sorted by increasing distance.
+    pub fn cast_ray(&self, ray: Ray) -> Vec<CuboidHit> {
+        self.cast_ray_in_view(ray, &RenderLayers::all(), None)
+    }
+
+    /// Like [`Self::cast_ray`], but only hits the entities that a camera with
+    /// `render_layers` renders, as they look with its `overrides`.
+    pub fn cast_ray_in_view(
+        &self,
+        ray: Ray,
+        render_layers: &RenderLayers,
+        overrides: Option<&CuboidsViewOverrides>,
+    ) -> Vec<CuboidHit> {
+        let clipping = ClippingVolumes::new(
+            &self.clipping_planes,
+            &self.clipping_boxes,
+            &self.clipping_spheres,
+        );
+        let view = RaycastView {
+            ray,
+            render_layers,
+            overrides,
+            clipping: &clipping,
+        };
+
+        let mut hits = Vec::new();
+        for (cuboids, maybe_bvh, entity) in self.cuboids.iter() {
+            let instances = cuboids.instances();
+            let Some(target) = self.target(&view, entity, instances.len()) else {
+                continue;
+            };
+            let mut visit = |instance_index: usize| {
+                let cuboid = &instances[instance_index];
+                if let Some(hit) = target.hit(instance_index, cuboid) {
+                    hits.push(hit);
+                }
+            };
+            match maybe_bvh {
+                Some(bvh) if bvh.is_up_to_date(&cuboids) => {
+                    bvh.for_each_ray_hit(&cuboids, &target.local_ray, |i, _, _| visit(i));
+                }
+                _ => (0..instances.len()).for_each(visit),
+            }
+        }
+        for (cuboids, entity) in self.oriented_cuboids.iter() {
+            let instances = cuboids.instances();
+            let Some(target) = self.target(&view, entity, instances.len()) else {
+                continue;
+            };
+            hits.extend(
+                instances
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(instance_index, cuboid)| target.hit(instance_index, cuboid)),
+            );
+        }
+        hits.sort_unstable_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(Ordering::Equal)
+        });
+        hits
+    }
+
+    /// Returns all cuboids under `cursor` (in logical viewport coordinates)
+    /// that the `camera` entity renders, as they look with its
+    /// [`CuboidsViewOverrides`], sorted by increasing distance from the camera.
+    ///
+    /// Returns no hits if `camera` isn't a camera.
+    pub fn cast_ray_from_cursor(&self, camera: Entity, cursor: Vec2) -> Vec<CuboidHit> {
+        let Ok((camera, camera_transform, maybe_render_layers, maybe_overrides)) =
+            self.cameras.get(camera)
+        else {
+            return Vec::new();
+        };
+        let Some(ray) = camera.viewport_to_world(camera_transform, cursor) else {
+            return Vec::new();
+        };
+        // Cameras without render layers only render the default layer.
+        let default_layers = RenderLayers::default();
+        self.cast_ray_in_view(
+            ray,
+            maybe_render_layers.unwrap_or(&default_layers),
+            maybe_overrides,
+        )
+    }
+
+    /// Returns the entity's instances as seen by `view`, or `None` if none of
+    /// them are rendered.
+    fn target<'a>(
+        &'a self,
+        view: &RaycastView<'a>,
+        entity: RaycastEntityItem<'a>,
+        num_instances: usize,
+    ) -> Option<RaycastTarget<'a>> {
+        let (
+            entity,
+            transform,
+            material,
+            maybe_visibility,
+            maybe_clipping_groups,
+            maybe_render_layers,
+            maybe_attributes,
+        ) = entity;
+        if !maybe_visibility.map(|vis| vis.get()).unwrap_or(true) {
+            return None;
+        }
+        if !view
+            .render_layers
+            .intersects(maybe_render_layers.unwrap_or(&RenderLayers::default()))
+        {
+            return None;
+        }
+        let mut material_id = material.id();
+        let mut clipping_groups = maybe_clipping_groups.copied().unwrap_or_default();
+        if let Some(overrides) = view.overrides {
+            material_id = overrides.material(material_id);
+            clipping_groups = clipping_groups.intersection(overrides.clipping_groups);
+        }
+        // Entities aren't drawn while their material doesn't exist.
+        let material = self.materials.get(material_id)?;
+
+        let matrix = transform.compute_matrix();
+        let inv_matrix = matrix.inverse();
+        Some(RaycastTarget {
+            entity,
+            material,
+            palettes: &self.palettes,
+            attributes: maybe_attributes.filter(|attributes| attributes.fits(num_instances)),
+            clipping: view.clipping,
+            clipping_groups,
+            ray: view.ray,
+            local_ray: Ray {
+                origin: inv_matrix.transform_point3(view.ray.origin),
+                direction: inv_matrix.transform_vector3(view.ray.direction),
+            },
+            matrix,
+            normal_matrix: Mat3::from_mat4(inv_matrix).transpose(),
+        })
+    }
+}
+
+/// A ray cast in a view.
+struct RaycastView<'a> {
+    ray: Ray,
+    render_layers: &'a RenderLayers,
+    overrides: Option<&'a CuboidsViewOverrides>,
+    clipping: &'a ClippingVolumes,
+}
+
+/// An entity with instances that a ray is cast against.
+struct RaycastTarget<'a> {
+    entity: Entity,
+    material: &'a CuboidMaterial,
+    palettes: &'a CuboidPalettes,
+    attributes: Option<&'a CuboidAttributes>,
+    clipping: &'a ClippingVolumes,
+    clipping_groups: ClippingGroups,
+    ray: Ray,
+    /// The ray in the entity's local space, which shares the world ray's
+    /// parameters.
+    local_ray: Ray,
+    matrix: Mat4,
+    normal_matrix: Mat3,
+}
+
+impl RaycastTarget<'_> {
+    /// Returns the hit of the ray with an instance, if it's rendered.
+    fn hit(&self, instance_index: usize, instance: &impl RaycastInstance) -> Option<CuboidHit> {
+        let (t_enter, t_exit, local_normal) = instance.ray_intersection(&self.local_ray)?;
+        // Rays starting inside of the cuboid don't hit it, since only the
+        // faces pointing towards the ray origin are rendered.
+        if t_enter < 0.0 {
+            return None;
+        }
+        let attributes = self
+            .attributes
+            .map_or(&[][..], |attributes| attributes.instance(instance_index));
+        if instance.is_invisible()
+            || self
+                .material
+                .clips_color(instance.color(), attributes, self.palettes)
+        {
+            return None;
+        }
+        let mut t = t_enter;
+        let mut cap_normal = None;
+        if !self.clipping.is_empty() {
+            let world_center = self.matrix.transform_point3(instance.center());
+            if self.material.precise_clipping == 0 {
+                if self.clipping.clips(world_center, self.clipping_groups) {
+                    return None;
+                }
+            } else {
+                if self
+                    .clipping
+                    .boxes_or_spheres_clip(world_center, self.clipping_groups)
+                {
+                    return None;
+                }
+                let (t_cut, normal) =
+                    self.clipping
+                        .cut_ray(&self.ray, t, t_exit, self.clipping_groups)?;
+                t = t_cut;
+                cap_normal = normal;
+            }
+        }
+        let normal = cap_normal.unwrap_or_else(|| (self.normal_matrix * local_normal).normalize());
+        Some(CuboidHit {
+            entity: self.entity,
+            instance_index,
+            point: self.ray.get_point(t),
+            normal,
+            distance: t * self.ray.direction.length(),
+        })
+    }
+}
+
+/// Instances that rays are cast against, in their entity's local space.
+trait RaycastInstance {
+    fn is_invisible(&self) -> bool;
+    fn color(&self) -> Color;
+    fn center(&self) -> Vec3;
+    /// Returns the entry and exit ray parameters, along with the normal of the
+    /// entry face.
+    fn ray_intersection(&self, ray: &Ray) -> Option<(f32, f32, Vec3)>;
+}
+
+impl RaycastInstance for Cuboid {
+    fn is_invisible(&self) -> bool {
+        Cuboid::is_invisible(self)
+    }
+
+    fn color(&self) -> Color {
+        self.color
+    }
+
+    fn center(&self) -> Vec3 {
+        Cuboid::center(self)
+    }
+
+    fn ray_intersection(&self, ray: &Ray) -> Option<(f32, f32, Vec3)> {
+        let (t_enter, t_exit, axis) = ray_aabb_intersection(ray, self.minimum, self.maximum)?;
+        Some((t_enter, t_exit, entry_face_normal(ray, axis)))
+    }
+}
+
+impl RaycastInstance for OrientedCuboid {
+    fn is_invisible(&self) -> bool {
+        OrientedCuboid::is_invisible(self)
+    }
+
+    fn color(&self) -> Color {
+        self.color
+    }
+
+    fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    fn ray_intersection(&self, ray: &Ray) -> Option<(f32, f32, Vec3)> {
+        // The cuboid's frame is a rigid transform of the entity's local space,
+        // so the ray keeps its parameters.
+        let rotation = self.rotation();
+        let inv_rotation = rotation.inverse();
+        let cuboid_ray = Ray {
+            origin: inv_rotation * (ray.origin - self.center),
+            direction: inv_rotation * ray.direction,
+        };
+        let (t_enter, t_exit, axis) =
+            ray_aabb_intersection(&cuboid_ray, -self.half_extents, self.half_extents)?;
+        Some((
+            t_enter,
+            t_exit,
+            rotation * entry_face_normal(&cuboid_ray, axis),
+        ))
+    }
+}
+
+/// The normal of the face perpendicular to `axis` where `ray` enters a box.
+fn entry_face_normal(ray: &Ray, axis: usize) -> Vec3 {
+    let mut normal = Vec3::ZERO;
+    normal[axis] = -ray.direction[axis].signum();
+    normal
+}
+
+/// Picks cuboid instances under a cursor on the GPU, for cameras rendering
+/// [`Cuboids`] and [`OrientedCuboids`].
+///
+/// Requires [`VertexPullingRenderPlugin::gpu_picking`](crate::VertexPullingRenderPlugin::gpu_picking).
+/// Every frame that `cursor` is set, the instance under it is rendered into an
+/// offscreen ID buffer and read back asynchronously. The result arrives as a
+/// [`CuboidsPicked`] event a frame or two later.
+///
+/// Picking honors the same visibility and clipping rules as the camera's
+/// render, but transparent cuboids are picked as if they were opaque.
+#[derive(Clone, Component, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsPickingCamera {
+    /// Position in physical pixels of the camera's render target, e.g. from
+    /// [`Window::physical_cursor_position`].
+    pub cursor: Option<Vec2>,
+}
+
+/// A single cuboid instance picked on the GPU.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GpuCuboidHit {
+    /// The entity holding the instances that were hit.
+    pub entity: Entity,
+    /// Index of the hit instance in [`Cuboids::instances`] or
+    /// [`OrientedCuboids::instances`].
+    pub instance_index: usize,
+}
+
+/// The result of picking with a [`CuboidsPickingCamera`].
+#[derive(Clone, Copy, Debug, Event, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsPicked {
+    pub camera: Entity,
+    /// The [`CuboidsPickingCamera::cursor`] that was picked.
+    pub cursor: Vec2,
+    /// `None` if there is no cuboid under the cursor.
+    pub hit: Option<GpuCuboidHit>,
+}
+
+/// Picks read back by the render world, waiting to be sent as events.
+#[derive(Clone, Default, Resource)]
+pub(crate) struct GpuPickingResults(pub Arc<Mutex<Vec<CuboidsPicked>>>);
+
+pub(crate) fn send_gpu_picking_events(
+    results: Res<GpuPickingResults>,
+    mut events: EventWriter<CuboidsPicked>,
+) {
+    events.send_batch(results.0.lock().unwrap().drain(..));
+}
+
+/// Returns the ray parameter where `ray` enters `cuboid` and the axis of the
+/// face it enters through.
+///
+/// Rays starting inside of the cuboid don't hit it, since only the faces
+/// pointing towards the ray origin are rendered.
+pub(crate) fn ray_cuboid_entry(ray: &Ray, cuboid: &Cuboid) -> Option<(f32, usize)> {
+    let (t_enter, _, axis) = ray_aabb_intersection(ray, cuboid.minimum, cuboid.maximum)?;
+    (t_enter >= 0.0).then_some((t_enter, axis))
+}
+
+/// Slab test, returning the entry and exit ray parameters along with the axis
+/// of the entry face.
+pub(crate) fn ray_aabb_intersection(ray: &Ray, min: Vec3, max: Vec3) -> Option<(f32, f32, usize)> {
+    let inv_dir = ray.direction.recip();
+    let t0 = (min - ray.origin) * inv_dir;
+    let t1 = (max - ray.origin) * inv_dir;
+    let t_near = t0.min(t1);
+    let t_far = t0.max(t1);
+
+    let mut axis = 0;
+    for i in 1..3 {
+        if t_near[i] > t_near[axis] {
+            axis = i;
+        }
+    }
+    let t_enter = t_near.max_element();
+    let t_exit = t_far.min_element();
+    // NaN comparisons (e.g. a ray in the plane of a face) conservatively miss.
+    (t_enter <= t_exit).then_some((t_enter, t_exit, axis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4, SQRT_2};
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray { origin, direction }
+    }
+
+    #[test]
+    fn cuboids_are_entered_through_the_nearest_face() {
+        let cuboid = Cuboid::new(Vec3::ZERO, Vec3::ONE, 0);
+        let (t_enter, t_exit, normal) = cuboid
+            .ray_intersection(&ray(Vec3::new(0.5, 0.5, 5.0), Vec3::NEG_Z))
+            .unwrap();
+        assert_eq!((t_enter, t_exit), (4.0, 5.0));
+        assert_eq!(normal, Vec3::Z);
+
+        assert!(cuboid
+            .ray_intersection(&ray(Vec3::new(2.0, 0.5, 5.0), Vec3::NEG_Z))
+            .is_none());
+        // Only the faces pointing towards the origin are rendered.
+        assert!(ray_cuboid_entry(&ray(Vec3::splat(0.5), Vec3::X), &cuboid).is_none());
+    }
+
+    #[test]
+    fn oriented_cuboids_are_hit_in_their_frame() {
+        let cuboid = OrientedCuboid::new(
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::ONE,
+            Quat::from_rotation_z(FRAC_PI_4),
+            0,
+        );
+
+        // Inside of the bounding box, but outside of the rotated cuboid.
+        let corner = ray(Vec3::new(1.2, 1.2, 10.0), Vec3::NEG_Z);
+        assert!(cuboid.ray_intersection(&corner).is_none());
+
+        let (t_enter, t_exit, normal) = cuboid
+            .ray_intersection(&ray(Vec3::new(10.0, 0.5, 1.0), Vec3::NEG_X))
+            .unwrap();
+        assert!((t_enter - (10.5 - SQRT_2)).abs() < 1e-5);
+        assert!((t_exit - (9.5 + SQRT_2)).abs() < 1e-5);
+        assert!(normal.abs_diff_eq(Vec3::new(FRAC_1_SQRT_2, FRAC_1_SQRT_2, 0.0), 1e-5));
+    }
+}
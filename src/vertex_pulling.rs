@@ -1,12 +1,19 @@
 // Original copyright: robswain, bevy-vertex-pulling, MIT OR Apache-2.0
 
+mod batching;
 mod buffers;
 mod cuboid_cache;
+mod culling;
 mod draw;
 mod extract;
 mod index_buffer;
+mod lod;
+mod picking;
 mod pipeline;
 mod prepare;
 mod queue;
+#[cfg(feature = "pbr")]
+mod shadow;
+mod view_overrides;
 
 pub mod plugin;
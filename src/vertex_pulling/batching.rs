@@ -0,0 +1,328 @@
+use crate::CuboidMaterial;
+
+use bevy::{
+    core::{cast_slice, Pod},
+    prelude::*,
+    render::{
+        render_resource::{BindGroup, BindingResource, Buffer, BufferDescriptor, BufferUsages},
+        renderer::{RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+use std::ops::Range;
+
+/// Batched entities with the same material and instance type share their
+/// instance buffers and draw calls.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct CuboidBatchKey {
+    pub material: AssetId<CuboidMaterial>,
+    /// The instances are [`OrientedCuboid`](crate::OrientedCuboid)s.
+    pub oriented: bool,
+}
+
+#[derive(Resource)]
+pub(crate) struct CuboidBatches {
+    pub batches: HashMap<CuboidBatchKey, CuboidBatch>,
+    /// Instance ranges to draw for each batch queued in a view, by the view
+    /// and the entity that the batch was queued with.
+    pub draws: HashMap<(Entity, Entity), Vec<Range<u32>>>,
+    /// The largest instance buffer that can be bound.
+    max_batch_size: usize,
+}
+
+impl FromWorld for CuboidBatches {
+    fn from_world(world: &mut World) -> Self {
+        let limits = world.resource::<RenderDevice>().limits();
+        Self {
+            batches: default(),
+            draws: default(),
+            max_batch_size: limits.max_storage_buffer_binding_size as usize,
+        }
+    }
+}
+
+impl CuboidBatches {
+    /// Returns `true` if `num_instances` instances of type `T` for `entity`
+    /// fit into the batch for `key`. Entities that don't fit are drawn on
+    /// their own.
+    pub fn has_room<T>(&self, key: CuboidBatchKey, entity: Entity, num_instances: usize) -> bool {
+        let max_instances = self.max_batch_size / std::mem::size_of::<T>();
+        let Some(batch) = self.batches.get(&key) else {
+            return num_instances <= max_instances;
+        };
+        let own = batch.members.get(&entity).map_or(0, |range| range.len());
+        own == num_instances || batch.len() - batch.unused - own + num_instances <= max_instances
+    }
+
+    /// Copies the instances of `entity` into the batch for `key`, adding the
+    /// entity to it if necessary. Only called if [`Self::has_room`].
+    ///
+    /// `dirty_ranges` are only used when `changed`, and an empty slice means
+    /// all instances changed.
+    pub fn update_member<T: Pod>(
+        &mut self,
+        key: CuboidBatchKey,
+        entity: Entity,
+        instances: &[T],
+        dirty_ranges: &[Range<usize>],
+        changed: bool,
+        transform_slot: u32,
+    ) {
+        let max_instances = self.max_batch_size / std::mem::size_of::<T>();
+        self.batches
+            .entry(key)
+            .or_insert_with(|| CuboidBatch::new(std::mem::size_of::<T>(), max_instances))
+            .update_member(entity, instances, dirty_ranges, changed, transform_slot);
+    }
+
+    pub fn remove_member(&mut self, key: CuboidBatchKey, entity: Entity) {
+        let Some(batch) = self.batches.get_mut(&key) else {
+            return;
+        };
+        batch.remove_member(entity);
+        if batch.members.is_empty() {
+            self.batches.remove(&key);
+        } else {
+            batch.compact_if_fragmented();
+        }
+    }
+
+    /// Records that the batched `entity` is visible in the view being queued.
+    pub fn add_visible(&self, view_batches: &mut ViewBatches, key: CuboidBatchKey, entity: Entity) {
+        let Some(range) = self
+            .batches
+            .get(&key)
+            .and_then(|batch| batch.members.get(&entity))
+        else {
+            return;
+        };
+        let (_, ranges) = view_batches
+            .batches
+            .entry(key)
+            .or_insert_with(|| (entity, Vec::new()));
+        ranges.push(range.start as u32..range.end as u32);
+    }
+
+    /// Stores the draws of each batch that's visible in `view`, and returns
+    /// the entities to queue them with.
+    pub fn finish_view(&mut self, view: Entity, view_batches: ViewBatches) -> Vec<Entity> {
+        let mut queued = Vec::with_capacity(view_batches.batches.len());
+        for (entity, mut ranges) in view_batches.batches.into_values() {
+            // Entities that are next to each other in the batch are drawn
+            // together.
+            ranges.sort_unstable_by_key(|range| range.start);
+            let mut runs: Vec<Range<u32>> = Vec::with_capacity(ranges.len());
+            for range in ranges {
+                match runs.last_mut() {
+                    Some(run) if run.end == range.start => run.end = range.end,
+                    _ => runs.push(range),
+                }
+            }
+            self.draws.insert((view, entity), runs);
+            queued.push(entity);
+        }
+        queued
+    }
+}
+
+/// The visible members of each batch in a single view.
+#[derive(Default)]
+pub(crate) struct ViewBatches {
+    /// The first visible member and the instance ranges of all visible
+    /// members.
+    batches: HashMap<CuboidBatchKey, (Entity, Vec<Range<u32>>)>,
+}
+
+/// The instances of all entities in a batch, in a single buffer.
+///
+/// Members that are removed or change their number of instances leave unused
+/// ranges behind, which are reclaimed once they make up half of the buffer.
+pub(crate) struct CuboidBatch {
+    /// Raw [`Cuboid`](crate::Cuboid)s or
+    /// [`OrientedCuboid`](crate::OrientedCuboid)s.
+    instances: Vec<u8>,
+    instance_size: usize,
+    max_instances: usize,
+    /// The transform slot and the index within its entity of each instance.
+    instance_refs: Vec<[u32; 2]>,
+    members: HashMap<Entity, Range<usize>>,
+    /// Number of instances that don't belong to a member.
+    unused: usize,
+    /// Instance ranges that must be written to the existing GPU buffers.
+    dirty_ranges: Vec<Range<usize>>,
+    instance_buffer: Option<Buffer>,
+    instance_refs_buffer: Option<Buffer>,
+    pub bind_group: Option<BindGroup>,
+}
+
+impl CuboidBatch {
+    fn new(instance_size: usize, max_instances: usize) -> Self {
+        Self {
+            instances: Vec::new(),
+            instance_size,
+            max_instances,
+            instance_refs: Vec::new(),
+            members: HashMap::default(),
+            unused: 0,
+            dirty_ranges: Vec::new(),
+            instance_buffer: None,
+            instance_refs_buffer: None,
+            bind_group: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.instance_refs.len()
+    }
+
+    fn update_member<T: Pod>(
+        &mut self,
+        entity: Entity,
+        instances: &[T],
+        dirty_ranges: &[Range<usize>],
+        changed: bool,
+        transform_slot: u32,
+    ) {
+        if let Some(range) = self.members.get(&entity).cloned() {
+            if range.len() == instances.len() {
+                if changed {
+                    let all = 0..instances.len();
+                    let ranges = if dirty_ranges.is_empty() {
+                        std::slice::from_ref(&all)
+                    } else {
+                        dirty_ranges
+                    };
+                    for dirty in ranges {
+                        let start = range.start + dirty.start;
+                        self.write_instances(start, &instances[dirty.clone()]);
+                        self.dirty_ranges.push(start..start + dirty.len());
+                    }
+                }
+                if self.instance_refs[range.start][0] != transform_slot {
+                    self.write_refs(range.clone(), transform_slot);
+                    self.dirty_ranges.push(range);
+                }
+                return;
+            }
+            self.remove_member(entity);
+        }
+        if self.len() + instances.len() > self.max_instances {
+            self.compact();
+        }
+
+        // Appended members are written without reallocating while the
+        // buffers have room for them.
+        let start = self.len();
+        self.instances.extend_from_slice(cast_slice(instances));
+        self.instance_refs
+            .extend((0..instances.len() as u32).map(|i| [transform_slot, i]));
+        self.members.insert(entity, start..self.len());
+        self.dirty_ranges.push(start..self.len());
+        self.compact_if_fragmented();
+    }
+
+    fn remove_member(&mut self, entity: Entity) {
+        if let Some(range) = self.members.remove(&entity) {
+            self.unused += range.len();
+        }
+    }
+
+    fn write_instances<T: Pod>(&mut self, start: usize, instances: &[T]) {
+        let bytes: &[u8] = cast_slice(instances);
+        let offset = start * self.instance_size;
+        self.instances[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn write_refs(&mut self, range: Range<usize>, transform_slot: u32) {
+        for (i, instance_ref) in self.instance_refs[range].iter_mut().enumerate() {
+            *instance_ref = [transform_slot, i as u32];
+        }
+    }
+
+    /// Moves all members to the front once unused instances make up half of
+    /// the buffer.
+    fn compact_if_fragmented(&mut self) {
+        if self.unused > 0 && self.unused * 2 >= self.len() {
+            self.compact();
+        }
+    }
+
+    /// Moves all members to the front, reclaiming the unused instances.
+    fn compact(&mut self) {
+        let size = self.instance_size;
+        let mut instances = Vec::with_capacity((self.len() - self.unused) * size);
+        let mut instance_refs = Vec::with_capacity(self.len() - self.unused);
+        for range in self.members.values_mut() {
+            let start = instance_refs.len();
+            instances.extend_from_slice(&self.instances[range.start * size..range.end * size]);
+            instance_refs.extend_from_slice(&self.instance_refs[range.clone()]);
+            *range = start..instance_refs.len();
+        }
+        self.instances = instances;
+        self.instance_refs = instance_refs;
+        self.unused = 0;
+        self.dirty_ranges.clear();
+        self.dirty_ranges.push(0..self.len());
+    }
+
+    /// Writes the modified instances to the GPU, returning `true` if the
+    /// buffers were reallocated, invalidating the bind group.
+    pub fn write_buffers(&mut self, device: &RenderDevice, queue: &RenderQueue) -> bool {
+        let size = self.instances.len() as u64;
+        if size == 0 {
+            return false;
+        }
+        let has_room = self
+            .instance_buffer
+            .as_ref()
+            .is_some_and(|buffer| buffer.size() >= size);
+        if has_room {
+            let instance_buffer = self.instance_buffer.as_ref().unwrap();
+            let instance_refs_buffer = self.instance_refs_buffer.as_ref().unwrap();
+            for range in self.dirty_ranges.drain(..) {
+                let bytes = range.start * self.instance_size..range.end * self.instance_size;
+                queue.write_buffer(instance_buffer, bytes.start as u64, &self.instances[bytes]);
+                queue.write_buffer(
+                    instance_refs_buffer,
+                    (range.start * std::mem::size_of::<[u32; 2]>()) as u64,
+                    cast_slice(&self.instance_refs[range]),
+                );
+            }
+            return false;
+        }
+
+        // Grow geometrically so that adding members doesn't reallocate every
+        // frame.
+        let capacity = self.len().next_power_of_two().min(self.max_instances);
+        let create_buffer = |label, size: usize, data: &[u8]| {
+            let buffer = device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: size as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            queue.write_buffer(&buffer, 0, data);
+            buffer
+        };
+        self.instance_buffer = Some(create_buffer(
+            "cuboid_batch_instance_buffer",
+            capacity * self.instance_size,
+            &self.instances,
+        ));
+        self.instance_refs_buffer = Some(create_buffer(
+            "cuboid_batch_instance_refs_buffer",
+            capacity * std::mem::size_of::<[u32; 2]>(),
+            cast_slice(&self.instance_refs),
+        ));
+        self.dirty_ranges.clear();
+        true
+    }
+
+    /// The instance and instance reference buffers.
+    pub fn bindings(&self) -> Option<(BindingResource<'_>, BindingResource<'_>)> {
+        Some((
+            self.instance_buffer.as_ref()?.as_entire_binding(),
+            self.instance_refs_buffer.as_ref()?.as_entire_binding(),
+        ))
+    }
+}
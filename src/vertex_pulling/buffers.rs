@@ -1,20 +1,174 @@
-use crate::clipping_planes::GpuClippingPlaneRanges;
+use crate::clipping_planes::{
+    GpuClippingPlaneRange, GpuClippingPlaneRanges, GpuViewClippingGroups,
+};
 use crate::cuboids::CuboidsTransform;
+use crate::lighting::GpuCuboidLighting;
 use crate::CuboidMaterial;
+use bevy::math::UVec2;
 use bevy::prelude::{Deref, DerefMut, Resource};
-use bevy::render::render_resource::{DynamicUniformBuffer, UniformBuffer};
+use bevy::render::render_resource::{
+    encase::{self, internal::WriteInto},
+    BindingResource, Buffer, BufferBinding, BufferDescriptor, BufferUsages, DynamicUniformBuffer,
+    ShaderType, StorageBuffer, UniformBuffer,
+};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use std::marker::PhantomData;
+use std::ops::Range;
 
-#[derive(Resource, Default, Deref, DerefMut)]
-pub(crate) struct DynamicUniformBufferOfCuboidMaterial(
-    pub(crate) DynamicUniformBuffer<CuboidMaterial>,
+#[derive(Resource, Deref, DerefMut)]
+pub(crate) struct PersistentUniformBufferOfCuboidMaterial(
+    pub(crate) PersistentUniformBuffer<CuboidMaterial>,
 );
 
-#[derive(Resource, Default, Deref, DerefMut)]
-pub(crate) struct DynamicUniformBufferOfCuboidTransforms(
-    pub(crate) DynamicUniformBuffer<CuboidsTransform>,
+impl Default for PersistentUniformBufferOfCuboidMaterial {
+    fn default() -> Self {
+        Self(PersistentUniformBuffer::new(
+            "cuboid_materials_buffer",
+            BufferUsages::UNIFORM,
+        ))
+    }
+}
+
+#[derive(Resource, Deref, DerefMut)]
+pub(crate) struct PersistentUniformBufferOfCuboidTransforms(
+    pub(crate) PersistentUniformBuffer<CuboidsTransform>,
 );
 
+impl Default for PersistentUniformBufferOfCuboidTransforms {
+    fn default() -> Self {
+        // Batched draws index all transforms in a storage buffer.
+        Self(PersistentUniformBuffer::new(
+            "cuboid_transforms_buffer",
+            BufferUsages::UNIFORM | BufferUsages::STORAGE,
+        ))
+    }
+}
+
 #[derive(Resource, Default, Deref, DerefMut)]
 pub(crate) struct UniformBufferOfGpuClippingPlaneRanges(
     pub(crate) UniformBuffer<GpuClippingPlaneRanges>,
 );
+
+/// Only used when storage buffers are supported.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct StorageBufferOfGpuClippingPlaneRanges(
+    pub(crate) StorageBuffer<Vec<GpuClippingPlaneRange>>,
+);
+
+/// The first entry holds the defaults for views without overrides.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct DynamicUniformBufferOfViewClippingGroups(
+    pub(crate) DynamicUniformBuffer<GpuViewClippingGroups>,
+);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct UniformBufferOfGpuCuboidLighting(pub(crate) UniformBuffer<GpuCuboidLighting>);
+
+/// Packed [`CuboidPalettes`](crate::CuboidPalettes).
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct StorageBufferOfCuboidPalettes(pub(crate) StorageBuffer<Vec<UVec2>>);
+
+/// A dynamic uniform buffer whose entries keep their offsets until they're
+/// removed, so only new and modified entries are written to the GPU.
+pub(crate) struct PersistentUniformBuffer<T> {
+    label: &'static str,
+    usage: BufferUsages,
+    data: Vec<u8>,
+    /// Offsets of removed entries, reused by the next pushes.
+    free_offsets: Vec<u32>,
+    /// Byte ranges of `data` that must be written to the existing buffer.
+    dirty_ranges: Vec<Range<usize>>,
+    buffer: Option<Buffer>,
+    marker: PhantomData<T>,
+}
+
+impl<T: ShaderType + WriteInto> PersistentUniformBuffer<T> {
+    /// Satisfies every device's `min_uniform_buffer_offset_alignment`.
+    const ALIGNMENT: usize = 256;
+
+    pub fn new(label: &'static str, usage: BufferUsages) -> Self {
+        Self {
+            label,
+            usage,
+            data: Vec::new(),
+            free_offsets: Vec::new(),
+            dirty_ranges: Vec::new(),
+            buffer: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// The distance between the dynamic offsets of consecutive entries.
+    pub fn stride() -> usize {
+        (T::min_size().get() as usize).next_multiple_of(Self::ALIGNMENT)
+    }
+
+    /// Adds `value` to the buffer and returns its dynamic offset.
+    pub fn push(&mut self, value: &T) -> u32 {
+        let offset = self.free_offsets.pop().unwrap_or_else(|| {
+            let offset = self.data.len();
+            self.data.resize(offset + Self::stride(), 0);
+            offset as u32
+        });
+        self.set(offset, value);
+        offset
+    }
+
+    /// Overwrites the value at the dynamic `offset` returned by
+    /// [`Self::push`].
+    pub fn set(&mut self, offset: u32, value: &T) {
+        let range = offset as usize..offset as usize + Self::stride();
+        encase::UniformBuffer::new(&mut self.data[range.clone()])
+            .write(value)
+            .unwrap();
+        self.dirty_ranges.push(range);
+    }
+
+    /// Frees the value at the dynamic `offset` for reuse.
+    pub fn remove(&mut self, offset: u32) {
+        self.free_offsets.push(offset);
+    }
+
+    /// Writes the modified entries to the GPU, returning `true` if the buffer
+    /// was reallocated, invalidating its bind groups.
+    pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) -> bool {
+        let size = self.data.len() as u64;
+        if size == 0 {
+            return false;
+        }
+        match &self.buffer {
+            Some(buffer) if buffer.size() >= size => {
+                for range in self.dirty_ranges.drain(..) {
+                    queue.write_buffer(buffer, range.start as u64, &self.data[range]);
+                }
+                false
+            }
+            _ => {
+                let buffer = device.create_buffer(&BufferDescriptor {
+                    label: Some(self.label),
+                    size: size.next_power_of_two(),
+                    usage: self.usage | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                queue.write_buffer(&buffer, 0, &self.data);
+                self.dirty_ranges.clear();
+                self.buffer = Some(buffer);
+                true
+            }
+        }
+    }
+
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        Some(BindingResource::Buffer(BufferBinding {
+            buffer: self.buffer.as_ref()?,
+            offset: 0,
+            size: Some(T::min_size()),
+        }))
+    }
+
+    /// Binds all entries, to index them as an array of [`Self::stride`] sized
+    /// elements.
+    pub fn array_binding(&self) -> Option<BindingResource<'_>> {
+        Some(self.buffer.as_ref()?.as_entire_binding())
+    }
+}
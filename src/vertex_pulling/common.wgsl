@@ -0,0 +1,464 @@
+#define_import_path bevy_aabb_instancing::common
+
+#import bevy_render::view::View
+
+fn hsl_to_nonlinear_srgb(hue: f32, saturation: f32, lightness: f32) -> vec3<f32> {
+    // https://en.wikipedia.org/wiki/HSL_and_HSV#HSL_to_RGB
+    let chroma = (1.0 - abs(2.0 * lightness - 1.0)) * saturation;
+    let hue_prime = hue / 60.0;
+    let largest_component = chroma * (1.0 - abs(hue_prime % 2.0 - 1.0));
+    var rgb_temp: vec3<f32>;
+    if (hue_prime < 1.0) {
+        rgb_temp = vec3<f32>(chroma, largest_component, 0.0);
+    } else if (hue_prime < 2.0) {
+        rgb_temp = vec3<f32>(largest_component, chroma, 0.0);
+    } else if (hue_prime < 3.0) {
+        rgb_temp = vec3<f32>(0.0, chroma, largest_component);
+    } else if (hue_prime < 4.0) {
+        rgb_temp = vec3<f32>(0.0, largest_component, chroma);
+    } else if (hue_prime < 5.0) {
+        rgb_temp = vec3<f32>(largest_component, 0.0, chroma);
+    } else {
+        rgb_temp = vec3<f32>(chroma, 0.0, largest_component);
+    }
+    let lightness_match = lightness - chroma / 2.0;
+    return rgb_temp + lightness_match;
+}
+
+struct ScalarHueOptions {
+    min_visible: f32,
+    max_visible: f32,
+    clamp_min: f32,
+    clamp_max: f32,
+    hue_zero: f32,
+    hue_slope: f32,
+    lightness: f32,
+    saturation: f32,
+}
+
+struct CuboidMaterial {
+    color_mode: u32,
+    wireframe: u32, // Any nonzero value means "on".
+    // Uniform buffers align nested structs to 16 bytes. This can't be an
+    // `@align` attribute, which is lost when the module is imported.
+    _scalar_hue_padding: vec2<u32>,
+    scalar_hue: ScalarHueOptions,
+    emissive_gain: vec3<f32>,
+    opacity: f32,
+    instance_alpha: u32, // Any nonzero value means "on".
+    colormap: u32,
+    palette: u32,
+    lighting: u32, // Any nonzero value means "on".
+    light_direction: vec3<f32>,
+    precise_clipping: u32, // Any nonzero value means "on".
+    cap_color: vec4<f32>,
+    // Attributes replacing `cuboid.color` and filtered by visibility, or
+    // 0xFFFFFFFF for none.
+    color_attribute: u32,
+    visibility_attribute: u32,
+}
+
+struct Lighting {
+    direction: vec3<f32>,
+    color: vec3<f32>,
+    ambient: vec3<f32>,
+}
+
+struct ClippingPlaneRange {
+    origin: vec3<f32>,
+    unit_normal: vec3<f32>,
+    min_sdist: f32,
+    max_sdist: f32,
+    groups: u32,
+}
+
+struct ClippingBox {
+    world_to_box: mat4x4<f32>,
+    half_extents: vec3<f32>,
+    keep_inside: u32,
+    groups: u32,
+}
+
+struct ClippingSphere {
+    center: vec3<f32>,
+    radius: f32,
+    keep_inside: u32,
+    groups: u32,
+}
+
+struct ViewClippingGroups {
+    groups: u32,
+}
+
+struct ClippingPlaneRanges {
+    ranges: array<ClippingPlaneRange, 16>,
+    num_ranges: u32,
+    boxes: array<ClippingBox, 8>,
+    num_boxes: u32,
+    spheres: array<ClippingSphere, 8>,
+    num_spheres: u32,
+}
+
+#ifdef ORIENTED
+struct Cuboid {
+    center: vec3<f32>,
+    meta_bits: u32,
+    half_extents: vec3<f32>,
+    color: u32,
+    // Unit quaternion.
+    rotation: vec4<f32>,
+}
+#else
+struct Cuboid {
+    min: vec3<f32>,
+    meta_bits: u32,
+    max: vec3<f32>,
+    color: u32,
+}
+#endif
+
+struct Cuboids {
+    data: array<Cuboid>,
+}
+
+struct Transform {
+    m: mat4x4<f32>,
+    m_inv: mat4x4<f32>,
+    // Bits of the entity holding the instances, for GPU picking.
+    entity: vec2<u32>,
+    // Only clipping volumes in any of these groups apply.
+    clipping_groups: u32,
+    // Attributes per instance, in `attributes`.
+    num_attributes: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> view: View;
+
+@group(1) @binding(0)
+var<uniform> material: CuboidMaterial;
+
+@group(1) @binding(1)
+var<uniform> clipping_planes: ClippingPlaneRanges;
+
+// One gradient per row.
+@group(1) @binding(2)
+var colormap_atlas: texture_2d<f32>;
+
+@group(1) @binding(3)
+var colormap_sampler: sampler;
+
+// [num_palettes, 0], then [entries_offset, num_entries] per palette, then
+// [rgba, visible] per entry.
+@group(1) @binding(4)
+var<storage> palettes: array<vec2<u32>>;
+
+@group(1) @binding(5)
+var<uniform> lighting: Lighting;
+
+#ifdef CLIPPING_PLANES_STORAGE
+// Replaces `clipping_planes.ranges`, which is limited to 16 planes.
+@group(1) @binding(6)
+var<storage> clipping_plane_ranges: array<ClippingPlaneRange>;
+#endif
+
+// Only clipping volumes in these groups apply in the current view.
+@group(1) @binding(7)
+var<uniform> view_clipping_groups: ViewClippingGroups;
+
+#ifdef BATCHED
+// Batched draws index the persistent transform uniforms as an array, so each
+// transform is padded to their 256 byte dynamic offset alignment.
+struct TransformSlot {
+    transform: Transform,
+    padding: array<vec4<f32>, 7>,
+}
+
+@group(2) @binding(0)
+var<storage> transform_slots: array<TransformSlot>;
+
+// The transform of the current instance, set by `load_transform`.
+var<private> transform: Transform;
+
+fn load_transform(slot: u32) {
+    transform = transform_slots[slot].transform;
+}
+#else
+@group(2) @binding(0)
+var<uniform> transform: Transform;
+#endif
+
+#ifdef ATTRIBUTES
+// `transform.num_attributes` scalars per instance, instance by instance.
+@group(3) @binding(2)
+var<storage> attributes: array<f32>;
+
+// Index of the current instance's first attribute, set by `load_attributes`.
+var<private> attributes_offset: u32;
+
+fn load_attributes(cuboid_index: u32) {
+    attributes_offset = cuboid_index * transform.num_attributes;
+}
+#endif
+
+fn quat_to_mat3(q: vec4<f32>) -> mat3x3<f32> {
+    let x2 = q.x + q.x;
+    let y2 = q.y + q.y;
+    let z2 = q.z + q.z;
+    let xx = q.x * x2;
+    let xy = q.x * y2;
+    let xz = q.x * z2;
+    let yy = q.y * y2;
+    let yz = q.y * z2;
+    let zz = q.z * z2;
+    let wx = q.w * x2;
+    let wy = q.w * y2;
+    let wz = q.w * z2;
+    return mat3x3<f32>(
+        vec3<f32>(1.0 - (yy + zz), xy + wz, xz - wy),
+        vec3<f32>(xy - wz, 1.0 - (xx + zz), yz + wx),
+        vec3<f32>(xz + wy, yz - wx, 1.0 - (xx + yy)),
+    );
+}
+
+fn cuboid_center(cuboid: Cuboid) -> vec3<f32> {
+#ifdef ORIENTED
+    return cuboid.center;
+#else
+    return (cuboid.min + cuboid.max) / 2.0;
+#endif
+}
+
+fn cuboid_half_extents(cuboid: Cuboid) -> vec3<f32> {
+#ifdef ORIENTED
+    return cuboid.half_extents;
+#else
+    return (cuboid.max - cuboid.min) / 2.0;
+#endif
+}
+
+// Rotation from the cuboid's frame to model space.
+fn cuboid_rotation(cuboid: Cuboid) -> mat3x3<f32> {
+#ifdef ORIENTED
+    return quat_to_mat3(cuboid.rotation);
+#else
+    return mat3x3<f32>(
+        vec3<f32>(1.0, 0.0, 0.0),
+        vec3<f32>(0.0, 1.0, 0.0),
+        vec3<f32>(0.0, 0.0, 1.0),
+    );
+#endif
+}
+
+// Returns the [rgba, visible] palette entry for a category, or zero if there is
+// no such entry.
+fn palette_entry(category: u32) -> vec2<u32> {
+    if (material.palette >= palettes[0].x) {
+        return vec2<u32>(0u);
+    }
+    let header = palettes[1u + material.palette];
+    if (category >= header.y) {
+        return vec2<u32>(0u);
+    }
+    return palettes[header.x + category];
+}
+
+// The value that the color mode interprets: `cuboid.color`, or the attribute
+// selected by `material.color_attribute`.
+fn cuboid_color_value(cuboid: Cuboid) -> u32 {
+#ifdef ATTRIBUTES
+    if (material.color_mode != 0u && material.color_attribute < transform.num_attributes) {
+        let value = attributes[attributes_offset + material.color_attribute];
+        if (material.color_mode == 3u) {
+            // CATEGORICAL
+            return u32(value);
+        }
+        return bitcast<u32>(value);
+    }
+#endif
+    return cuboid.color;
+}
+
+// Lighting factor for a face with the given world space normal.
+fn face_lighting(world_normal: vec3<f32>) -> vec3<f32> {
+    var direction = lighting.direction;
+    if (any(material.light_direction != vec3<f32>(0.0))) {
+        direction = normalize(material.light_direction);
+    }
+    let n_dot_l = max(dot(world_normal, -direction), 0.0);
+    return lighting.ambient + lighting.color * n_dot_l;
+}
+
+// Returns true if the cuboid must not be rendered in any view.
+fn cuboid_is_discarded(cuboid: Cuboid) -> bool {
+    // Check visibility mask.
+    if ((cuboid.meta_bits & 0x01u) != 0u) {
+        return true;
+    }
+
+    let color = cuboid_color_value(cuboid);
+
+    // SCALAR HUE or SCALAR COLORMAP filter their scalar, unless an attribute
+    // is selected for visibility.
+    var filter_scalar = material.color_mode == 1u || material.color_mode == 2u;
+    var scalar = bitcast<f32>(color);
+#ifdef ATTRIBUTES
+    if (material.visibility_attribute < transform.num_attributes) {
+        filter_scalar = true;
+        scalar = attributes[attributes_offset + material.visibility_attribute];
+    }
+#endif
+    if (filter_scalar && (scalar < material.scalar_hue.min_visible ||
+        scalar > material.scalar_hue.max_visible))
+    {
+        return true;
+    }
+
+    if (material.color_mode == 3u) {
+        // CATEGORICAL
+        if (palette_entry(color).y == 0u) {
+            return true;
+        }
+    }
+
+    if (clipping_planes.num_ranges > 0u ||
+        clipping_planes.num_boxes > 0u ||
+        clipping_planes.num_spheres > 0u)
+    {
+        let tfm_cuboid_center_v4 = transform.m * vec4<f32>(cuboid_center(cuboid), 1.0);
+        let tfm_cuboid_center = tfm_cuboid_center_v4.xyz / tfm_cuboid_center_v4.w;
+
+        // With precise clipping, cuboids are only discarded when they're
+        // entirely outside of a plane's range, and the fragment shader cuts the
+        // rest.
+        var half_axes = mat3x3<f32>(vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0));
+        if material.precise_clipping != 0u {
+            let m3 = mat3x3<f32>(transform.m[0].xyz, transform.m[1].xyz, transform.m[2].xyz);
+            let half = cuboid_half_extents(cuboid);
+            let scale = mat3x3<f32>(
+                vec3<f32>(half.x, 0.0, 0.0),
+                vec3<f32>(0.0, half.y, 0.0),
+                vec3<f32>(0.0, 0.0, half.z),
+            );
+            half_axes = m3 * cuboid_rotation(cuboid) * scale;
+        }
+        return point_is_clipped(tfm_cuboid_center, half_axes);
+    }
+
+    return false;
+}
+
+fn clipping_plane_range(i: u32) -> ClippingPlaneRange {
+#ifdef CLIPPING_PLANES_STORAGE
+    return clipping_plane_ranges[i];
+#else
+    return clipping_planes.ranges[i];
+#endif
+}
+
+// Groups of the clipping volumes that apply to the current transform in the
+// current view.
+fn clipping_groups() -> u32 {
+    return transform.clipping_groups & view_clipping_groups.groups;
+}
+
+// Returns true if the world space point is clipped by any plane, box or
+// sphere in the clipping groups.
+//
+// Planes only clip the point if the box spanned by `half_axes` around it lies
+// entirely outside of their range.
+fn point_is_clipped(point: vec3<f32>, half_axes: mat3x3<f32>) -> bool {
+    let groups = clipping_groups();
+
+    // Clip any cuboid instance that falls out of the allowed ranges.
+    for (var i = 0u; i < clipping_planes.num_ranges; i++) {
+        let range = clipping_plane_range(i);
+        if (range.groups & groups) == 0u {
+            continue;
+        }
+        let sdist_to_plane = dot(point - range.origin, range.unit_normal);
+        let radius = dot(abs(range.unit_normal * half_axes), vec3<f32>(1.0));
+        if sdist_to_plane + radius < range.min_sdist || sdist_to_plane - radius > range.max_sdist {
+            return true;
+        }
+    }
+
+    for (var i = 0u; i < clipping_planes.num_boxes; i++) {
+        let clipping_box = clipping_planes.boxes[i];
+        if (clipping_box.groups & groups) == 0u {
+            continue;
+        }
+        let local = (clipping_box.world_to_box * vec4<f32>(point, 1.0)).xyz;
+        let inside = all(abs(local) <= clipping_box.half_extents);
+        if inside != (clipping_box.keep_inside != 0u) {
+            return true;
+        }
+    }
+
+    for (var i = 0u; i < clipping_planes.num_spheres; i++) {
+        let sphere = clipping_planes.spheres[i];
+        if (sphere.groups & groups) == 0u {
+            continue;
+        }
+        let inside = distance(point, sphere.center) <= sphere.radius;
+        if inside != (sphere.keep_inside != 0u) {
+            return true;
+        }
+    }
+
+    return false;
+}
+
+fn cuboid_color(cuboid: Cuboid) -> vec4<f32> {
+    let value = cuboid_color_value(cuboid);
+    var color: vec4<f32>;
+    if (material.color_mode == 1u) {
+        // SCALAR HUE
+        let opt = material.scalar_hue;
+        let scalar = bitcast<f32>(value);
+
+        // HSL
+        let cmin = opt.clamp_min;
+        let cmax = opt.clamp_max;
+        let s = (clamp(scalar, cmin, cmax) - cmin) / (cmax - cmin);
+        let hue = (360.0 + (opt.hue_zero + s * opt.hue_slope)) % 360.0;
+        color = vec4<f32>(hsl_to_nonlinear_srgb(hue, opt.saturation, opt.lightness), 1.0);
+    } else if (material.color_mode == 2u) {
+        // SCALAR COLORMAP
+        let opt = material.scalar_hue;
+        let scalar = bitcast<f32>(value);
+        let cmin = opt.clamp_min;
+        let cmax = opt.clamp_max;
+        let s = (clamp(scalar, cmin, cmax) - cmin) / (cmax - cmin);
+
+        // Sample between the centers of the first and last texels.
+        let size = vec2<f32>(textureDimensions(colormap_atlas));
+        let uv = vec2<f32>(
+            (s * (size.x - 1.0) + 0.5) / size.x,
+            (f32(material.colormap) + 0.5) / size.y,
+        );
+        color = textureSampleLevel(colormap_atlas, colormap_sampler, uv, 0.0);
+    } else {
+        // RGB or CATEGORICAL
+        var rgba = value;
+        if (material.color_mode == 3u) {
+            rgba = palette_entry(value).x;
+        }
+        var alpha = 255.0;
+        if (material.instance_alpha != 0u) {
+            alpha = f32(rgba >> 24u);
+        }
+        color = vec4<f32>(
+            f32(rgba & 0xFFu),
+            f32((rgba >> 8u) & 0xFFu),
+            f32((rgba >> 16u) & 0xFFu),
+            alpha
+        ) / 255.0;
+    }
+
+    if ((cuboid.meta_bits & 0x02u) != 0u) {
+        color *= vec4(material.emissive_gain, 1.0);
+    }
+    color.a *= material.opacity;
+    return color;
+}
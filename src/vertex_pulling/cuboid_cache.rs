@@ -1,30 +1,100 @@
-use crate::Cuboid;
+use super::batching::{CuboidBatchKey, CuboidBatches};
+use super::buffers::PersistentUniformBufferOfCuboidTransforms;
+use super::lod::CachedCuboidsLod;
+use crate::{Cuboid, CuboidMaterial, OrientedCuboid};
 
 use bevy::{
+    core::{cast_slice, Pod},
     prelude::*,
-    render::render_resource::{BindGroup, StorageBuffer},
+    render::{
+        render_resource::{
+            encase::internal::WriteInto, BindGroup, BindingResource, Buffer, ShaderSize,
+            StorageBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
     utils::HashMap,
 };
+use std::ops::Range;
 
 #[derive(Default, Resource)]
 pub(crate) struct CuboidBufferCache {
     pub entries: HashMap<Entity, CachedCuboidBuffers>,
 }
 
+/// The parts of a [`CuboidMaterial`](crate::CuboidMaterial) that decide how
+/// cuboids are queued and drawn.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct CachedCuboidMaterial {
+    pub uniform_index: u32,
+    /// The material requires alpha blending.
+    pub transparent: bool,
+    /// The material cuts cuboids at clipping planes per fragment.
+    pub precise_clipping: bool,
+}
+
+impl CachedCuboidMaterial {
+    pub fn new(uniform_index: u32, material: &CuboidMaterial) -> Self {
+        Self {
+            uniform_index,
+            transparent: material.is_transparent(),
+            precise_clipping: material.precise_clipping != 0,
+        }
+    }
+}
+
 #[derive(Default)]
 pub(crate) struct CachedCuboidBuffers {
-    pub material_index: u32,
+    pub material: CachedCuboidMaterial,
     pub dirty: bool,
+    /// Instance ranges that must be written to the existing GPU buffer when
+    /// the whole buffer is not `dirty`.
+    pub dirty_ranges: Vec<Range<usize>>,
     pub enabled: bool,
     pub keep_alive: bool,
-    pub instance_buffer: StorageBuffer<Vec<Cuboid>>,
+    pub instance_buffer: InstanceBuffer,
     pub instance_buffer_bind_group: Option<BindGroup>,
     pub position: Vec3,
+    pub clipping_groups: u32,
+    /// Attributes per instance in the transform uniform, or 0 without
+    /// `attributes`.
+    pub num_attributes: u32,
+    /// The entity's [`CuboidAttributes`](crate::CuboidAttributes), bound next
+    /// to `instance_buffer`.
+    pub attributes: Option<CachedCuboidAttributes>,
+    /// Dynamic offset of the entity's persistent transform uniform.
     pub transform_index: u32,
+    /// The batch holding the instances instead of `instance_buffer`.
+    pub batch: Option<CuboidBatchKey>,
+    /// Coarser levels of detail, drawn instead of `instance_buffer` in views
+    /// listed in [`SelectedCuboidLods`](super::lod::SelectedCuboidLods).
+    pub lod: Option<CachedCuboidsLod>,
+}
+
+#[derive(Default)]
+pub(crate) struct CachedCuboidAttributes {
+    pub buffer: StorageBuffer<Vec<f32>>,
+    /// The buffer must be uploaded, and bound again.
+    pub dirty: bool,
+}
+
+impl CachedCuboidBuffers {
+    pub fn is_oriented(&self) -> bool {
+        match self.batch {
+            Some(key) => key.oriented,
+            None => self.instance_buffer.is_oriented(),
+        }
+    }
 }
 
 impl CuboidBufferCache {
-    pub fn cull_entities(&mut self) {
+    /// Removes the entries that weren't extracted this frame, freeing their
+    /// transform uniforms and batched instances.
+    pub fn cull_entities(
+        &mut self,
+        transform_uniforms: &mut PersistentUniformBufferOfCuboidTransforms,
+        batches: &mut CuboidBatches,
+    ) {
         let mut to_remove = Vec::new();
         for (entity, entry) in self.entries.iter_mut() {
             if !entry.keep_alive {
@@ -33,7 +103,100 @@ impl CuboidBufferCache {
             entry.keep_alive = false;
         }
         for entity in to_remove {
-            self.entries.remove(&entity);
+            if let Some(entry) = self.entries.remove(&entity) {
+                transform_uniforms.remove(entry.transform_index);
+                if let Some(key) = entry.batch {
+                    batches.remove_member(key, entity);
+                }
+            }
+        }
+    }
+}
+
+/// The instances of either a [`Cuboids`](crate::Cuboids) or an
+/// [`OrientedCuboids`](crate::OrientedCuboids) component.
+pub(crate) enum InstanceBuffer {
+    Aligned(StorageBuffer<Vec<Cuboid>>),
+    Oriented(StorageBuffer<Vec<OrientedCuboid>>),
+}
+
+impl Default for InstanceBuffer {
+    fn default() -> Self {
+        Self::Aligned(default())
+    }
+}
+
+impl InstanceBuffer {
+    /// Switches to aligned instances if necessary, discarding any oriented
+    /// instances.
+    pub fn aligned_mut(&mut self) -> &mut StorageBuffer<Vec<Cuboid>> {
+        if let Self::Oriented(_) = self {
+            *self = Self::Aligned(default());
+        }
+        match self {
+            Self::Aligned(buffer) => buffer,
+            Self::Oriented(_) => unreachable!(),
+        }
+    }
+
+    /// Switches to oriented instances if necessary, discarding any aligned
+    /// instances.
+    pub fn oriented_mut(&mut self) -> &mut StorageBuffer<Vec<OrientedCuboid>> {
+        if let Self::Aligned(_) = self {
+            *self = Self::Oriented(default());
+        }
+        match self {
+            Self::Oriented(buffer) => buffer,
+            Self::Aligned(_) => unreachable!(),
+        }
+    }
+
+    pub fn is_oriented(&self) -> bool {
+        matches!(self, Self::Oriented(_))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Aligned(buffer) => buffer.get().len(),
+            Self::Oriented(buffer) => buffer.get().len(),
+        }
+    }
+
+    pub fn buffer(&self) -> Option<&Buffer> {
+        match self {
+            Self::Aligned(buffer) => buffer.buffer(),
+            Self::Oriented(buffer) => buffer.buffer(),
+        }
+    }
+
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        match self {
+            Self::Aligned(buffer) => buffer.binding(),
+            Self::Oriented(buffer) => buffer.binding(),
+        }
+    }
+
+    pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) {
+        match self {
+            Self::Aligned(buffer) => buffer.write_buffer(device, queue),
+            Self::Oriented(buffer) => buffer.write_buffer(device, queue),
+        }
+    }
+
+    /// Overwrites `range` of the existing GPU buffer with the cached instances.
+    pub fn write_range(&self, queue: &RenderQueue, range: Range<usize>) {
+        fn write<T: Pod + ShaderSize + WriteInto>(
+            queue: &RenderQueue,
+            buffer: &StorageBuffer<Vec<T>>,
+            range: Range<usize>,
+        ) {
+            let offset = (range.start * std::mem::size_of::<T>()) as u64;
+            let instances = &buffer.get()[range];
+            queue.write_buffer(buffer.buffer().unwrap(), offset, cast_slice(instances));
+        }
+        match self {
+            Self::Aligned(buffer) => write(queue, buffer, range),
+            Self::Oriented(buffer) => write(queue, buffer, range),
         }
     }
 }
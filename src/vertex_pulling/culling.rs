@@ -0,0 +1,221 @@
+use super::cuboid_cache::CuboidBufferCache;
+use super::draw::{AuxiliaryMeta, TransformsMeta, ViewMeta};
+use super::index_buffer::CUBE_INDICES;
+use super::pipeline::CuboidsPipelines;
+use super::view_overrides::ExtractedCuboidsViewOverrides;
+
+use bevy::{
+    core::cast_slice,
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        render_graph::{NodeRunError, RenderGraphContext, ViewNode},
+        render_resource::{
+            BindGroup, BindGroupEntries, BindGroupLayout, Buffer, BufferDescriptor, BufferId,
+            BufferUsages, ComputePassDescriptor, PipelineCache,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::ViewUniformOffset,
+    },
+    utils::HashMap,
+};
+
+pub(crate) const CUBOIDS_CULLING_NODE: &str = "cuboids_culling";
+
+const WORKGROUP_SIZE: u32 = 64;
+const MAX_WORKGROUPS_PER_DIMENSION: u32 = 65535;
+
+/// Size of the `wgpu::util::DrawIndexedIndirect` arguments.
+const INDIRECT_ARGS_SIZE: u64 = 5 * std::mem::size_of::<u32>() as u64;
+
+/// GPU culling outputs for every `(view, entity)` pair queued this frame.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsCulling {
+    /// `(view, entity)` pairs queued this frame.
+    pub queued: Vec<(Entity, Entity)>,
+    pub entries: HashMap<(Entity, Entity), CulledCuboids>,
+}
+
+pub(crate) struct CulledCuboids {
+    pub num_instances: u32,
+    pub indirect_args: Buffer,
+    /// The instance buffer that the bind groups were created with.
+    pub instance_buffer_id: BufferId,
+    pub culling_bind_group: BindGroup,
+    pub draw_bind_group: BindGroup,
+}
+
+impl CulledCuboids {
+    fn new(
+        render_device: &RenderDevice,
+        pipeline: &CuboidsPipelines,
+        culling_layout: &BindGroupLayout,
+        instance_buffer: &Buffer,
+        num_instances: u32,
+    ) -> Self {
+        let visible_indices = render_device.create_buffer(&BufferDescriptor {
+            label: Some("cuboids_visible_indices"),
+            size: (num_instances as usize * std::mem::size_of::<u32>()) as u64,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let indirect_args = render_device.create_buffer(&BufferDescriptor {
+            label: Some("cuboids_indirect_args"),
+            size: INDIRECT_ARGS_SIZE,
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let culling_bind_group = render_device.create_bind_group(
+            "cuboids_culling_bind_group",
+            culling_layout,
+            &BindGroupEntries::sequential((
+                instance_buffer.as_entire_binding(),
+                visible_indices.as_entire_binding(),
+                indirect_args.as_entire_binding(),
+            )),
+        );
+        let draw_bind_group = render_device.create_bind_group(
+            "culled_cuboids_instance_buffer_bind_group",
+            &pipeline.culled_cuboids_layout,
+            &BindGroupEntries::sequential((
+                instance_buffer.as_entire_binding(),
+                visible_indices.as_entire_binding(),
+            )),
+        );
+        Self {
+            num_instances,
+            indirect_args,
+            instance_buffer_id: instance_buffer.id(),
+            culling_bind_group,
+            draw_bind_group,
+        }
+    }
+}
+
+pub(crate) fn prepare_cuboids_culling(
+    pipeline: Res<CuboidsPipelines>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut culling: ResMut<CuboidsCulling>,
+) {
+    let Some(culling_pipeline) = &pipeline.culling else {
+        return;
+    };
+
+    let culling = &mut *culling;
+    let mut entries = HashMap::with_capacity(culling.queued.len());
+    for key @ (_, entity) in culling.queued.drain(..) {
+        let Some(entry) = buffer_cache.entries.get(&entity) else {
+            continue;
+        };
+        let instance_buffer = entry.instance_buffer.buffer().unwrap();
+        let num_instances = entry.instance_buffer.len() as u32;
+
+        let culled = match culling.entries.remove(&key) {
+            Some(culled)
+                if culled.num_instances == num_instances
+                    && culled.instance_buffer_id == instance_buffer.id() =>
+            {
+                culled
+            }
+            _ => CulledCuboids::new(
+                &render_device,
+                &pipeline,
+                &culling_pipeline.layout,
+                instance_buffer,
+                num_instances,
+            ),
+        };
+
+        // The culling pass counts surviving instances from zero.
+        // [index_count, instance_count, first_index, base_vertex, first_instance]
+        let args = [CUBE_INDICES.len() as u32, 0, 0, 0, 0];
+        render_queue.write_buffer(&culled.indirect_args, 0, cast_slice(&args));
+
+        entries.insert(key, culled);
+    }
+    // Drop the buffers of any pairs that weren't queued this frame.
+    culling.entries = entries;
+}
+
+/// Runs the culling compute shader for all cuboids queued in a view, before
+/// any of the view's render passes.
+#[derive(Default)]
+pub(crate) struct CuboidsCullingNode;
+
+impl ViewNode for CuboidsCullingNode {
+    type ViewQuery = (
+        Entity,
+        &'static ViewUniformOffset,
+        Option<&'static ExtractedCuboidsViewOverrides>,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (view_entity, view_uniform_offset, maybe_overrides): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let pipelines = world.resource::<CuboidsPipelines>();
+        let Some(culling_pipeline) = &pipelines.culling else {
+            return Ok(());
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let (Some(compute_pipeline), Some(oriented_compute_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(culling_pipeline.pipeline_id),
+            pipeline_cache.get_compute_pipeline(culling_pipeline.oriented_pipeline_id),
+        ) else {
+            return Ok(());
+        };
+        let culling = world.resource::<CuboidsCulling>();
+        let buffer_cache = world.resource::<CuboidBufferCache>();
+        let (Some(view_bind_group), Some(aux_bind_group), Some(transforms_bind_group)) = (
+            &world.resource::<ViewMeta>().cuboids_view_bind_group,
+            &world.resource::<AuxiliaryMeta>().bind_group,
+            &world
+                .resource::<TransformsMeta>()
+                .transform_buffer_bind_group,
+        ) else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("cuboids_culling_pass"),
+                });
+
+        for (&(view, entity), culled) in culling.entries.iter() {
+            if view != view_entity {
+                continue;
+            }
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+
+            pass.set_pipeline(if entry.instance_buffer.is_oriented() {
+                oriented_compute_pipeline
+            } else {
+                compute_pipeline
+            });
+            pass.set_bind_group(0, view_bind_group, &[view_uniform_offset.offset]);
+            pass.set_bind_group(
+                1,
+                aux_bind_group,
+                &ExtractedCuboidsViewOverrides::aux_offsets(maybe_overrides, entry),
+            );
+            pass.set_bind_group(2, transforms_bind_group, &[entry.transform_index]);
+            pass.set_bind_group(3, &culled.culling_bind_group, &[]);
+
+            let num_workgroups = culled.num_instances.div_ceil(WORKGROUP_SIZE);
+            let x = num_workgroups.min(MAX_WORKGROUPS_PER_DIMENSION);
+            let y = num_workgroups.div_ceil(MAX_WORKGROUPS_PER_DIMENSION);
+            pass.dispatch_workgroups(x, y, 1);
+        }
+
+        Ok(())
+    }
+}
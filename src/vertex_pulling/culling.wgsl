@@ -0,0 +1,64 @@
+#import bevy_aabb_instancing::common::{
+    view, transform, Cuboid, Cuboids, cuboid_center, cuboid_half_extents, cuboid_is_discarded,
+    cuboid_rotation
+}
+
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+@group(3) @binding(0)
+var<storage> cuboids: Cuboids;
+
+@group(3) @binding(1)
+var<storage, read_write> visible_indices: array<u32>;
+
+@group(3) @binding(2)
+var<storage, read_write> indirect_args: DrawIndexedIndirect;
+
+fn cuboid_intersects_frustum(cuboid: Cuboid) -> bool {
+    let half_extents = cuboid_half_extents(cuboid);
+    let center = (transform.m * vec4<f32>(cuboid_center(cuboid), 1.0)).xyz;
+    let basis = mat3x3<f32>(transform.m[0].xyz, transform.m[1].xyz, transform.m[2].xyz) *
+        cuboid_rotation(cuboid);
+    let axis_x = basis[0] * half_extents.x;
+    let axis_y = basis[1] * half_extents.y;
+    let axis_z = basis[2] * half_extents.z;
+
+    // Like Bevy's CPU frustum culling, ignore the far plane.
+    for (var i = 0u; i < 5u; i++) {
+        let plane = view.frustum[i];
+        let relative_radius =
+            abs(dot(plane.xyz, axis_x)) +
+            abs(dot(plane.xyz, axis_y)) +
+            abs(dot(plane.xyz, axis_z));
+        if dot(plane.xyz, center) + plane.w + relative_radius < 0.0 {
+            return false;
+        }
+    }
+    return true;
+}
+
+@compute @workgroup_size(64)
+fn cull(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    // Large instance buffers are dispatched over two dimensions.
+    let cuboid_index = global_id.x + global_id.y * num_workgroups.x * 64u;
+    if cuboid_index >= arrayLength(&cuboids.data) {
+        return;
+    }
+
+    let cuboid = cuboids.data[cuboid_index];
+    if cuboid_is_discarded(cuboid) || !cuboid_intersects_frustum(cuboid) {
+        return;
+    }
+
+    let slot = atomicAdd(&indirect_args.instance_count, 1u);
+    visible_indices[slot] = cuboid_index;
+}
@@ -1,4 +1,8 @@
-use super::{cuboid_cache::CuboidBufferCache, index_buffer::CuboidsIndexBuffer};
+use super::{
+    batching::CuboidBatches, cuboid_cache::CuboidBufferCache, culling::CuboidsCulling,
+    index_buffer::CuboidsIndexBuffer, lod::SelectedCuboidLods,
+    view_overrides::ExtractedCuboidsViewOverrides,
+};
 use bevy::{
     ecs::system::{lifetimeless::*, SystemParamItem},
     prelude::*,
@@ -21,6 +25,17 @@ pub(crate) type DrawCuboids = (
     DrawVertexPulledCuboids,
 );
 
+/// Ignores coarser levels of detail, so that GPU picking hits refer to the
+/// full resolution instances.
+pub(crate) type DrawFullResolutionCuboids = (
+    SetItemPipeline,
+    SetCuboidsViewBindGroup<0>,
+    SetAuxBindGroup<1>,
+    SetGpuTransformBufferBindGroup<2>,
+    SetGpuCuboidBuffersBindGroup<3, false>,
+    DrawVertexPulledCuboids<false>,
+);
+
 #[derive(Default, Resource)]
 pub struct ViewMeta {
     pub cuboids_view_bind_group: Option<BindGroup>,
@@ -53,7 +68,8 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetCuboidsViewBindGroup<
     }
 }
 
-/// Holds the bind group for materials and clipping planes.
+/// Holds the bind group for materials, clipping volumes and the clipping groups
+/// of views.
 #[derive(Default, Resource)]
 pub struct AuxiliaryMeta {
     pub bind_group: Option<BindGroup>,
@@ -64,12 +80,12 @@ pub(crate) struct SetAuxBindGroup<const I: usize>;
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetAuxBindGroup<I> {
     type Param = (SRes<CuboidBufferCache>, SRes<AuxiliaryMeta>);
     type ItemWorldQuery = Entity;
-    type ViewWorldQuery = ();
+    type ViewWorldQuery = Option<Read<ExtractedCuboidsViewOverrides>>;
 
     #[inline]
     fn render<'w>(
         _item: &P,
-        _view: (),
+        maybe_overrides: Option<&'w ExtractedCuboidsViewOverrides>,
         entity: Entity,
         (buffer_cache, aux_meta): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
@@ -80,7 +96,7 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetAuxBindGroup<I> {
         pass.set_bind_group(
             I,
             aux_meta.bind_group.as_ref().unwrap(),
-            &[entry.material_index],
+            &ExtractedCuboidsViewOverrides::aux_offsets(maybe_overrides, entry),
         );
         RenderCommandResult::Success
     }
@@ -89,6 +105,8 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetAuxBindGroup<I> {
 #[derive(Default, Resource)]
 pub struct TransformsMeta {
     pub transform_buffer_bind_group: Option<BindGroup>,
+    /// Binds all transforms at once for batched draws.
+    pub batched_bind_group: Option<BindGroup>,
 }
 
 pub(crate) struct SetGpuTransformBufferBindGroup<const I: usize>;
@@ -108,6 +126,11 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetGpuTransformBufferBin
     ) -> RenderCommandResult {
         let transforms_meta = transforms_meta.into_inner();
         let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
+        if entry.batch.is_some() {
+            // Each instance refers to its transform.
+            pass.set_bind_group(I, transforms_meta.batched_bind_group.as_ref().unwrap(), &[]);
+            return RenderCommandResult::Success;
+        }
         pass.set_bind_group(
             I,
             transforms_meta
@@ -120,53 +143,115 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetGpuTransformBufferBin
     }
 }
 
-pub(crate) struct SetGpuCuboidBuffersBindGroup<const I: usize>;
+/// Binds the instances, or those of the selected level of detail if `LOD`.
+pub(crate) struct SetGpuCuboidBuffersBindGroup<const I: usize, const LOD: bool = true>;
 
-impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetGpuCuboidBuffersBindGroup<I> {
-    type Param = SRes<CuboidBufferCache>;
+impl<P: PhaseItem, const I: usize, const LOD: bool> RenderCommand<P>
+    for SetGpuCuboidBuffersBindGroup<I, LOD>
+{
+    type Param = (
+        SRes<CuboidBufferCache>,
+        SRes<CuboidsCulling>,
+        SRes<CuboidBatches>,
+        SRes<SelectedCuboidLods>,
+    );
     type ItemWorldQuery = Entity;
-    type ViewWorldQuery = ();
+    type ViewWorldQuery = Entity;
 
     #[inline]
     fn render<'w>(
         _item: &P,
-        _view: (),
+        view: Entity,
         entity: Entity,
-        buffer_cache: SystemParamItem<'w, '_, Self::Param>,
+        (buffer_cache, culling, batches, selected_lods): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
+        if let Some(culled) = culling.into_inner().entries.get(&(view, entity)) {
+            pass.set_bind_group(I, &culled.draw_bind_group, &[]);
+            return RenderCommandResult::Success;
+        }
         let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
-        pass.set_bind_group(I, entry.instance_buffer_bind_group.as_ref().unwrap(), &[]);
+        if let Some(level) = LOD
+            .then(|| selected_lods.into_inner().get(view, entity, entry))
+            .flatten()
+        {
+            let Some(bind_group) = level.bind_group.as_ref() else {
+                return RenderCommandResult::Failure;
+            };
+            pass.set_bind_group(I, bind_group, &[]);
+        } else if let Some(key) = entry.batch {
+            let Some(bind_group) = batches
+                .into_inner()
+                .batches
+                .get(&key)
+                .and_then(|batch| batch.bind_group.as_ref())
+            else {
+                return RenderCommandResult::Failure;
+            };
+            pass.set_bind_group(I, bind_group, &[]);
+        } else {
+            pass.set_bind_group(I, entry.instance_buffer_bind_group.as_ref().unwrap(), &[]);
+        }
         RenderCommandResult::Success
     }
 }
 
-pub(crate) struct DrawVertexPulledCuboids;
+/// Draws the instances, or those of the selected level of detail if `LOD`.
+pub(crate) struct DrawVertexPulledCuboids<const LOD: bool = true>;
 
-impl<P: PhaseItem> RenderCommand<P> for DrawVertexPulledCuboids {
+impl<P: PhaseItem, const LOD: bool> RenderCommand<P> for DrawVertexPulledCuboids<LOD> {
     type Param = (
         SRes<CuboidBufferCache>,
+        SRes<CuboidsCulling>,
+        SRes<CuboidBatches>,
+        SRes<SelectedCuboidLods>,
         SRes<RenderAssets<CuboidsIndexBuffer>>,
     );
     type ItemWorldQuery = Entity;
-    type ViewWorldQuery = ();
+    type ViewWorldQuery = Entity;
 
     #[inline]
     fn render<'w>(
         _item: &P,
-        _view: (),
+        view: Entity,
         entity: Entity,
-        (buffer_cache, index_buffers): SystemParamItem<'w, '_, Self::Param>,
+        (buffer_cache, culling, batches, selected_lods, index_buffers): SystemParamItem<
+            'w,
+            '_,
+            Self::Param,
+        >,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         use super::index_buffer::{CUBE_INDICES, CUBE_INDICES_HANDLE};
-        let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
-        let num_cuboids = entry.instance_buffer.get().len().try_into().unwrap();
         let index_buffer = index_buffers
             .into_inner()
             .get(&CUBE_INDICES_HANDLE)
             .unwrap();
         pass.set_index_buffer(index_buffer.slice(..), 0, IndexFormat::Uint32);
+
+        // With GPU culling, the number of instances is only known on the GPU.
+        if let Some(culled) = culling.into_inner().entries.get(&(view, entity)) {
+            pass.draw_indexed_indirect(&culled.indirect_args, 0);
+            return RenderCommandResult::Success;
+        }
+
+        // Batches draw each run of adjacent visible members at once.
+        if let Some(runs) = batches.into_inner().draws.get(&(view, entity)) {
+            for run in runs {
+                pass.draw_indexed(0..(CUBE_INDICES.len() as u32), 0, run.clone());
+            }
+            return RenderCommandResult::Success;
+        }
+
+        let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
+        let num_cuboids = match LOD
+            .then(|| selected_lods.into_inner().get(view, entity, entry))
+            .flatten()
+        {
+            Some(level) => level.instance_buffer.get().len(),
+            None => entry.instance_buffer.len(),
+        };
+        let num_cuboids = num_cuboids.try_into().unwrap();
         pass.draw_indexed(0..(CUBE_INDICES.len() as u32), 0, 0..num_cuboids);
         RenderCommandResult::Success
     }
@@ -1,13 +1,33 @@
+use super::batching::{CuboidBatchKey, CuboidBatches};
 use super::buffers::*;
-use super::cuboid_cache::CuboidBufferCache;
+use super::cuboid_cache::{
+    CachedCuboidAttributes, CachedCuboidBuffers, CachedCuboidMaterial, CuboidBufferCache,
+    InstanceBuffer,
+};
+use super::lod::SelectedCuboidLods;
+use super::pipeline::CuboidsShaderDefs;
+use super::view_overrides::ExtractedCuboidMaterials;
 use crate::clipping_planes::*;
 use crate::cuboids::*;
-use crate::CuboidMaterialId;
-use crate::CuboidMaterialMap;
+use crate::CuboidAttributes;
+use crate::CuboidLighting;
+use crate::CuboidMaterial;
+use crate::CuboidPalettes;
+use crate::CuboidsLod;
+use crate::OrientedCuboids;
 
-use bevy::{prelude::*, render::Extract};
+use bevy::{
+    core::Pod,
+    prelude::*,
+    render::{
+        render_resource::{encase::internal::WriteInto, ShaderSize, StorageBuffer},
+        Extract,
+    },
+    utils::HashSet,
+};
+use std::ops::Range;
 
-#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub(crate) fn extract_cuboids(
     mut prev_extracted_entities_size: Local<usize>,
     mut commands: Commands,
@@ -15,92 +35,464 @@ pub(crate) fn extract_cuboids(
         Query<(
             Entity,
             &Cuboids,
-            &GlobalTransform,
-            &CuboidMaterialId,
+            Ref<GlobalTransform>,
+            &Handle<CuboidMaterial>,
             Option<&ViewVisibility>,
+            Option<&ClippingGroups>,
+            Option<Ref<CuboidsLod>>,
+            Option<Ref<CuboidAttributes>>,
             Or<(Added<Cuboids>, Changed<Cuboids>)>,
         )>,
     >,
-    materials: Extract<Res<CuboidMaterialMap>>,
-    mut materials_uniforms: ResMut<DynamicUniformBufferOfCuboidMaterial>,
+    oriented_cuboids: Extract<
+        Query<(
+            Entity,
+            &OrientedCuboids,
+            Ref<GlobalTransform>,
+            &Handle<CuboidMaterial>,
+            Option<&ViewVisibility>,
+            Option<&ClippingGroups>,
+            Option<Ref<CuboidAttributes>>,
+            Or<(Added<OrientedCuboids>, Changed<OrientedCuboids>)>,
+        )>,
+    >,
+    extracted_materials: Res<ExtractedCuboidMaterials>,
+    shader_defs: Res<CuboidsShaderDefs>,
     mut cuboid_buffers: ResMut<CuboidBufferCache>,
-    mut transform_uniforms: ResMut<DynamicUniformBufferOfCuboidTransforms>,
+    mut batches: ResMut<CuboidBatches>,
+    mut selected_lods: ResMut<SelectedCuboidLods>,
+    mut transform_uniforms: ResMut<PersistentUniformBufferOfCuboidTransforms>,
 ) {
-    transform_uniforms.clear();
-
-    if materials.is_empty() {
-        warn!("Cannot draw Cuboids with empty CuboidMaterialMap");
-        return;
-    }
-
-    // First extract material so we can assign dynamic uniform indices to
-    // cuboids.
-    let materials_indices = materials.write_uniforms(&mut materials_uniforms);
+    // Transparent entities are drawn on their own, since they're sorted by
+    // distance.
+    let batch_key = |material: &Handle<CuboidMaterial>, oriented| {
+        let cached = extracted_materials.0.get(&material.id())?;
+        (shader_defs.batching && !cached.transparent).then_some(CuboidBatchKey {
+            material: material.id(),
+            oriented,
+        })
+    };
 
     let mut extracted_entities = Vec::with_capacity(*prev_extracted_entities_size);
     for (
         entity,
         cuboids,
         transform,
-        materials_id,
+        material,
         maybe_visibility,
+        maybe_clipping_groups,
+        maybe_lod,
+        maybe_attributes,
         instance_buffer_needs_update,
     ) in cuboids.iter()
     {
         // Filter all entities that don't have any instances. If an entity went
         // from non-empty to empty, then it will get culled from the buffer
         // cache.
-        if cuboids.instances.is_empty() {
+        if cuboids.instances().is_empty() {
             continue;
         }
 
         extracted_entities.push((entity, ()));
 
-        let transform = CuboidsTransform::from_matrix(transform.compute_matrix());
+        let is_new = !cuboid_buffers.entries.contains_key(&entity);
+        let entry = cuboid_buffers.entries.entry(entity).or_default();
+        match maybe_lod {
+            Some(lod) => {
+                let cached = entry.lod.get_or_insert_with(default);
+                cached.update(&lod, lod.is_changed() || is_new, &transform);
+            }
+            None => entry.lod = None,
+        }
+        let num_attributes =
+            update_entry_attributes(entry, entity, maybe_attributes, cuboids.instances().len());
+        update_entry(
+            entry,
+            is_new,
+            entity,
+            extracted_materials.0.get(&material.id()).copied(),
+            maybe_visibility,
+            transform,
+            maybe_clipping_groups,
+            num_attributes,
+            &mut transform_uniforms,
+        );
+        // Levels of detail are selected per entity, and batches don't bind
+        // attributes, so neither can be batched.
+        let batch_key = batch_key(material, false)
+            .filter(|_| entry.lod.is_none() && entry.attributes.is_none());
+        update_entry_instances(
+            entry,
+            entity,
+            batch_key,
+            cuboids.instances(),
+            cuboids.dirty_ranges(),
+            instance_buffer_needs_update,
+            InstanceBuffer::aligned_mut,
+            &mut batches,
+        );
+    }
+    for (
+        entity,
+        cuboids,
+        transform,
+        material,
+        maybe_visibility,
+        maybe_clipping_groups,
+        maybe_attributes,
+        instance_buffer_needs_update,
+    ) in oriented_cuboids.iter()
+    {
+        if cuboids.instances().is_empty() {
+            continue;
+        }
 
-        let is_visible = maybe_visibility.map(|vis| vis.get()).unwrap_or(true);
+        extracted_entities.push((entity, ()));
 
+        let is_new = !cuboid_buffers.entries.contains_key(&entity);
         let entry = cuboid_buffers.entries.entry(entity).or_default();
-        if instance_buffer_needs_update {
-            entry.instance_buffer.set(cuboids.instances.clone());
-        }
-        entry.material_index = materials_indices[materials_id.0].0;
-        entry.dirty = instance_buffer_needs_update;
-        entry.enabled = is_visible;
-        entry.keep_alive = true;
-        entry.position = transform.position();
-        entry.transform_index = transform_uniforms.push(transform);
+        let num_attributes =
+            update_entry_attributes(entry, entity, maybe_attributes, cuboids.instances().len());
+        update_entry(
+            entry,
+            is_new,
+            entity,
+            extracted_materials.0.get(&material.id()).copied(),
+            maybe_visibility,
+            transform,
+            maybe_clipping_groups,
+            num_attributes,
+            &mut transform_uniforms,
+        );
+        update_entry_instances(
+            entry,
+            entity,
+            batch_key(material, true).filter(|_| entry.attributes.is_none()),
+            cuboids.instances(),
+            cuboids.dirty_ranges(),
+            instance_buffer_needs_update,
+            InstanceBuffer::oriented_mut,
+            &mut batches,
+        );
     }
 
     *prev_extracted_entities_size = extracted_entities.len();
     commands.insert_or_spawn_batch(extracted_entities);
 
-    cuboid_buffers.cull_entities();
+    cuboid_buffers.cull_entities(&mut transform_uniforms, &mut batches);
+
+    // Batches and levels of detail are queued again for every view.
+    batches.draws.clear();
+    selected_lods.0.clear();
+}
+
+/// Copies the modified instances of an entity into its batch, or into its own
+/// cache entry if it isn't batched.
+#[allow(clippy::too_many_arguments)]
+fn update_entry_instances<T: Pod + ShaderSize + WriteInto>(
+    entry: &mut CachedCuboidBuffers,
+    entity: Entity,
+    batch_key: Option<CuboidBatchKey>,
+    instances: &[T],
+    dirty_ranges: &[Range<usize>],
+    mut changed: bool,
+    cached_instances: fn(&mut InstanceBuffer) -> &mut StorageBuffer<Vec<T>>,
+    batches: &mut CuboidBatches,
+) {
+    let batch_key = batch_key.filter(|&key| batches.has_room::<T>(key, entity, instances.len()));
+
+    // Entities moving in or out of a batch, e.g. because their material
+    // changed, upload all of their instances to the new location.
+    if entry.batch != batch_key {
+        if let Some(old_key) = entry.batch {
+            batches.remove_member(old_key, entity);
+        }
+        entry.batch = batch_key;
+        entry.instance_buffer = InstanceBuffer::default();
+        entry.instance_buffer_bind_group = None;
+        entry.dirty_ranges.clear();
+        changed = true;
+    }
+
+    entry.dirty = false;
+    match batch_key {
+        Some(key) => {
+            let transform_slot = entry.transform_index as usize
+                / PersistentUniformBuffer::<CuboidsTransform>::stride();
+            batches.update_member(
+                key,
+                entity,
+                instances,
+                dirty_ranges,
+                changed,
+                transform_slot as u32,
+            );
+        }
+        None if changed => {
+            entry.dirty = update_instances(
+                cached_instances(&mut entry.instance_buffer),
+                instances,
+                dirty_ranges,
+                &mut entry.dirty_ranges,
+            );
+        }
+        None => {}
+    }
+}
+
+/// Copies modified instances into the cache, returning `true` if the whole
+/// buffer needs to be re-uploaded.
+fn update_instances<T: Copy + ShaderSize + WriteInto>(
+    cached: &mut StorageBuffer<Vec<T>>,
+    instances: &[T],
+    dirty_ranges: &[Range<usize>],
+    cached_dirty_ranges: &mut Vec<Range<usize>>,
+) -> bool {
+    let cached_instances = cached.get_mut();
+    if dirty_ranges.is_empty() || cached_instances.len() != instances.len() {
+        cached.set(instances.to_vec());
+        cached_dirty_ranges.clear();
+        return true;
+    }
+    // Only copy the modified instances into the cache.
+    for range in dirty_ranges {
+        cached_instances[range.clone()].copy_from_slice(&instances[range.clone()]);
+        cached_dirty_ranges.push(range.clone());
+    }
+    false
+}
+
+/// Copies the attributes of an entity into its cache entry when they change,
+/// returning the number of attributes per instance, or 0 if it has none.
+fn update_entry_attributes(
+    entry: &mut CachedCuboidBuffers,
+    entity: Entity,
+    maybe_attributes: Option<Ref<CuboidAttributes>>,
+    num_instances: usize,
+) -> u32 {
+    let maybe_attributes = maybe_attributes.filter(|attributes| {
+        let fits = attributes.fits(num_instances);
+        if !fits && attributes.is_changed() {
+            warn!(
+                "Ignoring the CuboidAttributes of {entity:?}, which don't hold {} attributes for \
+                each of its {num_instances} instances",
+                attributes.num_attributes()
+            );
+        }
+        fits
+    });
+    let Some(attributes) = maybe_attributes else {
+        // Rebind the instances without attributes.
+        if entry.attributes.take().is_some() {
+            entry.instance_buffer_bind_group = None;
+        }
+        return 0;
+    };
+    if entry.attributes.is_none() || attributes.is_changed() {
+        let cached = entry
+            .attributes
+            .get_or_insert_with(CachedCuboidAttributes::default);
+        cached.buffer.set(attributes.values().to_vec());
+        cached.dirty = true;
+        entry.instance_buffer_bind_group = None;
+    }
+    attributes.num_attributes()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_entry(
+    entry: &mut CachedCuboidBuffers,
+    is_new: bool,
+    entity: Entity,
+    material: Option<CachedCuboidMaterial>,
+    maybe_visibility: Option<&ViewVisibility>,
+    transform: Ref<GlobalTransform>,
+    maybe_clipping_groups: Option<&ClippingGroups>,
+    num_attributes: u32,
+    transform_uniforms: &mut PersistentUniformBufferOfCuboidTransforms,
+) {
+    // Entities are hidden while their material doesn't exist.
+    entry.material = material.unwrap_or_default();
+    entry.enabled = material.is_some() && maybe_visibility.map(|vis| vis.get()).unwrap_or(true);
+    entry.keep_alive = true;
+
+    // The transform uniform keeps its slot, and is only recomputed and
+    // uploaded when it changes.
+    let clipping_groups = maybe_clipping_groups.copied().unwrap_or_default().bits();
+    if !is_new
+        && !transform.is_changed()
+        && clipping_groups == entry.clipping_groups
+        && num_attributes == entry.num_attributes
+    {
+        return;
+    }
+    let gpu_transform = CuboidsTransform::from_matrix(
+        transform.compute_matrix(),
+        entity,
+        clipping_groups,
+        num_attributes,
+    );
+    entry.position = gpu_transform.position();
+    entry.clipping_groups = clipping_groups;
+    entry.num_attributes = num_attributes;
+    if is_new {
+        entry.transform_index = transform_uniforms.push(&gpu_transform);
+    } else {
+        transform_uniforms.set(entry.transform_index, &gpu_transform);
+    }
+}
+
+/// Uploads new and modified materials into their uniform slots.
+pub(crate) fn extract_materials(
+    mut events: Extract<EventReader<AssetEvent<CuboidMaterial>>>,
+    materials: Extract<Res<Assets<CuboidMaterial>>>,
+    mut materials_uniforms: ResMut<PersistentUniformBufferOfCuboidMaterial>,
+    mut extracted_materials: ResMut<ExtractedCuboidMaterials>,
+) {
+    let changed_ids: HashSet<_> = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } | AssetEvent::Removed { id } => {
+                Some(*id)
+            }
+            _ => None,
+        })
+        .collect();
+
+    for id in changed_ids {
+        let existing = extracted_materials.0.get(&id).map(|m| m.uniform_index);
+        match (materials.get(id), existing) {
+            (Some(material), Some(uniform_index)) => {
+                materials_uniforms.set(uniform_index, material);
+                extracted_materials
+                    .0
+                    .insert(id, CachedCuboidMaterial::new(uniform_index, material));
+            }
+            (Some(material), None) => {
+                let uniform_index = materials_uniforms.push(material);
+                extracted_materials
+                    .0
+                    .insert(id, CachedCuboidMaterial::new(uniform_index, material));
+            }
+            (None, Some(uniform_index)) => {
+                materials_uniforms.remove(uniform_index);
+                extracted_materials.0.remove(&id);
+            }
+            (None, None) => {}
+        }
+    }
 }
 
 pub(crate) fn extract_clipping_planes(
-    clipping_planes: Extract<Query<(&ClippingPlaneRange, &GlobalTransform)>>,
+    clipping_planes: Extract<
+        Query<(
+            &ClippingPlaneRange,
+            &GlobalTransform,
+            Option<&ClippingGroups>,
+        )>,
+    >,
+    clipping_boxes: Extract<Query<(&ClippingBox, &GlobalTransform, Option<&ClippingGroups>)>>,
+    clipping_spheres: Extract<Query<(&ClippingSphere, &GlobalTransform, Option<&ClippingGroups>)>>,
+    shader_defs: Res<CuboidsShaderDefs>,
     mut clipping_plane_uniform: ResMut<UniformBufferOfGpuClippingPlaneRanges>,
+    mut clipping_plane_storage: ResMut<StorageBufferOfGpuClippingPlaneRanges>,
 ) {
-    let mut iter = clipping_planes.iter();
+    let volumes = ClippingVolumes::new(&*clipping_planes, &*clipping_boxes, &*clipping_spheres);
+
+    let planes_in_storage = shader_defs.clipping_planes_storage;
     let mut gpu_planes = GpuClippingPlaneRanges::default();
-    for (range, transform) in iter.by_ref() {
-        let (_, rotation, translation) = transform.to_scale_rotation_translation();
-        gpu_planes.ranges[gpu_planes.num_ranges as usize] = GpuClippingPlaneRange {
-            origin: translation,
-            unit_normal: rotation * Vec3::X,
-            min_sdist: range.min_sdist,
-            max_sdist: range.max_sdist,
-        };
-        gpu_planes.num_ranges += 1;
-        if gpu_planes.num_ranges as usize == MAX_CLIPPING_PLANES {
-            break;
-        }
-    }
-    if iter.next().is_some() {
+    if !gpu_planes.pack(&volumes, planes_in_storage) {
         warn!(
-            "Too many GpuClippingPlaneRanges entities, at most {MAX_CLIPPING_PLANES} are supported"
+            "Too many clipping entities, at most {MAX_CLIPPING_PLANES} ClippingPlaneRanges, \
+            {MAX_CLIPPING_BOXES} ClippingBoxes and {MAX_CLIPPING_SPHERES} ClippingSpheres are \
+            supported"
         );
     }
     clipping_plane_uniform.set(gpu_planes);
+
+    if planes_in_storage {
+        let mut planes = volumes.planes;
+        // Empty buffers can't be bound.
+        if planes.is_empty() {
+            planes.push(GpuClippingPlaneRange::default());
+        }
+        clipping_plane_storage.set(planes);
+    }
+}
+
+pub(crate) fn extract_palettes(
+    palettes: Extract<Res<CuboidPalettes>>,
+    mut palettes_buffer: ResMut<StorageBufferOfCuboidPalettes>,
+) {
+    if palettes.is_changed() {
+        palettes_buffer.set(palettes.gpu_data());
+    }
+}
+
+pub(crate) fn extract_lighting(
+    lighting: Extract<Res<CuboidLighting>>,
+    mut lighting_uniform: ResMut<UniformBufferOfGpuCuboidLighting>,
+) {
+    if lighting.is_changed() {
+        lighting_uniform.set(lighting.as_ref().into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cuboid(color: u32) -> Cuboid {
+        Cuboid::new(Vec3::ZERO, Vec3::ONE, color)
+    }
+
+    fn colors(cached: &StorageBuffer<Vec<Cuboid>>) -> Vec<u32> {
+        cached.get().iter().map(|cuboid| cuboid.color).collect()
+    }
+
+    #[test]
+    fn update_instances_copies_dirty_ranges() {
+        let mut cached = StorageBuffer::from(vec![cuboid(0); 4]);
+        let instances: Vec<_> = (0..4).map(cuboid).collect();
+        let mut cached_dirty_ranges = Vec::new();
+
+        let full = update_instances(
+            &mut cached,
+            &instances,
+            &[1..2, 3..4],
+            &mut cached_dirty_ranges,
+        );
+        assert!(!full);
+        assert_eq!(colors(&cached), [0, 1, 0, 3]);
+        assert_eq!(cached_dirty_ranges, [1..2, 3..4]);
+    }
+
+    #[test]
+    fn update_instances_copies_everything_without_ranges() {
+        let mut cached = StorageBuffer::from(vec![cuboid(0); 4]);
+        let instances: Vec<_> = (0..4).map(cuboid).collect();
+        let mut cached_dirty_ranges = vec![0..1, 2..3];
+
+        let full = update_instances(&mut cached, &instances, &[], &mut cached_dirty_ranges);
+        assert!(full);
+        assert_eq!(colors(&cached), [0, 1, 2, 3]);
+        assert!(cached_dirty_ranges.is_empty());
+    }
+
+    #[test]
+    fn update_instances_copies_everything_when_the_length_changes() {
+        let mut cached = StorageBuffer::from(vec![cuboid(0); 4]);
+        let instances: Vec<_> = (0..5).map(cuboid).collect();
+        let mut cached_dirty_ranges = Vec::new();
+
+        let full = update_instances(
+            &mut cached,
+            &instances,
+            &[0..1, 2..3],
+            &mut cached_dirty_ranges,
+        );
+        assert!(full);
+        assert_eq!(colors(&cached), [0, 1, 2, 3, 4]);
+        assert!(cached_dirty_ranges.is_empty());
+    }
 }
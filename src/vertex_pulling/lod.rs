@@ -0,0 +1,113 @@
+use super::cuboid_cache::{CachedCuboidBuffers, CuboidBufferCache};
+use super::pipeline::CuboidsPipelines;
+use crate::{Cuboid, CuboidsLod, LodSelection};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{BindGroup, BindGroupEntries, StorageBuffer},
+        renderer::{RenderDevice, RenderQueue},
+        view::ExtractedView,
+    },
+    utils::HashMap,
+};
+
+/// The coarser levels of a [`CuboidsLod`].
+#[derive(Default)]
+pub(crate) struct CachedCuboidsLod {
+    pub levels: Vec<CachedLodLevel>,
+    selection: LodSelection,
+    /// Largest dimension of the full resolution blocks, in world space.
+    cell_size: f32,
+    /// World space bounding sphere of the visible instances.
+    center: Vec3,
+    radius: f32,
+}
+
+pub(crate) struct CachedLodLevel {
+    pub instance_buffer: StorageBuffer<Vec<Cuboid>>,
+    /// Created when the level is uploaded.
+    pub bind_group: Option<BindGroup>,
+}
+
+impl CachedCuboidsLod {
+    /// Copies the levels if they changed, and updates the bounds to the
+    /// entity's current transform.
+    pub fn update(&mut self, lod: &CuboidsLod, levels_changed: bool, transform: &GlobalTransform) {
+        if levels_changed {
+            self.levels = lod
+                .levels()
+                .iter()
+                .map(|instances| CachedLodLevel {
+                    instance_buffer: StorageBuffer::from(instances.clone()),
+                    bind_group: None,
+                })
+                .collect();
+            self.selection = lod.selection.clone();
+        }
+        let (min, max) = lod.aabb();
+        let (scale, _, _) = transform.to_scale_rotation_translation();
+        let max_scale = scale.abs().max_element();
+        self.cell_size = lod.cell_size.max_element() * max_scale;
+        self.center = transform.transform_point((min + max) / 2.0);
+        self.radius = ((max - min) / 2.0).length() * max_scale;
+    }
+
+    /// Returns the level to draw in `view`, where 0 is full resolution.
+    pub fn level(&self, view: &ExtractedView) -> usize {
+        let camera = view.transform.translation();
+        let distance = (camera.distance(self.center) - self.radius).max(0.0);
+        // Half the viewport height covers a unit at a distance of one, or
+        // everywhere in orthographic views.
+        let pixels_per_unit = 0.5 * view.viewport.w as f32 * view.projection.y_axis.y;
+        let pixels_per_unit = if view.projection.w_axis.w == 1.0 {
+            pixels_per_unit
+        } else {
+            pixels_per_unit / distance
+        };
+        self.selection
+            .level(distance, pixels_per_unit, self.cell_size)
+            .min(self.levels.len())
+    }
+}
+
+/// The level of detail drawn for each entity that isn't drawn at full
+/// resolution, by view and entity.
+#[derive(Default, Resource)]
+pub(crate) struct SelectedCuboidLods(pub HashMap<(Entity, Entity), usize>);
+
+impl SelectedCuboidLods {
+    /// Returns the coarser level that `entry` is drawn with in `view`, if any.
+    pub fn get<'a>(
+        &self,
+        view: Entity,
+        entity: Entity,
+        entry: &'a CachedCuboidBuffers,
+    ) -> Option<&'a CachedLodLevel> {
+        let level = *self.0.get(&(view, entity))?;
+        entry.lod.as_ref()?.levels.get(level - 1)
+    }
+}
+
+pub(crate) fn prepare_cuboid_lods(
+    pipeline: Res<CuboidsPipelines>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut cuboid_buffers: ResMut<CuboidBufferCache>,
+) {
+    let levels = cuboid_buffers
+        .entries
+        .values_mut()
+        .filter_map(|entry| entry.lod.as_mut())
+        .flat_map(|lod| lod.levels.iter_mut());
+    for level in levels.filter(|level| level.bind_group.is_none()) {
+        level
+            .instance_buffer
+            .write_buffer(&render_device, &render_queue);
+        level.bind_group = Some(render_device.create_bind_group(
+            "cuboids_lod_instance_buffer_bind_group",
+            &pipeline.cuboids_layout,
+            &BindGroupEntries::single(level.instance_buffer.binding().unwrap()),
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+use super::batching::{CuboidBatches, ViewBatches};
+use super::cuboid_cache::{CachedCuboidBuffers, CuboidBufferCache};
+use super::draw::DrawFullResolutionCuboids;
+use super::pipeline::{CuboidsPipelineKey, CuboidsPipelines};
+use super::view_overrides::ExtractedCuboidsViewOverrides;
+use crate::picking::{CuboidsPicked, CuboidsPickingCamera, GpuCuboidHit, GpuPickingResults};
+
+use bevy::{
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::{NodeRunError, RenderGraphContext, ViewNode},
+        render_phase::{
+            CachedRenderPipelinePhaseItem, DrawFunctionId, DrawFunctions, PhaseItem, RenderPhase,
+        },
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, CachedRenderPipelineId, Extent3d,
+            ImageCopyBuffer, ImageCopyTexture, ImageDataLayout, LoadOp, MapMode, Operations,
+            Origin3d, PipelineCache, RenderPassColorAttachment, RenderPassDepthStencilAttachment,
+            RenderPassDescriptor, SpecializedRenderPipelines, TextureAspect, TextureDescriptor,
+            TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::{RenderContext, RenderDevice},
+        texture::{CachedTexture, TextureCache},
+        view::{ExtractedView, VisibleEntities},
+        Extract,
+    },
+    utils::nonmax::NonMaxU32,
+};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const CUBOIDS_PICKING_NODE: &str = "cuboids_picking";
+
+/// Format of the ID buffer, holding `[entity_low, entity_high, instance, hit]`.
+pub(crate) const PICKING_ID_FORMAT: TextureFormat = TextureFormat::Rgba32Uint;
+const PICKING_ID_SIZE: u64 = 4 * std::mem::size_of::<u32>() as u64;
+
+/// Draws cuboids into the ID buffer of a [`CuboidsPickingCamera`].
+pub(crate) struct CuboidsPickingItem {
+    pub entity: Entity,
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+    pub batch_range: Range<u32>,
+    pub dynamic_offset: Option<NonMaxU32>,
+}
+
+impl PhaseItem for CuboidsPickingItem {
+    // Only the front-most instance matters, so there's no need to sort.
+    type SortKey = ();
+
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {}
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+
+    #[inline]
+    fn batch_range(&self) -> &Range<u32> {
+        &self.batch_range
+    }
+
+    #[inline]
+    fn batch_range_mut(&mut self) -> &mut Range<u32> {
+        &mut self.batch_range
+    }
+
+    #[inline]
+    fn dynamic_offset(&self) -> Option<NonMaxU32> {
+        self.dynamic_offset
+    }
+
+    #[inline]
+    fn dynamic_offset_mut(&mut self) -> &mut Option<NonMaxU32> {
+        &mut self.dynamic_offset
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for CuboidsPickingItem {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+#[derive(Component)]
+pub(crate) struct ExtractedCuboidsPicking {
+    pub cursor: Vec2,
+    /// The pixel under `cursor`.
+    pub pixel: UVec2,
+}
+
+#[derive(Component)]
+pub(crate) struct ViewCuboidsPickingTextures {
+    pub ids: CachedTexture,
+    pub depth: CachedTexture,
+    /// Receives the single picked ID.
+    pub readback: Buffer,
+}
+
+/// Readback buffers that haven't been mapped and read yet.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsPickingReadbacks {
+    pending: Vec<PendingReadback>,
+}
+
+struct PendingReadback {
+    camera: Entity,
+    cursor: Vec2,
+    buffer: Buffer,
+    /// `None` until the buffer has been submitted and mapping was requested.
+    map_result: Option<Arc<Mutex<Option<bool>>>>,
+}
+
+pub(crate) fn extract_cuboids_picking(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera, &CuboidsPickingCamera)>>,
+) {
+    for (entity, camera, picking) in cameras.iter() {
+        if !camera.is_active {
+            continue;
+        }
+        let (Some(cursor), Some(target_size)) = (picking.cursor, camera.physical_target_size())
+        else {
+            continue;
+        };
+        if cursor.cmplt(Vec2::ZERO).any() || cursor.cmpge(target_size.as_vec2()).any() {
+            continue;
+        }
+        commands.get_or_spawn(entity).insert((
+            ExtractedCuboidsPicking {
+                cursor,
+                pixel: cursor.as_uvec2(),
+            },
+            RenderPhase::<CuboidsPickingItem>::default(),
+        ));
+    }
+}
+
+pub(crate) fn prepare_cuboids_picking(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    mut texture_cache: ResMut<TextureCache>,
+    mut readbacks: ResMut<CuboidsPickingReadbacks>,
+    views: Query<(Entity, &ExtractedCamera, &ExtractedCuboidsPicking)>,
+) {
+    for (entity, camera, picking) in &views {
+        let Some(target_size) = camera.physical_target_size else {
+            continue;
+        };
+        let size = Extent3d {
+            width: target_size.x,
+            height: target_size.y,
+            depth_or_array_layers: 1,
+        };
+        let mut descriptor = TextureDescriptor {
+            label: Some("cuboids_picking_ids"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: PICKING_ID_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        };
+        let ids = texture_cache.get(&render_device, descriptor.clone());
+        descriptor.label = Some("cuboids_picking_depth");
+        descriptor.format = TextureFormat::Depth32Float;
+        descriptor.usage = TextureUsages::RENDER_ATTACHMENT;
+        let depth = texture_cache.get(&render_device, descriptor);
+
+        let readback = render_device.create_buffer(&BufferDescriptor {
+            label: Some("cuboids_picking_readback"),
+            size: PICKING_ID_SIZE,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        readbacks.pending.push(PendingReadback {
+            camera: entity,
+            cursor: picking.cursor,
+            buffer: readback.clone(),
+            map_result: None,
+        });
+
+        commands.entity(entity).insert(ViewCuboidsPickingTextures {
+            ids,
+            depth,
+            readback,
+        });
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub(crate) fn queue_cuboids_picking(
+    cuboids_pipelines: Res<CuboidsPipelines>,
+    pipeline_cache: Res<PipelineCache>,
+    mut specialized_pipelines: ResMut<SpecializedRenderPipelines<CuboidsPipelines>>,
+    picking_draw_functions: Res<DrawFunctions<CuboidsPickingItem>>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut batches: ResMut<CuboidBatches>,
+    mut views: Query<(
+        Entity,
+        &ExtractedView,
+        &VisibleEntities,
+        Option<&ExtractedCuboidsViewOverrides>,
+        &mut RenderPhase<CuboidsPickingItem>,
+    )>,
+) {
+    let draw_cuboids = picking_draw_functions
+        .read()
+        .get_id::<DrawFullResolutionCuboids>()
+        .unwrap();
+
+    // The main pass already queued these views for culling, except for the
+    // entities drawn with a coarser level of detail or with attributes.
+    let gpu_culling = cuboids_pipelines.culling.is_some();
+
+    for (view_entity, view, visible_entities, maybe_overrides, mut picking_phase) in
+        views.iter_mut()
+    {
+        let mut queue_entity = |entity: Entity,
+                                entry: &CachedCuboidBuffers,
+                                batched: bool,
+                                gpu_culling: bool,
+                                attributes: bool| {
+            let material = ExtractedCuboidsViewOverrides::material(maybe_overrides, entry);
+            let pipeline = specialized_pipelines.specialize(
+                &pipeline_cache,
+                &cuboids_pipelines,
+                CuboidsPipelineKey {
+                    hdr: false,
+                    gpu_culling,
+                    batched,
+                    oriented: entry.is_oriented(),
+                    attributes,
+                    transparent: false,
+                    shadow: false,
+                    depth_clamp_ortho: false,
+                    prepass: false,
+                    normal_prepass: false,
+                    motion_vector_prepass: false,
+                    picking: true,
+                    precise_clipping: material.precise_clipping,
+                },
+            );
+            picking_phase.add(CuboidsPickingItem {
+                entity,
+                pipeline,
+                draw_function: draw_cuboids,
+                batch_range: 0..1,
+                dynamic_offset: None,
+            });
+        };
+
+        // Batches are drawn with the same runs as in the main pass.
+        let mut view_batches = ViewBatches::default();
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            if !entry.enabled {
+                continue;
+            }
+            if let Some(key) = entry.batch {
+                batches.add_visible(&mut view_batches, key, entity);
+                continue;
+            }
+            let level = entry.lod.as_ref().map_or(0, |lod| lod.level(view));
+            let attributes = entry.attributes.is_some();
+            queue_entity(
+                entity,
+                entry,
+                false,
+                gpu_culling && level == 0 && !attributes,
+                attributes,
+            );
+        }
+        for entity in batches.finish_view(view_entity, view_batches) {
+            queue_entity(entity, &buffer_cache.entries[&entity], true, false, false);
+        }
+    }
+}
+
+/// Renders the pixel under the cursor into the ID buffer and copies it into
+/// the readback buffer.
+#[derive(Default)]
+pub(crate) struct CuboidsPickingNode;
+
+impl ViewNode for CuboidsPickingNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ExtractedCuboidsPicking,
+        &'static ViewCuboidsPickingTextures,
+        &'static RenderPhase<CuboidsPickingItem>,
+    );
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (camera, picking, textures, picking_phase): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        {
+            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("cuboids_picking_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &textures.ids.default_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        // All zeros means nothing was hit.
+                        load: LoadOp::Clear(Default::default()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &textures.depth.default_view,
+                    // NOTE: 0.0 is the far plane due to bevy's use of reverse-z projections.
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(0.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            if let Some(viewport) = camera.viewport.as_ref() {
+                render_pass.set_camera_viewport(viewport);
+            }
+            // Only rasterize the picked pixel.
+            render_pass.set_scissor_rect(picking.pixel.x, picking.pixel.y, 1, 1);
+            picking_phase.render(&mut render_pass, world, graph.view_entity());
+        }
+
+        render_context.command_encoder().copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture: &textures.ids.texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: picking.pixel.x,
+                    y: picking.pixel.y,
+                    z: 0,
+                },
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &textures.readback,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+/// Maps the readback buffers submitted this frame and sends the picks whose
+/// buffers finished mapping back to the main world.
+///
+/// Mapping callbacks run when the next frame's commands are submitted.
+pub(crate) fn map_cuboids_picking_readbacks(
+    results: Res<GpuPickingResults>,
+    mut readbacks: ResMut<CuboidsPickingReadbacks>,
+) {
+    readbacks.pending.retain_mut(|readback| {
+        let Some(map_result) = &readback.map_result else {
+            let map_result = Arc::new(Mutex::new(None));
+            let callback_result = map_result.clone();
+            readback
+                .buffer
+                .slice(..)
+                .map_async(MapMode::Read, move |result| {
+                    *callback_result.lock().unwrap() = Some(result.is_ok());
+                });
+            readback.map_result = Some(map_result);
+            return true;
+        };
+        let Some(mapped) = map_result.lock().unwrap().take() else {
+            return true;
+        };
+        if mapped {
+            let hit = {
+                let data = readback.buffer.slice(..).get_mapped_range();
+                let ids: &[u32] = bevy::core::cast_slice(&data);
+                (ids[3] != 0).then(|| GpuCuboidHit {
+                    entity: Entity::from_bits(ids[0] as u64 | (ids[1] as u64) << 32),
+                    instance_index: ids[2] as usize,
+                })
+            };
+            readback.buffer.unmap();
+            results.0.lock().unwrap().push(CuboidsPicked {
+                camera: readback.camera,
+                cursor: readback.cursor,
+                hit,
+            });
+        }
+        false
+    });
+}
@@ -1,7 +1,12 @@
-use crate::clipping_planes::GpuClippingPlaneRanges;
+use super::picking::PICKING_ID_FORMAT;
+use crate::clipping_planes::{GpuClippingPlaneRanges, GpuViewClippingGroups};
+use crate::lighting::GpuCuboidLighting;
 use crate::{cuboids::CuboidsTransform, CuboidMaterial};
 
-use bevy::render::render_resource::ShaderDefVal;
+use bevy::core_pipeline::prepass::{MOTION_VECTOR_PREPASS_FORMAT, NORMAL_PREPASS_FORMAT};
+use bevy::render::render_resource::{
+    CachedComputePipelineId, ComputePipelineDescriptor, ShaderDefVal, SpecializedRenderPipeline,
+};
 use bevy::render::texture::BevyDefault;
 use bevy::{
     prelude::*,
@@ -9,11 +14,11 @@ use bevy::{
         mesh::PrimitiveTopology,
         render_resource::{
             BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType,
-            BlendState, BufferBindingType, BufferSize, CachedRenderPipelineId, ColorTargetState,
-            ColorWrites, CompareFunction, DepthBiasState, DepthStencilState, FragmentState,
-            FrontFace, MultisampleState, PipelineCache, PolygonMode, PrimitiveState,
-            RenderPipelineDescriptor, ShaderStages, ShaderType, StencilFaceState, StencilState,
-            TextureFormat, VertexState,
+            BlendState, BufferBindingType, BufferSize, ColorTargetState, ColorWrites,
+            CompareFunction, DepthBiasState, DepthStencilState, FragmentState, FrontFace,
+            MultisampleState, PipelineCache, PolygonMode, PrimitiveState, RenderPipelineDescriptor,
+            SamplerBindingType, ShaderStages, ShaderType, StencilFaceState, StencilState,
+            TextureFormat, TextureSampleType, TextureViewDimension, VertexState,
         },
         renderer::RenderDevice,
         view::ViewUniform,
@@ -22,21 +27,57 @@ use bevy::{
 
 #[derive(Resource)]
 pub(crate) struct CuboidsPipelines {
-    pub pipeline_id: CachedRenderPipelineId,
-    pub hdr_pipeline_id: CachedRenderPipelineId,
+    /// Only exists when GPU culling is enabled.
+    pub culling: Option<CullingPipeline>,
 
     pub aux_layout: BindGroupLayout,
     pub cuboids_layout: BindGroupLayout,
+    pub culled_cuboids_layout: BindGroupLayout,
+    pub batched_cuboids_layout: BindGroupLayout,
+    pub attributes_cuboids_layout: BindGroupLayout,
     pub transforms_layout: BindGroupLayout,
+    pub batched_transforms_layout: BindGroupLayout,
     pub view_layout: BindGroupLayout,
+
+    pub sample_count: u32,
+    pub shader_defs: CuboidsShaderDefs,
 }
 
 pub(crate) const VERTEX_PULLING_SHADER_HANDLE: Handle<Shader> =
     Handle::weak_from_u128(17343092250772987267);
+pub(crate) const COMMON_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(3781465020394867103);
+pub(crate) const CULLING_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(9820634135711840257);
+
+fn storage_buffer_entry(
+    binding: u32,
+    visibility: ShaderStages,
+    read_only: bool,
+) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: BufferSize::new(0),
+        },
+        count: None,
+    }
+}
 
 impl FromWorld for CuboidsPipelines {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
+        let shader_defs = world.resource::<CuboidsShaderDefs>().clone();
+
+        // Only request compute visibility when it's needed, since it's not
+        // available on all platforms.
+        let compute_stage = if shader_defs.gpu_culling {
+            ShaderStages::COMPUTE
+        } else {
+            ShaderStages::NONE
+        };
 
         let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("cuboids_view_layout"),
@@ -44,7 +85,7 @@ impl FromWorld for CuboidsPipelines {
                 // View
                 BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT | compute_stage,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: true,
@@ -55,30 +96,82 @@ impl FromWorld for CuboidsPipelines {
             ],
         });
 
-        let aux_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("aux_layout"),
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: true,
-                        min_binding_size: Some(CuboidMaterial::min_size()),
-                    },
-                    count: None,
+        let mut aux_entries = vec![
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT | compute_stage,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(CuboidMaterial::min_size()),
                 },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::VERTEX,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(GpuClippingPlaneRanges::min_size()),
-                    },
-                    count: None,
+                count: None,
+            },
+            // Clipping volumes, also used by precise clipping in the fragment
+            // stage.
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT | compute_stage,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuClippingPlaneRanges::min_size()),
                 },
-            ],
+                count: None,
+            },
+            // Colormap atlas
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            },
+            // Categorical palettes
+            storage_buffer_entry(4, ShaderStages::VERTEX | compute_stage, true),
+            // Lighting, also used for caps in the fragment stage.
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuCuboidLighting::min_size()),
+                },
+                count: None,
+            },
+        ];
+        if shader_defs.clipping_planes_storage {
+            // Clipping planes
+            aux_entries.push(storage_buffer_entry(
+                6,
+                ShaderStages::VERTEX | ShaderStages::FRAGMENT | compute_stage,
+                true,
+            ));
+        }
+        // Clipping groups of the view
+        aux_entries.push(BindGroupLayoutEntry {
+            binding: 7,
+            visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT | compute_stage,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: true,
+                min_binding_size: Some(GpuViewClippingGroups::min_size()),
+            },
+            count: None,
+        });
+        let aux_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("aux_layout"),
+            entries: &aux_entries,
         });
 
         let transforms_layout =
@@ -86,7 +179,7 @@ impl FromWorld for CuboidsPipelines {
                 label: Some("transforms_layout"),
                 entries: &[BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStages::VERTEX,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT | compute_stage,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: true,
@@ -97,6 +190,17 @@ impl FromWorld for CuboidsPipelines {
                 }],
             });
 
+        // All transforms, indexed by the instances of batches.
+        let batched_transforms_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("batched_transforms_layout"),
+                entries: &[storage_buffer_entry(
+                    0,
+                    ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    true,
+                )],
+            });
+
         let cuboids_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("cuboid_instances_layout"),
             entries: &[BindGroupLayoutEntry {
@@ -111,30 +215,225 @@ impl FromWorld for CuboidsPipelines {
             }],
         });
 
-        let sample_count = world.resource::<Msaa>().samples();
-        let shader_defs = world.resource::<CuboidsShaderDefs>();
+        // Instances and the indices of those that survived culling.
+        let culled_cuboids_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("culled_cuboid_instances_layout"),
+                entries: &[
+                    storage_buffer_entry(0, ShaderStages::VERTEX, true),
+                    storage_buffer_entry(1, ShaderStages::VERTEX, true),
+                ],
+            });
+
+        // Instances of a batch and their transform slots.
+        let batched_cuboids_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("batched_cuboid_instances_layout"),
+                entries: &[
+                    storage_buffer_entry(0, ShaderStages::VERTEX, true),
+                    storage_buffer_entry(1, ShaderStages::VERTEX, true),
+                ],
+            });
+
+        // Instances and their attributes.
+        let attributes_cuboids_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cuboid_instances_with_attributes_layout"),
+                entries: &[
+                    storage_buffer_entry(0, ShaderStages::VERTEX, true),
+                    storage_buffer_entry(2, ShaderStages::VERTEX, true),
+                ],
+            });
+
+        let culling = shader_defs.gpu_culling.then(|| {
+            // Instances, visible instance indices and indirect draw arguments.
+            let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cuboids_culling_layout"),
+                entries: &[
+                    storage_buffer_entry(0, ShaderStages::COMPUTE, true),
+                    storage_buffer_entry(1, ShaderStages::COMPUTE, false),
+                    storage_buffer_entry(2, ShaderStages::COMPUTE, false),
+                ],
+            });
+            let pipeline_cache = world.resource::<PipelineCache>();
+            let queue_pipeline = |label: &'static str, mut defs: Vec<ShaderDefVal>| {
+                defs.extend(shader_defs.vertex.iter().cloned());
+                pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some(label.into()),
+                    layout: vec![
+                        view_layout.clone(),
+                        aux_layout.clone(),
+                        transforms_layout.clone(),
+                        layout.clone(),
+                    ],
+                    push_constant_ranges: Vec::new(),
+                    shader: CULLING_SHADER_HANDLE,
+                    shader_defs: defs,
+                    entry_point: "cull".into(),
+                })
+            };
+            let pipeline_id = queue_pipeline("cuboids_culling_pipeline", vec![]);
+            let oriented_pipeline_id =
+                queue_pipeline("oriented_cuboids_culling_pipeline", vec!["ORIENTED".into()]);
+            CullingPipeline {
+                layout,
+                pipeline_id,
+                oriented_pipeline_id,
+            }
+        });
+
+        Self {
+            culling,
+            view_layout,
+            aux_layout,
+            cuboids_layout,
+            culled_cuboids_layout,
+            batched_cuboids_layout,
+            attributes_cuboids_layout,
+            transforms_layout,
+            batched_transforms_layout,
+            sample_count: world.resource::<Msaa>().samples(),
+            shader_defs,
+        }
+    }
+}
+
+pub(crate) struct CullingPipeline {
+    pub layout: BindGroupLayout,
+    pub pipeline_id: CachedComputePipelineId,
+    pub oriented_pipeline_id: CachedComputePipelineId,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct CuboidsPipelineKey {
+    pub hdr: bool,
+    /// Draw the instances that survived the culling compute pass.
+    pub gpu_culling: bool,
+    /// Draw the instances of a batch, which are never GPU culled.
+    pub batched: bool,
+    /// The instances are [`OrientedCuboid`](crate::OrientedCuboid)s.
+    pub oriented: bool,
+    /// Bind the [`CuboidAttributes`](crate::CuboidAttributes) of the
+    /// instances, which are neither batched nor GPU culled.
+    pub attributes: bool,
+    /// Alpha blend without writing depth, for the transparent phase.
+    pub transparent: bool,
+    /// Depth-only variant for shadow maps.
+    pub shadow: bool,
+    /// Clamp depth to the near plane of orthographic shadow views, like
+    /// `bevy_pbr` does for directional lights.
+    pub depth_clamp_ortho: bool,
+    /// Variant for the `Opaque3dPrepass` phase.
+    pub prepass: bool,
+    /// Write face normals in the prepass.
+    pub normal_prepass: bool,
+    /// Write (zero) motion vectors in the prepass.
+    pub motion_vector_prepass: bool,
+    /// Write instance IDs into the GPU picking buffer.
+    pub picking: bool,
+    /// Cut cuboids at clipping planes per fragment.
+    pub precise_clipping: bool,
+}
+
+impl SpecializedRenderPipeline for CuboidsPipelines {
+    type Key = CuboidsPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let (vertex_defs, fragment_defs) = self.shader_defs.specialize(key);
+        let mut transforms_layout = self.transforms_layout.clone();
+        let cuboids_layout = if key.batched {
+            transforms_layout = self.batched_transforms_layout.clone();
+            self.batched_cuboids_layout.clone()
+        } else if key.gpu_culling {
+            self.culled_cuboids_layout.clone()
+        } else if key.attributes {
+            self.attributes_cuboids_layout.clone()
+        } else {
+            self.cuboids_layout.clone()
+        };
 
         let layout = vec![
-            view_layout.clone(),
-            aux_layout.clone(),
-            transforms_layout.clone(),
-            cuboids_layout.clone(),
+            self.view_layout.clone(),
+            self.aux_layout.clone(),
+            transforms_layout,
+            cuboids_layout,
         ];
         let vertex = VertexState {
             shader: VERTEX_PULLING_SHADER_HANDLE,
-            shader_defs: shader_defs.vertex.clone(),
+            shader_defs: vertex_defs,
             entry_point: "vertex".into(),
             buffers: vec![],
         };
-        let fragment_target = |texture_format| FragmentState {
-            shader: VERTEX_PULLING_SHADER_HANDLE,
-            shader_defs: shader_defs.fragment.clone(),
-            entry_point: "fragment".into(),
-            targets: vec![Some(ColorTargetState {
-                format: texture_format,
-                blend: Some(BlendState::REPLACE),
-                write_mask: ColorWrites::ALL,
-            })],
+        let texture_format = if key.hdr {
+            TextureFormat::Rgba16Float
+        } else {
+            TextureFormat::bevy_default()
+        };
+        let fragment = if key.picking {
+            Some(FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE,
+                shader_defs: fragment_defs,
+                entry_point: "fragment_picking".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: PICKING_ID_FORMAT,
+                    // Integer formats can't be blended.
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            })
+        } else if key.prepass {
+            // Attachments are laid out like the prepass node's: normals, motion
+            // vectors and the (unsupported) deferred targets.
+            let mut targets = vec![
+                key.normal_prepass.then_some(ColorTargetState {
+                    format: NORMAL_PREPASS_FORMAT,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                }),
+                key.motion_vector_prepass.then_some(ColorTargetState {
+                    format: MOTION_VECTOR_PREPASS_FORMAT,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                }),
+                None,
+                None,
+            ];
+            if targets.iter().all(Option::is_none) {
+                targets.clear();
+            }
+            // Wireframes still have to discard the inside of their faces, and
+            // precise clipping has to cut them.
+            (!targets.is_empty() || self.shader_defs.outlines || key.precise_clipping).then(|| {
+                FragmentState {
+                    shader: VERTEX_PULLING_SHADER_HANDLE,
+                    shader_defs: fragment_defs,
+                    entry_point: "fragment_prepass".into(),
+                    targets,
+                }
+            })
+        } else if key.shadow {
+            // Only needed to write the unclamped depth.
+            key.depth_clamp_ortho.then(|| FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE,
+                shader_defs: fragment_defs,
+                entry_point: "fragment_depth".into(),
+                targets: vec![],
+            })
+        } else {
+            Some(FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE,
+                shader_defs: fragment_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: texture_format,
+                    blend: Some(if key.transparent {
+                        BlendState::ALPHA_BLENDING
+                    } else {
+                        BlendState::REPLACE
+                    }),
+                    write_mask: ColorWrites::ALL,
+                })],
+            })
         };
         let primitive = PrimitiveState {
             front_face: FrontFace::Ccw,
@@ -147,8 +446,10 @@ impl FromWorld for CuboidsPipelines {
         };
         let depth_stencil = Some(DepthStencilState {
             format: TextureFormat::Depth32Float,
-            depth_write_enabled: true,
-            depth_compare: CompareFunction::Greater,
+            depth_write_enabled: !key.transparent,
+            // Equal depths must pass so that the main pass can draw over the
+            // prepass.
+            depth_compare: CompareFunction::GreaterEqual,
             stencil: StencilState {
                 front: StencilFaceState::IGNORE,
                 back: StencilFaceState::IGNORE,
@@ -162,44 +463,36 @@ impl FromWorld for CuboidsPipelines {
             },
         });
         let multisample = MultisampleState {
-            count: sample_count,
+            // Shadow maps and ID buffers are never multisampled.
+            count: if key.shadow || key.picking {
+                1
+            } else {
+                self.sample_count
+            },
             mask: !0,
             alpha_to_coverage_enabled: false,
         };
 
-        let pipeline_descriptor = RenderPipelineDescriptor {
-            label: Some("cuboids_pipeline".into()),
-            layout: layout.clone(),
-            vertex: vertex.clone(),
-            fragment: Some(fragment_target(TextureFormat::bevy_default())),
-            primitive,
-            depth_stencil: depth_stencil.clone(),
-            multisample,
-            push_constant_ranges: Vec::new(),
-        };
-
-        let hdr_pipeline_descriptor = RenderPipelineDescriptor {
-            label: Some("cuboids_hdr_pipeline".into()),
+        RenderPipelineDescriptor {
+            label: Some(
+                match (key.hdr, key.transparent, key.shadow, key.prepass) {
+                    _ if key.picking => "cuboids_picking_pipeline",
+                    (_, _, _, true) => "cuboids_prepass_pipeline",
+                    (_, _, true, false) => "cuboids_shadow_pipeline",
+                    (false, false, false, false) => "cuboids_pipeline",
+                    (true, false, false, false) => "cuboids_hdr_pipeline",
+                    (false, true, false, false) => "transparent_cuboids_pipeline",
+                    (true, true, false, false) => "transparent_cuboids_hdr_pipeline",
+                }
+                .into(),
+            ),
             layout,
             vertex,
-            fragment: Some(fragment_target(TextureFormat::Rgba16Float)),
+            fragment,
             primitive,
             depth_stencil,
             multisample,
             push_constant_ranges: Vec::new(),
-        };
-
-        let pipeline_cache = world.resource_mut::<PipelineCache>();
-        let pipeline_id = pipeline_cache.queue_render_pipeline(pipeline_descriptor);
-        let hdr_pipeline_id = pipeline_cache.queue_render_pipeline(hdr_pipeline_descriptor);
-
-        Self {
-            pipeline_id,
-            hdr_pipeline_id,
-            view_layout,
-            aux_layout,
-            cuboids_layout,
-            transforms_layout,
         }
     }
 }
@@ -208,11 +501,219 @@ impl FromWorld for CuboidsPipelines {
 pub(crate) struct CuboidsShaderDefs {
     pub vertex: Vec<ShaderDefVal>,
     pub fragment: Vec<ShaderDefVal>,
+    pub gpu_culling: bool,
+    pub outlines: bool,
+    /// Entities that share a material are drawn from shared instance buffers.
+    pub batching: bool,
+    /// Clipping planes live in a storage buffer rather than the fixed size
+    /// uniform array.
+    pub clipping_planes_storage: bool,
 }
 
 impl CuboidsShaderDefs {
     pub fn enable_outlines(&mut self) {
+        self.outlines = true;
         self.vertex.push("OUTLINES".into());
         self.fragment.push("OUTLINES".into());
     }
+
+    pub fn enable_gpu_culling(&mut self) {
+        self.gpu_culling = true;
+    }
+
+    pub fn enable_batching(&mut self) {
+        self.batching = true;
+    }
+
+    pub fn enable_clipping_planes_storage(&mut self) {
+        self.clipping_planes_storage = true;
+        self.vertex.push("CLIPPING_PLANES_STORAGE".into());
+        self.fragment.push("CLIPPING_PLANES_STORAGE".into());
+    }
+
+    /// Returns the vertex and fragment shader defs of the pipeline for `key`.
+    ///
+    /// Each stage compiles the whole shader, not only its entry point, so a def
+    /// that changes declarations shared between entry points must be set for
+    /// both stages.
+    pub fn specialize(&self, key: CuboidsPipelineKey) -> (Vec<ShaderDefVal>, Vec<ShaderDefVal>) {
+        let mut both = Vec::new();
+        let mut vertex_only = Vec::new();
+        if key.batched {
+            both.push("BATCHED");
+        } else if key.gpu_culling {
+            both.push("GPU_CULLING");
+        } else if key.attributes {
+            vertex_only.push("ATTRIBUTES");
+        }
+        for (enabled, def) in [
+            (key.oriented, "ORIENTED"),
+            (key.depth_clamp_ortho, "DEPTH_CLAMP_ORTHO"),
+            (key.prepass, "PREPASS"),
+            (key.precise_clipping, "PRECISE_CLIPPING"),
+            (key.picking, "PICKING"),
+            (key.normal_prepass, "NORMAL_PREPASS"),
+            (key.motion_vector_prepass, "MOTION_VECTOR_PREPASS"),
+        ] {
+            if enabled {
+                both.push(def);
+            }
+        }
+        // `fragment_prepass` returns the prepass attachments and the clipped
+        // depth, if there are any.
+        let prepass_outputs =
+            key.normal_prepass || key.motion_vector_prepass || key.precise_clipping;
+        if key.prepass && !key.picking && prepass_outputs {
+            both.push("PREPASS_FRAGMENT_OUTPUT");
+        }
+
+        let stage_defs = |common: &[ShaderDefVal], only: &[&str]| {
+            let defs = both.iter().chain(only).map(|&def| def.into());
+            common.iter().cloned().chain(defs).collect()
+        };
+        (
+            stage_defs(&self.vertex, &vertex_only),
+            stage_defs(&self.fragment, &[]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use naga_oil::compose::{
+        ComposableModuleDescriptor, Composer, NagaModuleDescriptor, ShaderDefValue,
+    };
+    use std::collections::BTreeSet;
+
+    /// The parts of `bevy_render::view` used by the shaders, laid out like
+    /// Bevy's.
+    const VIEW_SHADER: &str = "
+#define_import_path bevy_render::view
+
+struct View {
+    view_proj: mat4x4<f32>,
+    unjittered_view_proj: mat4x4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    inverse_view: mat4x4<f32>,
+    projection: mat4x4<f32>,
+    inverse_projection: mat4x4<f32>,
+    world_position: vec3<f32>,
+    viewport: vec4<f32>,
+    frustum: array<vec4<f32>, 6>,
+};
+";
+
+    /// All keys that the render phases queue.
+    fn all_keys() -> impl Iterator<Item = CuboidsPipelineKey> {
+        (0..1u32 << 13)
+            .map(|bits| {
+                let bit = |i: u32| bits & (1 << i) != 0;
+                CuboidsPipelineKey {
+                    hdr: bit(0),
+                    gpu_culling: bit(1),
+                    batched: bit(2),
+                    oriented: bit(3),
+                    attributes: bit(4),
+                    transparent: bit(5),
+                    shadow: bit(6),
+                    depth_clamp_ortho: bit(7),
+                    prepass: bit(8),
+                    normal_prepass: bit(9),
+                    motion_vector_prepass: bit(10),
+                    picking: bit(11),
+                    precise_clipping: bit(12),
+                }
+            })
+            .filter(|key| {
+                let passes = [key.prepass, key.shadow, key.picking];
+                passes.into_iter().filter(|&pass| pass).count() <= 1
+                    && (key.prepass || !(key.normal_prepass || key.motion_vector_prepass))
+                    && (key.shadow || !key.depth_clamp_ortho)
+                    && !(key.shadow && (key.gpu_culling || key.precise_clipping))
+            })
+    }
+
+    fn all_shader_defs() -> impl Iterator<Item = CuboidsShaderDefs> {
+        (0..4).map(|bits| {
+            let mut shader_defs = CuboidsShaderDefs::default();
+            if bits & 1 != 0 {
+                shader_defs.enable_outlines();
+            }
+            if bits & 2 != 0 {
+                shader_defs.enable_clipping_planes_storage();
+            }
+            shader_defs
+        })
+    }
+
+    fn def_names(defs: &[ShaderDefVal]) -> BTreeSet<String> {
+        defs.iter()
+            .map(|def| match def {
+                ShaderDefVal::Bool(name, true) => name.clone(),
+                other => panic!("unexpected shader def {other:?}"),
+            })
+            .collect()
+    }
+
+    fn composer() -> Composer {
+        let mut composer = Composer::default();
+        for (source, file_path) in [
+            (VIEW_SHADER, "view.wgsl"),
+            (include_str!("common.wgsl"), "common.wgsl"),
+        ] {
+            composer
+                .add_composable_module(ComposableModuleDescriptor {
+                    source,
+                    file_path,
+                    ..default()
+                })
+                .unwrap();
+        }
+        composer
+    }
+
+    fn validate(composer: &mut Composer, def_names: &BTreeSet<String>) -> Result<(), String> {
+        let shader_defs = def_names
+            .iter()
+            .map(|name| (name.clone(), ShaderDefValue::Bool(true)))
+            .collect();
+        let module = composer
+            .make_naga_module(NagaModuleDescriptor {
+                source: include_str!("vertex_pulling.wgsl"),
+                file_path: "vertex_pulling.wgsl",
+                shader_defs,
+                ..default()
+            })
+            .map_err(|e| e.emit_to_string(composer))?;
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::default(),
+        )
+        .validate(&module)
+        .map(|_| ())
+        .map_err(|e| format!("{e:?}"))
+    }
+
+    #[test]
+    fn every_pipeline_variant_compiles() {
+        let mut variants = BTreeSet::new();
+        for shader_defs in all_shader_defs() {
+            for key in all_keys() {
+                let (vertex_defs, fragment_defs) = shader_defs.specialize(key);
+                variants.insert(def_names(&vertex_defs));
+                variants.insert(def_names(&fragment_defs));
+            }
+        }
+        let mut composer = composer();
+        let failures: Vec<_> = variants
+            .iter()
+            .filter_map(|defs| {
+                let error = validate(&mut composer, defs).err()?;
+                Some(format!("{defs:?}:\n{error}"))
+            })
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
 }
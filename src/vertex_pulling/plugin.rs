@@ -1,37 +1,136 @@
+use super::batching::CuboidBatches;
 use super::buffers::*;
 use super::cuboid_cache::CuboidBufferCache;
-use super::draw::{AuxiliaryMeta, DrawCuboids, TransformsMeta, ViewMeta};
-use super::extract::{extract_clipping_planes, extract_cuboids};
-use super::pipeline::{CuboidsPipelines, CuboidsShaderDefs, VERTEX_PULLING_SHADER_HANDLE};
+use super::culling::{
+    prepare_cuboids_culling, CuboidsCulling, CuboidsCullingNode, CUBOIDS_CULLING_NODE,
+};
+use super::draw::{
+    AuxiliaryMeta, DrawCuboids, DrawFullResolutionCuboids, TransformsMeta, ViewMeta,
+};
+use super::extract::{
+    extract_clipping_planes, extract_cuboids, extract_lighting, extract_materials, extract_palettes,
+};
+use super::lod::{prepare_cuboid_lods, SelectedCuboidLods};
+use super::picking::{
+    extract_cuboids_picking, map_cuboids_picking_readbacks, prepare_cuboids_picking,
+    queue_cuboids_picking, CuboidsPickingItem, CuboidsPickingNode, CuboidsPickingReadbacks,
+    CUBOIDS_PICKING_NODE,
+};
+use super::pipeline::{
+    CuboidsPipelines, CuboidsShaderDefs, COMMON_SHADER_HANDLE, CULLING_SHADER_HANDLE,
+    VERTEX_PULLING_SHADER_HANDLE,
+};
 use super::prepare::{
-    prepare_auxiliary_bind_group, prepare_clipping_planes, prepare_cuboid_transforms,
-    prepare_cuboids, prepare_cuboids_view_bind_group, prepare_materials,
+    prepare_auxiliary_bind_group, prepare_clipping_planes, prepare_cuboid_batches,
+    prepare_cuboid_transforms, prepare_cuboids, prepare_cuboids_view_bind_group, prepare_lighting,
+    prepare_materials, prepare_palettes,
 };
 use super::queue::queue_cuboids;
-use crate::CuboidMaterialMap;
+use super::view_overrides::{
+    extract_cuboids_view_overrides, prepare_view_clipping_groups, ExtractedCuboidMaterials,
+};
+use crate::bvh::update_cuboids_bvh;
+use crate::colormap::update_colormap_atlas;
+use crate::cuboids::clear_cuboids_dirty_ranges;
+use crate::cuboids_asset::sync_cuboids_assets;
+use crate::lod::update_cuboids_lod;
+use crate::picking::{send_gpu_picking_events, GpuPickingResults};
+use crate::{
+    CuboidColormaps, CuboidLighting, CuboidMaterial, CuboidPalettes, CuboidsAsset,
+    CuboidsFileLoader, CuboidsPicked,
+};
 use bevy::asset::load_internal_asset;
-use bevy::core_pipeline::core_3d::Opaque3d;
+use bevy::core_pipeline::core_3d::{
+    graph::node::{END_MAIN_PASS, MAIN_TRANSPARENT_PASS, PREPASS},
+    Opaque3d, Transparent3d, CORE_3D,
+};
+use bevy::core_pipeline::prepass::Opaque3dPrepass;
+#[cfg(feature = "pbr")]
+use bevy::pbr::Shadow;
 use bevy::prelude::*;
+use bevy::render::render_graph::{RenderGraphApp, ViewNodeRunner};
+use bevy::render::render_phase::{AddRenderCommand, DrawFunctions};
+use bevy::render::render_resource::{BufferBindingType, SpecializedRenderPipelines};
+use bevy::render::renderer::RenderDevice;
 use bevy::render::view::prepare_view_uniforms;
-use bevy::render::{render_phase::AddRenderCommand, RenderApp};
+use bevy::render::RenderApp;
 use bevy::render::{Render, RenderSet};
 
 /// Renders the [`Cuboids`](crate::Cuboids) component using the "vertex pulling" technique.
 #[derive(Default)]
 pub struct VertexPullingRenderPlugin {
     pub outlines: bool,
+    /// Cull cuboids against each view's frustum in a compute pass, and only
+    /// draw the survivors with indirect draw calls.
+    ///
+    /// Requires compute shader support.
+    pub gpu_culling: bool,
+    /// Copy the instances of all entities that share an opaque material into
+    /// shared buffers, and draw the visible ones with a single draw call per
+    /// run of adjacent entities, instead of one per entity.
+    ///
+    /// Worthwhile for scenes split into thousands of small chunks. Batched
+    /// entities are not GPU culled.
+    pub batching: bool,
+    /// Render an ID buffer for every
+    /// [`CuboidsPickingCamera`](crate::CuboidsPickingCamera) and send the
+    /// results as [`CuboidsPicked`] events.
+    pub gpu_picking: bool,
 }
 
 impl Plugin for VertexPullingRenderPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<CuboidMaterialMap>();
+        app.init_asset::<CuboidMaterial>()
+            .init_asset::<CuboidsAsset>()
+            .init_asset_loader::<CuboidsFileLoader>()
+            .init_resource::<CuboidColormaps>()
+            .init_resource::<CuboidLighting>()
+            .init_resource::<CuboidPalettes>()
+            .add_systems(First, clear_cuboids_dirty_ranges)
+            .add_systems(PreUpdate, sync_cuboids_assets)
+            .add_systems(
+                PostUpdate,
+                (
+                    update_cuboids_bvh,
+                    update_cuboids_lod,
+                    update_colormap_atlas,
+                ),
+            );
+
+        // Drawn by entities with the default handle.
+        app.world.resource_mut::<Assets<CuboidMaterial>>().insert(
+            Handle::<CuboidMaterial>::default(),
+            CuboidMaterial::default(),
+        );
+
+        #[cfg(feature = "pbr")]
+        app.add_systems(PostUpdate, crate::lighting::sync_pbr_lighting);
+
+        #[cfg(feature = "csv")]
+        app.init_asset_loader::<crate::CuboidsCsvLoader>();
 
+        #[cfg(feature = "serde")]
+        app.init_asset_loader::<crate::CuboidMaterialLoader>();
+
+        if self.gpu_picking {
+            app.add_event::<CuboidsPicked>()
+                .init_resource::<GpuPickingResults>()
+                .add_systems(PreUpdate, send_gpu_picking_events);
+        }
+
+        load_internal_asset!(app, COMMON_SHADER_HANDLE, "common.wgsl", Shader::from_wgsl);
         load_internal_asset!(
             app,
             VERTEX_PULLING_SHADER_HANDLE,
             "vertex_pulling.wgsl",
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            CULLING_SHADER_HANDLE,
+            "culling.wgsl",
+            Shader::from_wgsl
+        );
         {
             use super::index_buffer::{CuboidsIndexBuffer, CUBE_INDICES_HANDLE};
             use bevy::render::render_asset::RenderAssetPlugin;
@@ -45,6 +144,7 @@ impl Plugin for VertexPullingRenderPlugin {
 
     fn finish(&self, app: &mut App) {
         let maybe_msaa = app.world.get_resource::<Msaa>().cloned();
+        let maybe_picking_results = app.world.get_resource::<GpuPickingResults>().cloned();
         let render_app = app.sub_app_mut(RenderApp);
 
         if let Some(msaa) = maybe_msaa {
@@ -54,33 +154,130 @@ impl Plugin for VertexPullingRenderPlugin {
         if self.outlines {
             shader_defs.enable_outlines();
         }
+        // Storage buffers in the vertex stage, which are the most in any
+        // stage: the instances, palettes and clipping planes, plus
+        // - the attributes of unculled, unbatched entities,
+        // - or the visible indices of GPU culled entities,
+        // - or the instance references and transforms of batches.
+        // The indirect arguments of culled draws aren't bound.
+        let storage_buffers = if self.batching { 5 } else { 4 };
+        if matches!(
+            render_app
+                .world
+                .resource::<RenderDevice>()
+                .get_supported_read_only_binding_type(storage_buffers),
+            BufferBindingType::Storage { .. }
+        ) {
+            shader_defs.enable_clipping_planes_storage();
+        }
+        if self.gpu_culling {
+            shader_defs.enable_gpu_culling();
+            render_app
+                .add_render_graph_node::<ViewNodeRunner<CuboidsCullingNode>>(
+                    CORE_3D,
+                    CUBOIDS_CULLING_NODE,
+                )
+                .add_render_graph_edges(CORE_3D, &[CUBOIDS_CULLING_NODE, PREPASS]);
+        }
+        if self.batching {
+            shader_defs.enable_batching();
+        }
         render_app.insert_resource(shader_defs);
 
         render_app
             .add_render_command::<Opaque3d, DrawCuboids>()
+            .add_render_command::<Transparent3d, DrawCuboids>()
+            .add_render_command::<Opaque3dPrepass, DrawCuboids>()
             .init_resource::<AuxiliaryMeta>()
+            .init_resource::<CuboidBatches>()
             .init_resource::<CuboidBufferCache>()
+            .init_resource::<CuboidsCulling>()
             .init_resource::<CuboidsPipelines>()
-            .init_resource::<DynamicUniformBufferOfCuboidMaterial>()
-            .init_resource::<DynamicUniformBufferOfCuboidTransforms>()
+            .init_resource::<SpecializedRenderPipelines<CuboidsPipelines>>()
+            .init_resource::<SelectedCuboidLods>()
+            .init_resource::<PersistentUniformBufferOfCuboidMaterial>()
+            .init_resource::<PersistentUniformBufferOfCuboidTransforms>()
+            .init_resource::<DynamicUniformBufferOfViewClippingGroups>()
+            .init_resource::<ExtractedCuboidMaterials>()
             .init_resource::<TransformsMeta>()
+            .init_resource::<StorageBufferOfCuboidPalettes>()
+            .init_resource::<StorageBufferOfGpuClippingPlaneRanges>()
             .init_resource::<UniformBufferOfGpuClippingPlaneRanges>()
+            .init_resource::<UniformBufferOfGpuCuboidLighting>()
             .init_resource::<ViewMeta>()
-            .add_systems(ExtractSchedule, (extract_cuboids, extract_clipping_planes))
+            .add_systems(
+                ExtractSchedule,
+                (
+                    extract_materials,
+                    extract_cuboids.after(extract_materials),
+                    extract_cuboids_view_overrides.after(extract_materials),
+                    extract_clipping_planes,
+                    extract_lighting,
+                    extract_palettes,
+                ),
+            )
             .add_systems(
                 Render,
                 (
                     prepare_materials,
                     prepare_clipping_planes,
+                    prepare_palettes,
+                    prepare_lighting,
+                    prepare_view_clipping_groups,
                     prepare_auxiliary_bind_group
                         .after(prepare_materials)
-                        .after(prepare_clipping_planes),
+                        .after(prepare_view_clipping_groups)
+                        .after(prepare_clipping_planes)
+                        .after(prepare_palettes)
+                        .after(prepare_lighting),
                     prepare_cuboid_transforms,
                     prepare_cuboids,
+                    prepare_cuboid_batches,
+                    prepare_cuboid_lods,
+                    prepare_cuboids_culling.after(prepare_cuboids),
                     prepare_cuboids_view_bind_group.after(prepare_view_uniforms),
                 )
                     .in_set(RenderSet::Prepare),
             )
             .add_systems(Render, queue_cuboids.in_set(RenderSet::Queue));
+
+        if let Some(picking_results) = maybe_picking_results {
+            render_app
+                .insert_resource(picking_results)
+                .init_resource::<CuboidsPickingReadbacks>()
+                .init_resource::<DrawFunctions<CuboidsPickingItem>>()
+                .add_render_command::<CuboidsPickingItem, DrawFullResolutionCuboids>()
+                .add_systems(ExtractSchedule, extract_cuboids_picking)
+                .add_systems(
+                    Render,
+                    (
+                        prepare_cuboids_picking.in_set(RenderSet::Prepare),
+                        queue_cuboids_picking.in_set(RenderSet::Queue),
+                        map_cuboids_picking_readbacks.in_set(RenderSet::Cleanup),
+                    ),
+                )
+                .add_render_graph_node::<ViewNodeRunner<CuboidsPickingNode>>(
+                    CORE_3D,
+                    CUBOIDS_PICKING_NODE,
+                )
+                .add_render_graph_edges(
+                    CORE_3D,
+                    &[MAIN_TRANSPARENT_PASS, CUBOIDS_PICKING_NODE, END_MAIN_PASS],
+                );
+        }
+
+        // Cast shadows when Bevy's PBR renderer is present.
+        #[cfg(feature = "pbr")]
+        if render_app
+            .world
+            .contains_resource::<DrawFunctions<Shadow>>()
+        {
+            render_app
+                .add_render_command::<Shadow, DrawCuboids>()
+                .add_systems(
+                    Render,
+                    super::shadow::queue_cuboid_shadows.in_set(RenderSet::Queue),
+                );
+        }
     }
 }
@@ -1,13 +1,17 @@
+use super::batching::CuboidBatches;
 use super::buffers::*;
 use super::cuboid_cache::CuboidBufferCache;
 use super::draw::{AuxiliaryMeta, TransformsMeta, ViewMeta};
-use super::pipeline::CuboidsPipelines;
+use super::pipeline::{CuboidsPipelines, CuboidsShaderDefs};
+use crate::colormap::COLORMAP_ATLAS_HANDLE;
 
-use bevy::render::render_resource::BindGroupEntries;
+use bevy::render::render_resource::{BindGroupEntries, BindGroupEntry};
 use bevy::{
     prelude::*,
     render::{
+        render_asset::RenderAssets,
         renderer::{RenderDevice, RenderQueue},
+        texture::FallbackImage,
         view::ViewUniforms,
     },
 };
@@ -15,35 +19,103 @@ use bevy::{
 pub(crate) fn prepare_clipping_planes(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    shader_defs: Res<CuboidsShaderDefs>,
     mut clipping_plane_uniform: ResMut<UniformBufferOfGpuClippingPlaneRanges>,
+    mut clipping_plane_storage: ResMut<StorageBufferOfGpuClippingPlaneRanges>,
 ) {
     // Values already pushed in extract stage.
     clipping_plane_uniform.write_buffer(&render_device, &render_queue);
+    if shader_defs.clipping_planes_storage {
+        clipping_plane_storage.write_buffer(&render_device, &render_queue);
+    }
+}
+
+pub(crate) fn prepare_palettes(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut palettes_buffer: ResMut<StorageBufferOfCuboidPalettes>,
+) {
+    // Only re-uploaded when the palettes were modified.
+    if palettes_buffer.is_changed() {
+        palettes_buffer.write_buffer(&render_device, &render_queue);
+    }
+}
+
+pub(crate) fn prepare_lighting(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut lighting_uniform: ResMut<UniformBufferOfGpuCuboidLighting>,
+) {
+    if lighting_uniform.is_changed() {
+        lighting_uniform.write_buffer(&render_device, &render_queue);
+    }
 }
 
 pub(crate) fn prepare_materials(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
-    mut material_uniforms: ResMut<DynamicUniformBufferOfCuboidMaterial>,
+    mut material_uniforms: ResMut<PersistentUniformBufferOfCuboidMaterial>,
 ) {
-    // Values already pushed in extract stage.
+    // Only the materials that changed in the extract stage are written.
     material_uniforms.write_buffer(&render_device, &render_queue);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn prepare_auxiliary_bind_group(
     pipeline: Res<CuboidsPipelines>,
     render_device: Res<RenderDevice>,
     mut aux_meta: ResMut<AuxiliaryMeta>,
     clipping_plane_uniform: Res<UniformBufferOfGpuClippingPlaneRanges>,
-    material_uniform: Res<DynamicUniformBufferOfCuboidMaterial>,
+    clipping_plane_storage: Res<StorageBufferOfGpuClippingPlaneRanges>,
+    view_clipping_groups_uniform: Res<DynamicUniformBufferOfViewClippingGroups>,
+    material_uniform: Res<PersistentUniformBufferOfCuboidMaterial>,
+    palettes_buffer: Res<StorageBufferOfCuboidPalettes>,
+    lighting_uniform: Res<UniformBufferOfGpuCuboidLighting>,
+    gpu_images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
 ) {
-    if let (Some(color_binding), Some(planes_binding)) =
-        (material_uniform.binding(), clipping_plane_uniform.binding())
-    {
+    if let (
+        Some(color_binding),
+        Some(planes_binding),
+        Some(palettes_binding),
+        Some(lighting_binding),
+        Some(view_clipping_groups_binding),
+    ) = (
+        material_uniform.binding(),
+        clipping_plane_uniform.binding(),
+        palettes_buffer.binding(),
+        lighting_uniform.binding(),
+        view_clipping_groups_uniform.binding(),
+    ) {
+        let colormap_atlas = gpu_images
+            .get(&COLORMAP_ATLAS_HANDLE)
+            .unwrap_or(&fallback_image.d2);
+        let mut entries = BindGroupEntries::sequential((
+            color_binding,
+            planes_binding,
+            &colormap_atlas.texture_view,
+            &colormap_atlas.sampler,
+            palettes_binding,
+            lighting_binding,
+        ))
+        .to_vec();
+        if pipeline.shader_defs.clipping_planes_storage {
+            let Some(planes_storage_binding) = clipping_plane_storage.binding() else {
+                return;
+            };
+            entries.push(BindGroupEntry {
+                binding: 6,
+                resource: planes_storage_binding,
+            });
+        }
+        entries.push(BindGroupEntry {
+            binding: 7,
+            resource: view_clipping_groups_binding,
+        });
         aux_meta.bind_group = Some(render_device.create_bind_group(
             "auxiliary_bind_group",
             &pipeline.aux_layout,
-            &BindGroupEntries::sequential((color_binding, planes_binding)),
+            &entries,
         ));
     }
 }
@@ -53,24 +125,35 @@ pub(crate) fn prepare_cuboid_transforms(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     mut transforms_meta: ResMut<TransformsMeta>,
-    mut transform_uniforms: ResMut<DynamicUniformBufferOfCuboidTransforms>,
+    mut transform_uniforms: ResMut<PersistentUniformBufferOfCuboidTransforms>,
 ) {
+    // Only the transforms that changed in the extract stage are written.
     let write_transform_buffer_span =
         bevy::log::info_span!("prepare_cuboids::write_transform_buffer");
-    write_transform_buffer_span.in_scope(|| {
-        transform_uniforms.write_buffer(&render_device, &render_queue);
-    });
-    if let Some(transforms_binding) = transform_uniforms.binding() {
+    let reallocated = write_transform_buffer_span
+        .in_scope(|| transform_uniforms.write_buffer(&render_device, &render_queue));
+    if !reallocated && transforms_meta.transform_buffer_bind_group.is_some() {
+        return;
+    }
+    if let (Some(transforms_binding), Some(transforms_array_binding)) = (
+        transform_uniforms.binding(),
+        transform_uniforms.array_binding(),
+    ) {
         let create_bind_group_span = bevy::log::info_span!("prepare_cuboids::create_bind_group");
-        transforms_meta.transform_buffer_bind_group = create_bind_group_span.in_scope(|| {
-            Some(render_device.create_bind_group(
+        create_bind_group_span.in_scope(|| {
+            transforms_meta.transform_buffer_bind_group = Some(render_device.create_bind_group(
                 "gpu_cuboids_transforms_bind_group",
                 &pipeline.transforms_layout,
                 &BindGroupEntries::single(transforms_binding),
-            ))
+            ));
+            if pipeline.shader_defs.batching {
+                transforms_meta.batched_bind_group = Some(render_device.create_bind_group(
+                    "batched_cuboids_transforms_bind_group",
+                    &pipeline.batched_transforms_layout,
+                    &BindGroupEntries::single(transforms_array_binding),
+                ));
+            }
         });
-    } else {
-        assert!(transform_uniforms.is_empty());
     }
 }
 
@@ -86,29 +169,80 @@ pub(crate) fn prepare_cuboids(
 
     // Write all dirty buffers from the cuboids cache.
     for entry in cuboid_buffers.entries.values_mut() {
-        if !entry.dirty {
-            assert!(entry.instance_buffer_bind_group.is_some());
+        // Batched instances are written by `prepare_cuboid_batches`.
+        if entry.batch.is_some() {
             continue;
         }
-
         write_instance_buffer_span.in_scope(|| {
-            entry
-                .instance_buffer
-                .write_buffer(&render_device, &render_queue);
+            if entry.dirty {
+                entry
+                    .instance_buffer
+                    .write_buffer(&render_device, &render_queue);
+            } else {
+                // Only overwrite the modified ranges of the existing buffer.
+                for range in entry.dirty_ranges.drain(..) {
+                    entry.instance_buffer.write_range(&render_queue, range);
+                }
+            }
+            if let Some(attributes) = entry.attributes.as_mut().filter(|a| a.dirty) {
+                attributes
+                    .buffer
+                    .write_buffer(&render_device, &render_queue);
+                attributes.dirty = false;
+            }
         });
 
-        entry.instance_buffer_bind_group = create_bind_group_span.in_scope(|| {
-            Some(render_device.create_bind_group(
-                "cuboids_instance_buffer_bind_group",
-                &pipeline.cuboids_layout,
-                &BindGroupEntries::single(entry.instance_buffer.binding().unwrap()),
-            ))
-        });
+        // Writing a whole buffer may have reallocated it. Extraction also
+        // drops the bind group when attributes are added or removed.
+        if entry.dirty || entry.instance_buffer_bind_group.is_none() {
+            entry.instance_buffer_bind_group = create_bind_group_span.in_scope(|| {
+                Some(match &entry.attributes {
+                    Some(attributes) => render_device.create_bind_group(
+                        "cuboids_instance_buffer_bind_group",
+                        &pipeline.attributes_cuboids_layout,
+                        &BindGroupEntries::with_indices((
+                            (0, entry.instance_buffer.binding().unwrap()),
+                            (2, attributes.buffer.binding().unwrap()),
+                        )),
+                    ),
+                    None => render_device.create_bind_group(
+                        "cuboids_instance_buffer_bind_group",
+                        &pipeline.cuboids_layout,
+                        &BindGroupEntries::single(entry.instance_buffer.binding().unwrap()),
+                    ),
+                })
+            });
+        }
 
         entry.dirty = false;
     }
 }
 
+pub(crate) fn prepare_cuboid_batches(
+    pipeline: Res<CuboidsPipelines>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut batches: ResMut<CuboidBatches>,
+) {
+    let write_instance_buffer_span =
+        bevy::log::info_span!("prepare_cuboid_batches::write_instance_buffer");
+
+    for batch in batches.batches.values_mut() {
+        let reallocated = write_instance_buffer_span
+            .in_scope(|| batch.write_buffers(&render_device, &render_queue));
+        if !reallocated && batch.bind_group.is_some() {
+            continue;
+        }
+        if let Some(bindings) = batch.bindings() {
+            batch.bind_group = Some(render_device.create_bind_group(
+                "cuboid_batch_bind_group",
+                &pipeline.batched_cuboids_layout,
+                &BindGroupEntries::sequential(bindings),
+            ));
+        }
+    }
+}
+
 pub(crate) fn prepare_cuboids_view_bind_group(
     render_device: Res<RenderDevice>,
     cuboids_pipeline: Res<CuboidsPipelines>,
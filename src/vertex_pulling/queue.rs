@@ -1,47 +1,189 @@
-use super::cuboid_cache::CuboidBufferCache;
+use super::batching::{CuboidBatches, ViewBatches};
+use super::cuboid_cache::{CachedCuboidBuffers, CuboidBufferCache};
+use super::culling::CuboidsCulling;
 use super::draw::DrawCuboids;
-use super::pipeline::CuboidsPipelines;
+use super::lod::SelectedCuboidLods;
+use super::pipeline::{CuboidsPipelineKey, CuboidsPipelines};
+use super::view_overrides::ExtractedCuboidsViewOverrides;
 
-use bevy::core_pipeline::core_3d::Opaque3d;
+use bevy::core_pipeline::core_3d::{Opaque3d, Transparent3d};
+use bevy::core_pipeline::prepass::{
+    DeferredPrepass, MotionVectorPrepass, NormalPrepass, Opaque3dPrepass,
+};
+use bevy::ecs::query::Has;
 use bevy::prelude::*;
 use bevy::render::render_phase::{DrawFunctions, RenderPhase};
+use bevy::render::render_resource::{PipelineCache, SpecializedRenderPipelines};
 use bevy::render::view::{ExtractedView, VisibleEntities};
 
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub(crate) fn queue_cuboids(
     cuboids_pipelines: Res<CuboidsPipelines>,
+    pipeline_cache: Res<PipelineCache>,
+    mut specialized_pipelines: ResMut<SpecializedRenderPipelines<CuboidsPipelines>>,
     opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
+    prepass_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
     buffer_cache: Res<CuboidBufferCache>,
-    mut views: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<Opaque3d>)>,
+    mut batches: ResMut<CuboidBatches>,
+    mut selected_lods: ResMut<SelectedCuboidLods>,
+    mut culling: ResMut<CuboidsCulling>,
+    mut views: Query<(
+        Entity,
+        &ExtractedView,
+        &VisibleEntities,
+        Option<&ExtractedCuboidsViewOverrides>,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<Transparent3d>,
+        Option<&mut RenderPhase<Opaque3dPrepass>>,
+        (
+            Has<NormalPrepass>,
+            Has<MotionVectorPrepass>,
+            Has<DeferredPrepass>,
+        ),
+    )>,
 ) {
-    let draw_cuboids = opaque_3d_draw_functions
+    let draw_opaque_cuboids = opaque_3d_draw_functions
         .read()
         .get_id::<DrawCuboids>()
         .unwrap();
+    let draw_transparent_cuboids = transparent_3d_draw_functions
+        .read()
+        .get_id::<DrawCuboids>()
+        .unwrap();
+    let draw_prepass_cuboids = prepass_draw_functions
+        .read()
+        .get_id::<DrawCuboids>()
+        .unwrap();
+
+    let gpu_culling = cuboids_pipelines.culling.is_some();
+
+    for (
+        view_entity,
+        view,
+        visible_entities,
+        maybe_overrides,
+        mut opaque_phase,
+        mut transparent_phase,
+        mut prepass_phase,
+        (normal_prepass, motion_vector_prepass, deferred_prepass),
+    ) in views.iter_mut()
+    {
+        // Cuboids can't write the deferred G-buffer.
+        if deferred_prepass {
+            prepass_phase = None;
+        }
 
-    for (view, visible_entities, mut opaque_phase) in views.iter_mut() {
         // TODO: add method so we can use this on a vector
         // let range_finder = view.rangefinder3d();
         let inverse_view_matrix = view.transform.compute_matrix().inverse();
         let inverse_view_row_2 = inverse_view_matrix.row(2);
 
-        for &entity in &visible_entities.entities {
-            if let Some(entry) = buffer_cache.entries.get(&entity) {
-                if entry.enabled {
-                    let pipeline = if view.hdr {
-                        cuboids_pipelines.hdr_pipeline_id
-                    } else {
-                        cuboids_pipelines.pipeline_id
-                    };
-                    opaque_phase.add(Opaque3d {
-                        pipeline,
+        let mut queue_entity = |entity: Entity,
+                                entry: &CachedCuboidBuffers,
+                                batched: bool,
+                                gpu_culling: bool,
+                                attributes: bool| {
+            let material = ExtractedCuboidsViewOverrides::material(maybe_overrides, entry);
+            let pipeline = specialized_pipelines.specialize(
+                &pipeline_cache,
+                &cuboids_pipelines,
+                CuboidsPipelineKey {
+                    hdr: view.hdr,
+                    gpu_culling,
+                    batched,
+                    oriented: entry.is_oriented(),
+                    attributes,
+                    transparent: material.transparent,
+                    shadow: false,
+                    depth_clamp_ortho: false,
+                    prepass: false,
+                    normal_prepass: false,
+                    motion_vector_prepass: false,
+                    picking: false,
+                    precise_clipping: material.precise_clipping,
+                },
+            );
+            let distance = inverse_view_row_2.dot(entry.position.extend(1.0));
+            if material.transparent {
+                // Sorted back to front by the phase.
+                transparent_phase.add(Transparent3d {
+                    pipeline,
+                    entity,
+                    distance,
+                    draw_function: draw_transparent_cuboids,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            } else {
+                if let Some(prepass_phase) = prepass_phase.as_mut() {
+                    let pipeline = specialized_pipelines.specialize(
+                        &pipeline_cache,
+                        &cuboids_pipelines,
+                        CuboidsPipelineKey {
+                            hdr: false,
+                            gpu_culling,
+                            batched,
+                            oriented: entry.is_oriented(),
+                            attributes,
+                            transparent: false,
+                            shadow: false,
+                            depth_clamp_ortho: false,
+                            prepass: true,
+                            normal_prepass,
+                            motion_vector_prepass,
+                            picking: false,
+                            precise_clipping: material.precise_clipping,
+                        },
+                    );
+                    prepass_phase.add(Opaque3dPrepass {
+                        pipeline_id: pipeline,
                         entity,
-                        distance: inverse_view_row_2.dot(entry.position.extend(1.0)),
-                        draw_function: draw_cuboids,
+                        distance,
+                        draw_function: draw_prepass_cuboids,
                         batch_range: 0..1,
                         dynamic_offset: None,
                     });
                 }
+                opaque_phase.add(Opaque3d {
+                    pipeline,
+                    entity,
+                    distance,
+                    draw_function: draw_opaque_cuboids,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            }
+        };
+
+        let mut view_batches = ViewBatches::default();
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            if !entry.enabled {
+                continue;
+            }
+            if let Some(key) = entry.batch {
+                batches.add_visible(&mut view_batches, key, entity);
+                continue;
             }
+            // Coarser levels of detail are small enough to skip culling, and
+            // the culling pass can't read attributes. Only the full resolution
+            // instances have attributes.
+            let level = entry.lod.as_ref().map_or(0, |lod| lod.level(view));
+            let attributes = level == 0 && entry.attributes.is_some();
+            let culled = gpu_culling && level == 0 && !attributes;
+            if level > 0 {
+                selected_lods.0.insert((view_entity, entity), level);
+            } else if culled {
+                culling.queued.push((view_entity, entity));
+            }
+            queue_entity(entity, entry, false, culled, attributes);
+        }
+        // Each batch is queued once, with one of its visible members.
+        for entity in batches.finish_view(view_entity, view_batches) {
+            queue_entity(entity, &buffer_cache.entries[&entity], true, false, false);
         }
     }
 }
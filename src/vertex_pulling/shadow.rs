@@ -0,0 +1,130 @@
+use super::batching::{CuboidBatches, ViewBatches};
+use super::cuboid_cache::CuboidBufferCache;
+use super::draw::DrawCuboids;
+use super::lod::SelectedCuboidLods;
+use super::pipeline::{CuboidsPipelineKey, CuboidsPipelines};
+
+use bevy::pbr::{
+    CascadesVisibleEntities, CubemapVisibleEntities, ExtractedDirectionalLight,
+    ExtractedPointLight, LightEntity, Shadow, ViewLightEntities,
+};
+use bevy::prelude::*;
+use bevy::render::render_phase::{DrawFunctions, RenderPhase};
+use bevy::render::render_resource::{PipelineCache, SpecializedRenderPipelines};
+use bevy::render::view::{ExtractedView, VisibleEntities};
+
+/// Queues cuboids into the shadow map of every light visible from each view.
+///
+/// Shadow views are never GPU culled, since the culling pass only runs for
+/// cameras.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub(crate) fn queue_cuboid_shadows(
+    cuboids_pipelines: Res<CuboidsPipelines>,
+    pipeline_cache: Res<PipelineCache>,
+    mut specialized_pipelines: ResMut<SpecializedRenderPipelines<CuboidsPipelines>>,
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut batches: ResMut<CuboidBatches>,
+    mut selected_lods: ResMut<SelectedCuboidLods>,
+    view_lights: Query<(Entity, &ExtractedView, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    point_light_entities: Query<&CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_light_entities: Query<&CascadesVisibleEntities, With<ExtractedDirectionalLight>>,
+    spot_light_entities: Query<&VisibleEntities, With<ExtractedPointLight>>,
+) {
+    let draw_cuboids = shadow_draw_functions
+        .read()
+        .get_id::<DrawCuboids>()
+        .unwrap();
+
+    for (view_entity, view, view_lights) in &view_lights {
+        for &view_light_entity in &view_lights.lights {
+            let Ok((light_entity, mut shadow_phase)) =
+                view_light_shadow_phases.get_mut(view_light_entity)
+            else {
+                continue;
+            };
+            let visible_entities = match light_entity {
+                LightEntity::Directional {
+                    light_entity,
+                    cascade_index,
+                } => directional_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .and_then(|cascades| cascades.entities.get(&view_entity))
+                    .and_then(|cascades| cascades.get(*cascade_index)),
+                LightEntity::Point {
+                    light_entity,
+                    face_index,
+                } => point_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .map(|cubemap| cubemap.get(*face_index)),
+                LightEntity::Spot { light_entity } => spot_light_entities.get(*light_entity).ok(),
+            };
+            let Some(visible_entities) = visible_entities else {
+                continue;
+            };
+
+            let mut specialize = |oriented, batched, attributes| {
+                specialized_pipelines.specialize(
+                    &pipeline_cache,
+                    &cuboids_pipelines,
+                    CuboidsPipelineKey {
+                        hdr: false,
+                        gpu_culling: false,
+                        batched,
+                        oriented,
+                        attributes,
+                        transparent: false,
+                        shadow: true,
+                        depth_clamp_ortho: matches!(light_entity, LightEntity::Directional { .. }),
+                        prepass: false,
+                        normal_prepass: false,
+                        motion_vector_prepass: false,
+                        picking: false,
+                        precise_clipping: false,
+                    },
+                )
+            };
+            let mut add_item = |entity, oriented, batched, attributes| {
+                shadow_phase.add(Shadow {
+                    pipeline: specialize(oriented, batched, attributes),
+                    entity,
+                    distance: 0.0,
+                    draw_function: draw_cuboids,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            };
+
+            // NOTE: Lights with shadow mapping disabled have no visible
+            // entities.
+            let mut view_batches = ViewBatches::default();
+            for &entity in visible_entities.iter() {
+                let Some(entry) = buffer_cache.entries.get(&entity) else {
+                    continue;
+                };
+                if !entry.enabled {
+                    continue;
+                }
+                if let Some(key) = entry.batch {
+                    batches.add_visible(&mut view_batches, key, entity);
+                    continue;
+                }
+                // Shadows use the level of detail of the camera that they're
+                // rendered for.
+                let level = entry.lod.as_ref().map_or(0, |lod| lod.level(view));
+                if level > 0 {
+                    selected_lods.0.insert((view_light_entity, entity), level);
+                }
+                let attributes = level == 0 && entry.attributes.is_some();
+                add_item(entity, entry.is_oriented(), false, attributes);
+            }
+            for entity in batches.finish_view(view_light_entity, view_batches) {
+                let oriented = buffer_cache.entries[&entity].is_oriented();
+                add_item(entity, oriented, true, false);
+            }
+        }
+    }
+}
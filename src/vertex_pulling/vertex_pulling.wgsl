@@ -1,91 +1,30 @@
-#import bevy_render::view::View
-
-fn hsl_to_nonlinear_srgb(hue: f32, saturation: f32, lightness: f32) -> vec3<f32> {
-    // https://en.wikipedia.org/wiki/HSL_and_HSV#HSL_to_RGB
-    let chroma = (1.0 - abs(2.0 * lightness - 1.0)) * saturation;
-    let hue_prime = hue / 60.0;
-    let largest_component = chroma * (1.0 - abs(hue_prime % 2.0 - 1.0));
-    var rgb_temp: vec3<f32>;
-    if (hue_prime < 1.0) {
-        rgb_temp = vec3<f32>(chroma, largest_component, 0.0);
-    } else if (hue_prime < 2.0) {
-        rgb_temp = vec3<f32>(largest_component, chroma, 0.0);
-    } else if (hue_prime < 3.0) {
-        rgb_temp = vec3<f32>(0.0, chroma, largest_component);
-    } else if (hue_prime < 4.0) {
-        rgb_temp = vec3<f32>(0.0, largest_component, chroma);
-    } else if (hue_prime < 5.0) {
-        rgb_temp = vec3<f32>(largest_component, 0.0, chroma);
-    } else {
-        rgb_temp = vec3<f32>(chroma, 0.0, largest_component);
-    }
-    let lightness_match = lightness - chroma / 2.0;
-    return rgb_temp + lightness_match;
+#import bevy_aabb_instancing::common::{
+    view, material, transform, clipping_planes, Cuboids, clipping_groups, clipping_plane_range,
+    cuboid_center, cuboid_color, cuboid_half_extents, cuboid_is_discarded, cuboid_rotation,
+    face_lighting, quat_to_mat3
 }
-
-struct ScalarHueOptions {
-    min_visible: f32,
-    max_visible: f32,
-    clamp_min: f32,
-    clamp_max: f32,
-    hue_zero: f32,
-    hue_slope: f32,
-    lightness: f32,
-    saturation: f32,
-}
-
-struct CuboidMaterial {
-    color_mode: u32,
-    wireframe: u32, // Any nonzero value means "on".
-    _pad0: u32,
-    _pad1: u32,
-    scalar_hue: ScalarHueOptions,
-    emissive_gain: vec3<f32>,
-}
-
-struct ClippingPlaneRange {
-    origin: vec3<f32>,
-    unit_normal: vec3<f32>,
-    min_sdist: f32,
-    max_sdist: f32,
-}
-
-struct ClippingPlaneRanges {
-    ranges: array<ClippingPlaneRange, 16>,
-    num_ranges: u32,
-}
-
-struct Cuboid {
-    min: vec3<f32>,
-    meta_bits: u32,
-    max: vec3<f32>,
-    color: u32,
-}
-
-struct Cuboids {
-    data: array<Cuboid>,
-}
-
-struct Transform {
-    m: mat4x4<f32>,
-    m_inv: mat4x4<f32>,
-}
-
-@group(0) @binding(0)
-var<uniform> view: View;
-
-@group(1) @binding(0)
-var<uniform> material: CuboidMaterial;
-
-@group(1) @binding(1)
-var<uniform> clipping_planes: ClippingPlaneRanges;
-
-@group(2) @binding(0)
-var<uniform> transform: Transform;
+#ifdef BATCHED
+#import bevy_aabb_instancing::common::load_transform
+#endif
+#ifdef ATTRIBUTES
+#import bevy_aabb_instancing::common::load_attributes
+#endif
 
 @group(3) @binding(0)
 var<storage> cuboids: Cuboids;
 
+#ifdef GPU_CULLING
+// Indices of the cuboids that passed the culling compute pass.
+@group(3) @binding(1)
+var<storage> visible_indices: array<u32>;
+#endif
+
+#ifdef BATCHED
+// Transform slot and index within its entity of each instance in the batch.
+@group(3) @binding(1)
+var<storage> instance_refs: array<vec2<u32>>;
+#endif
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec4<f32>,
@@ -93,6 +32,29 @@ struct VertexOutput {
     #ifdef OUTLINES
     @location(1) face_center_to_corner: vec2<f32>,
     #endif
+
+    #ifdef DEPTH_CLAMP_ORTHO
+    @location(2) clip_position_unclamped: vec4<f32>,
+    #endif
+
+    #ifdef NORMAL_PREPASS
+    @location(3) world_normal: vec3<f32>,
+    #endif
+
+    #ifdef PICKING
+    @location(4) @interpolate(flat) picking_id: vec4<u32>,
+    #endif
+
+    #ifdef PRECISE_CLIPPING
+    @location(5) world_position: vec3<f32>,
+    // Position relative to the cuboid's center, in the cuboid's frame.
+    @location(6) box_position: vec3<f32>,
+    @location(7) @interpolate(flat) half_extents: vec3<f32>,
+    @location(8) @interpolate(flat) rotation: vec4<f32>,
+    #ifdef BATCHED
+    @location(9) @interpolate(flat) transform_slot: u32,
+    #endif
+    #endif
 }
 
 fn discard_vertex() -> VertexOutput {
@@ -102,71 +64,59 @@ fn discard_vertex() -> VertexOutput {
     return out;
 }
 
+// World space normal of the visible face perpendicular to the cuboid space `axis`.
+fn face_world_normal(axis: u32, mirror_mask: u32, rotation: mat3x3<f32>) -> vec3<f32> {
+    // The mirror mask flips faces towards the camera.
+    var local_normal = vec3<f32>(0.0);
+    local_normal[axis] = select(-1.0, 1.0, ((mirror_mask >> axis) & 1u) != 0u);
+    let m_inv = mat3x3<f32>(transform.m_inv[0].xyz, transform.m_inv[1].xyz, transform.m_inv[2].xyz);
+    // Multiplying on the right applies the inverse transpose.
+    return normalize((rotation * local_normal) * m_inv);
+}
+
 @vertex
 fn vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> VertexOutput {
     var out: VertexOutput;
 
-    let cuboid = cuboids.data[instance_index];
-
-    // Check visibility mask.
-    if ((cuboid.meta_bits & 0x01u) != 0u) {
+#ifdef GPU_CULLING
+    // Culling already discarded invisible cuboids.
+    let cuboid_index = visible_indices[instance_index];
+    let cuboid = cuboids.data[cuboid_index];
+#else
+    let cuboid_index = instance_index;
+    let cuboid = cuboids.data[cuboid_index];
+#ifdef BATCHED
+    let instance_ref = instance_refs[instance_index];
+    load_transform(instance_ref.x);
+#endif
+#ifdef ATTRIBUTES
+    load_attributes(cuboid_index);
+#endif
+    if cuboid_is_discarded(cuboid) {
         // DISCARD CUBOID
         return discard_vertex();
     }
+#endif
 
-    if (material.color_mode == 1u) {
-        // SCALAR HUE
-        let opt = material.scalar_hue;
+    out.color = cuboid_color(cuboid);
 
-        let scalar = bitcast<f32>(cuboid.color);
-        if (scalar < opt.min_visible ||
-            scalar > opt.max_visible)
-        {
-            // DISCARD CUBOID
-            return discard_vertex();
-        }
+    let center = cuboid_center(cuboid);
+    let rotation = cuboid_rotation(cuboid);
 
-        // HSL
-        let cmin = opt.clamp_min;
-        let cmax = opt.clamp_max;
-        let s = (clamp(scalar, cmin, cmax) - cmin) / (cmax - cmin);
-        let hue = (360.0 + (opt.hue_zero + s * opt.hue_slope)) % 360.0;
-        out.color = vec4<f32>(hsl_to_nonlinear_srgb(hue, opt.saturation, opt.lightness), 1.0);
+    // Need to do this calculation in cuboid space so our offsets are aligned
+    // with the cuboid's faces.
+    var camera_offset: vec3<f32>;
+    if view.projection[3].w == 1.0 {
+        // Orthographic views (e.g. directional light shadows) look in the
+        // same direction from everywhere.
+        camera_offset = (transform.m_inv * vec4<f32>(view.view[2].xyz, 0.0)).xyz;
     } else {
-        // RGB
-        out.color = vec4<f32>(
-            f32(cuboid.color & 0xFFu),
-            f32((cuboid.color >> 8u) & 0xFFu),
-            f32((cuboid.color >> 16u) & 0xFFu),
-            255.0
-        ) / 255.0;
+        let camera_in_cuboid_space_v4 = transform.m_inv * vec4<f32>(view.world_position, 1.0);
+        let camera_in_cuboid_space = camera_in_cuboid_space_v4.xyz / camera_in_cuboid_space_v4.w;
+        camera_offset = camera_in_cuboid_space - center;
     }
-
-    if ((cuboid.meta_bits & 0x02u) != 0u) {
-        out.color *= vec4(material.emissive_gain, 1.0);
-    }
-
-    let cuboid_center = (cuboid.min + cuboid.max) / 2.0;
-
-    if (clipping_planes.num_ranges > 0u) {
-        let tfm_cuboid_center_v4 = transform.m * vec4<f32>(cuboid_center, 1.0);
-        let tfm_cuboid_center = tfm_cuboid_center_v4.xyz / tfm_cuboid_center_v4.w;
-
-        // Clip any cuboid instance that falls out of the allowed ranges.
-        for (var i = 0u; i < clipping_planes.num_ranges; i++) {
-            let range = clipping_planes.ranges[i];
-            let sdist_to_plane = dot(tfm_cuboid_center - range.origin, range.unit_normal);
-            if sdist_to_plane < range.min_sdist || sdist_to_plane > range.max_sdist {
-                // DISCARD CUBOID
-                return discard_vertex();
-            }
-        }
-    }
-
-    // Need to do this calculation in cuboid (model) space so our offsets are grid-aligned.
-    let camera_in_cuboid_space_v4 = transform.m_inv * vec4<f32>(view.world_position, 1.0);
-    let camera_in_cuboid_space = camera_in_cuboid_space_v4.xyz / camera_in_cuboid_space_v4.w;
-    let offset = camera_in_cuboid_space - cuboid_center;
+    // Multiplying on the right applies the inverse rotation.
+    let offset = camera_offset * rotation;
     let mirror_mask =
         u32(offset.x > 0.0) |
         u32(offset.y > 0.0) << 1u |
@@ -178,7 +128,12 @@ fn vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) ins
         f32((visible_vertex_index & 0x2u) >> 1u),
         f32((visible_vertex_index & 0x4u) >> 2u),
     );
+#ifdef ORIENTED
+    let corner_offset = (2.0 * cube_corner - 1.0) * cuboid_half_extents(cuboid);
+    let model_position = center + rotation * corner_offset;
+#else
     let model_position = cube_corner * cuboid.max + (1.0 - cube_corner) * cuboid.min;
+#endif
     let world_position = transform.m * vec4<f32>(model_position, 1.0);
     let ndc_position = view.view_proj * world_position;
 
@@ -190,10 +145,54 @@ fn vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) ins
     let nudge_z = (ndc_position.z / ndc_position.w) * (1.0 - depth_bias);
     out.clip_position.z = nudge_z * ndc_position.w;
 
+#ifdef DEPTH_CLAMP_ORTHO
+    // Clamp shadow casters behind the light's near plane onto it.
+    out.clip_position_unclamped = out.clip_position;
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+#endif
+
+    let face = (vertex_index >> 3u) & 0x3u;
+    // Faces 0, 1 and 2 are perpendicular to Z, Y and X respectively.
+    let axis = 2u - face;
+
+    // Emissive cuboids are not lit.
+    if material.lighting != 0u && (cuboid.meta_bits & 0x02u) == 0u {
+        let world_normal = face_world_normal(axis, mirror_mask, rotation);
+        out.color = vec4<f32>(out.color.rgb * face_lighting(world_normal), out.color.a);
+    }
+
+#ifdef NORMAL_PREPASS
+    out.world_normal = face_world_normal(axis, mirror_mask, rotation);
+#endif
+
+#ifdef PICKING
+    // The last component distinguishes hits from the cleared background.
+#ifdef BATCHED
+    // Relative to the entity rather than the batch.
+    out.picking_id = vec4<u32>(transform.entity, instance_ref.y, 1u);
+#else
+    out.picking_id = vec4<u32>(transform.entity, cuboid_index, 1u);
+#endif
+#endif
+
+#ifdef PRECISE_CLIPPING
+    out.world_position = world_position.xyz / world_position.w;
+    // Multiplying on the right applies the inverse rotation.
+    out.box_position = (model_position - center) * rotation;
+    out.half_extents = cuboid_half_extents(cuboid);
+#ifdef ORIENTED
+    out.rotation = cuboid.rotation;
+#else
+    out.rotation = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+#endif
+#ifdef BATCHED
+    out.transform_slot = instance_ref.x;
+#endif
+#endif
+
     #ifdef OUTLINES
 
     let centroid_to_corner = 2.0 * (cube_corner - vec3<f32>(0.5));
-    let face = (vertex_index >> 3u) & 0x3u;
     if face == 0u {
         out.face_center_to_corner = centroid_to_corner.xy;
     } else if face == 1u {
@@ -214,26 +213,156 @@ struct FragmentInput {
     // "normalized face coordinates" in [-1, 1]^2
     @location(1) face_center_to_fragment: vec2<f32>,
     #endif
+
+    #ifdef NORMAL_PREPASS
+    @location(3) world_normal: vec3<f32>,
+    #endif
+
+    #ifdef PICKING
+    @location(4) @interpolate(flat) picking_id: vec4<u32>,
+    #endif
+
+    #ifdef PRECISE_CLIPPING
+    @builtin(position) position: vec4<f32>,
+    @location(5) world_position: vec3<f32>,
+    @location(6) box_position: vec3<f32>,
+    @location(7) @interpolate(flat) half_extents: vec3<f32>,
+    @location(8) @interpolate(flat) rotation: vec4<f32>,
+    #ifdef BATCHED
+    @location(9) @interpolate(flat) transform_slot: u32,
+    #endif
+    #endif
 }
 
 struct FragmentOutput {
     @location(0) color: vec4<f32>,
+
+    #ifdef PRECISE_CLIPPING
+    @builtin(frag_depth) depth: f32,
+    #endif
 }
 
 // Constant-pixel-width edges:
 // https://catlikecoding.com/unity/tutorials/advanced-rendering/flat-and-wireframe-shading/
+#ifdef OUTLINES
+fn edge_step(face_center_to_fragment: vec2<f32>) -> f32 {
+    let dist_to_edge = vec2<f32>(1.0) - abs(face_center_to_fragment);
+    let screen_derivative = fwidth(face_center_to_fragment);
+    let step = smoothstep(vec2<f32>(0.0), 2.0 * screen_derivative, dist_to_edge);
+    return min(step.x, step.y);
+}
+#endif
+
+#ifdef PRECISE_CLIPPING
+struct ClippedFragment {
+    // Depth of the visible surface at this fragment.
+    depth: f32,
+    // True if the visible surface is a cross-section cut by a clipping plane.
+    is_cap: bool,
+    // World space normal of the cap, facing the camera.
+    cap_normal: vec3<f32>,
+}
+
+// Only the faces towards the camera are rasterized, so this follows the view
+// ray from the fragment through the cuboid to find the part of it that's left
+// by the clipping planes. The fragment is discarded if nothing is left.
+fn clip_fragment(in: FragmentInput) -> ClippedFragment {
+#ifdef BATCHED
+    load_transform(in.transform_slot);
+#endif
+
+    var ray_direction: vec3<f32>;
+    if view.projection[3].w == 1.0 {
+        ray_direction = -view.view[2].xyz;
+    } else {
+        ray_direction = in.world_position - view.world_position;
+    }
+
+    // Find where the ray leaves the cuboid, in units of `ray_direction`.
+    let model_direction = (transform.m_inv * vec4<f32>(ray_direction, 0.0)).xyz;
+    // Multiplying on the right applies the inverse rotation.
+    let box_direction = model_direction * quat_to_mat3(in.rotation);
+    var t_exit = bitcast<f32>(0x7f800000u); // inf
+    for (var axis = 0u; axis < 3u; axis++) {
+        let d = box_direction[axis];
+        if abs(d) > 1e-20 {
+            let face = select(-in.half_extents[axis], in.half_extents[axis], d > 0.0);
+            t_exit = min(t_exit, (face - in.box_position[axis]) / d);
+        }
+    }
+
+    // Intersect that segment with the slab between each pair of planes.
+    var t_enter = 0.0;
+    var cap_normal = vec3<f32>(0.0);
+    for (var i = 0u; i < clipping_planes.num_ranges; i++) {
+        let range = clipping_plane_range(i);
+        if (range.groups & clipping_groups()) == 0u {
+            continue;
+        }
+        let sdist = dot(in.world_position - range.origin, range.unit_normal);
+        let rate = dot(ray_direction, range.unit_normal);
+        if abs(rate) < 1e-20 {
+            if sdist < range.min_sdist || sdist > range.max_sdist {
+                discard;
+            }
+            continue;
+        }
+        let t_min = (range.min_sdist - sdist) / rate;
+        let t_max = (range.max_sdist - sdist) / rate;
+        let t_near = min(t_min, t_max);
+        if t_near > t_enter {
+            t_enter = t_near;
+            cap_normal = select(range.unit_normal, -range.unit_normal, rate > 0.0);
+        }
+        t_exit = min(t_exit, max(t_min, t_max));
+    }
+    if t_enter > t_exit {
+        discard;
+    }
+
+    var out: ClippedFragment;
+    out.is_cap = t_enter > 0.0;
+    out.cap_normal = cap_normal;
+    if out.is_cap {
+        let cap_position = in.world_position + t_enter * ray_direction;
+        let cap_clip_position = view.view_proj * vec4<f32>(cap_position, 1.0);
+        out.depth = cap_clip_position.z / cap_clip_position.w;
+    } else {
+        out.depth = in.position.z;
+    }
+    return out;
+}
+
+fn cap_color(in: FragmentInput, cap_normal: vec3<f32>) -> vec4<f32> {
+    if material.cap_color.a == 0.0 {
+        return in.color;
+    }
+    var color = material.cap_color;
+    if material.lighting != 0u {
+        color = vec4<f32>(color.rgb * face_lighting(cap_normal), color.a);
+    }
+    return color;
+}
+#endif
 
 @fragment
 fn fragment(in: FragmentInput) -> FragmentOutput {
     var out: FragmentOutput;
     out.color = in.color;
 
+    #ifdef PRECISE_CLIPPING
+    let clipped = clip_fragment(in);
+    out.depth = clipped.depth;
+    // Wireframes don't get caps, only their remaining edges.
+    if clipped.is_cap && material.wireframe == 0u {
+        out.color = cap_color(in, clipped.cap_normal);
+        return out;
+    }
+    #endif
+
     #ifdef OUTLINES
 
-    let dist_to_edge = vec2<f32>(1.0) - abs(in.face_center_to_fragment);
-    let screen_derivative = fwidth(in.face_center_to_fragment);
-    let step = smoothstep(vec2<f32>(0.0), 2.0 * screen_derivative, dist_to_edge);
-    let min_step = min(step.x, step.y);
+    let min_step = edge_step(in.face_center_to_fragment);
 
     if material.wireframe != 0u {
         let edge_factor = mix(0.0, 1.0, min_step);
@@ -242,10 +371,101 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
         }
     } else {
         let edge_factor = mix(0.5, 1.0, min_step);
-        out.color *= edge_factor;
+        out.color = vec4<f32>(out.color.rgb * edge_factor, out.color.a);
+    }
+
+    #endif
+
+    return out;
+}
+
+#ifdef DEPTH_CLAMP_ORTHO
+@fragment
+fn fragment_depth(@location(2) clip_position_unclamped: vec4<f32>) -> @builtin(frag_depth) f32 {
+    return clip_position_unclamped.z;
+}
+#endif
+
+#ifdef PREPASS
+#ifdef PREPASS_FRAGMENT_OUTPUT
+struct PrepassOutput {
+    #ifdef NORMAL_PREPASS
+    @location(0) normal: vec4<f32>,
+    #endif
+
+    #ifdef MOTION_VECTOR_PREPASS
+    @location(1) motion_vector: vec2<f32>,
+    #endif
+
+    #ifdef PRECISE_CLIPPING
+    @builtin(frag_depth) depth: f32,
+    #endif
+}
+
+@fragment
+fn fragment_prepass(in: FragmentInput) -> PrepassOutput {
+    var out: PrepassOutput;
+#else
+@fragment
+fn fragment_prepass(in: FragmentInput) {
+#endif
+
+    #ifdef OUTLINES
+    // Wireframes only have depth on their edges.
+    if material.wireframe != 0u && edge_step(in.face_center_to_fragment) > 0.99999 {
+        discard;
+    }
+    #endif
+
+    #ifdef NORMAL_PREPASS
+    out.normal = vec4<f32>(in.world_normal * 0.5 + vec3<f32>(0.5), 1.0);
+    #endif
+
+    #ifdef PRECISE_CLIPPING
+    let clipped = clip_fragment(in);
+    out.depth = clipped.depth;
+    #ifdef NORMAL_PREPASS
+    if clipped.is_cap {
+        out.normal = vec4<f32>(clipped.cap_normal * 0.5 + vec3<f32>(0.5), 1.0);
     }
+    #endif
+    #endif
+
+    #ifdef MOTION_VECTOR_PREPASS
+    // Cuboids are assumed to be static.
+    out.motion_vector = vec2<f32>(0.0);
+    #endif
+
+#ifdef PREPASS_FRAGMENT_OUTPUT
+    return out;
+#endif
+}
+#endif
+
+#ifdef PICKING
+struct PickingOutput {
+    @location(0) id: vec4<u32>,
+
+    #ifdef PRECISE_CLIPPING
+    @builtin(frag_depth) depth: f32,
+    #endif
+}
+
+@fragment
+fn fragment_picking(in: FragmentInput) -> PickingOutput {
+    var out: PickingOutput;
+
+    #ifdef OUTLINES
+    if material.wireframe != 0u && edge_step(in.face_center_to_fragment) > 0.99999 {
+        discard;
+    }
+    #endif
 
+    #ifdef PRECISE_CLIPPING
+    out.depth = clip_fragment(in).depth;
     #endif
 
+    out.id = in.picking_id;
     return out;
 }
+#endif
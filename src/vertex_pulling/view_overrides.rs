@@ -0,0 +1,95 @@
+use super::buffers::DynamicUniformBufferOfViewClippingGroups;
+use super::cuboid_cache::{CachedCuboidBuffers, CachedCuboidMaterial};
+use crate::clipping_planes::GpuViewClippingGroups;
+use crate::{CuboidMaterial, CuboidsViewOverrides};
+
+use bevy::{
+    prelude::*,
+    render::{
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::HashMap,
+};
+
+/// The cached state of every material.
+#[derive(Default, Resource)]
+pub(crate) struct ExtractedCuboidMaterials(
+    pub HashMap<AssetId<CuboidMaterial>, CachedCuboidMaterial>,
+);
+
+/// The [`CuboidsViewOverrides`] of a camera.
+#[derive(Component)]
+pub(crate) struct ExtractedCuboidsViewOverrides {
+    /// Replacements for the materials of entities, by their uniform index.
+    pub materials: HashMap<u32, CachedCuboidMaterial>,
+    pub clipping_groups: u32,
+    /// Dynamic offset into [`DynamicUniformBufferOfViewClippingGroups`].
+    pub clipping_groups_index: u32,
+}
+
+impl ExtractedCuboidsViewOverrides {
+    /// Returns the material that `entry` is drawn with in a view with these
+    /// overrides.
+    pub fn material(overrides: Option<&Self>, entry: &CachedCuboidBuffers) -> CachedCuboidMaterial {
+        overrides
+            .and_then(|overrides| overrides.materials.get(&entry.material.uniform_index))
+            .copied()
+            .unwrap_or(entry.material)
+    }
+
+    /// Returns the dynamic offsets of the auxiliary bind group for drawing
+    /// `entry` in a view with these overrides.
+    pub fn aux_offsets(overrides: Option<&Self>, entry: &CachedCuboidBuffers) -> [u32; 2] {
+        [
+            Self::material(overrides, entry).uniform_index,
+            // Views without overrides use the first entry.
+            overrides.map_or(0, |overrides| overrides.clipping_groups_index),
+        ]
+    }
+}
+
+pub(crate) fn extract_cuboids_view_overrides(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera, &CuboidsViewOverrides)>>,
+    materials: Res<ExtractedCuboidMaterials>,
+) {
+    for (entity, camera, overrides) in cameras.iter() {
+        if !camera.is_active {
+            continue;
+        }
+        // Ignore materials that don't exist.
+        let materials = overrides
+            .materials
+            .iter()
+            .filter_map(|(from, to)| {
+                let from = materials.0.get(from)?;
+                let to = materials.0.get(&to.id())?;
+                Some((from.uniform_index, *to))
+            })
+            .collect();
+        commands
+            .get_or_spawn(entity)
+            .insert(ExtractedCuboidsViewOverrides {
+                materials,
+                clipping_groups: overrides.clipping_groups.bits(),
+                clipping_groups_index: 0,
+            });
+    }
+}
+
+pub(crate) fn prepare_view_clipping_groups(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut views: Query<&mut ExtractedCuboidsViewOverrides>,
+    mut clipping_groups_uniforms: ResMut<DynamicUniformBufferOfViewClippingGroups>,
+) {
+    clipping_groups_uniforms.clear();
+    clipping_groups_uniforms.push(GpuViewClippingGroups::default());
+    for mut overrides in &mut views {
+        overrides.clipping_groups_index = clipping_groups_uniforms.push(GpuViewClippingGroups {
+            groups: overrides.clipping_groups,
+        });
+    }
+    clipping_groups_uniforms.write_buffer(&render_device, &render_queue);
+}
@@ -0,0 +1,59 @@
+use crate::{ClippingGroups, CuboidMaterial};
+use bevy::{prelude::*, utils::HashMap};
+
+/// Changes how a single camera renders [`Cuboids`](crate::Cuboids) and
+/// [`OrientedCuboids`](crate::OrientedCuboids), e.g. for a plan view minimap or
+/// a section view next to the main view.
+///
+/// Which entities a camera renders at all is controlled by Bevy's
+/// [`RenderLayers`](bevy::render::view::RenderLayers) on the camera and on the
+/// cuboid entities.
+///
+/// Only applies to the camera's main passes, prepass and GPU picking. Shadow
+/// maps are shared by all cameras, so they are rendered without overrides.
+#[derive(Clone, Component, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CuboidsViewOverrides {
+    /// Entities whose material is a key are drawn with the mapped material
+    /// instead.
+    ///
+    /// Material handles can't be serialized, so this is left out with the
+    /// `serde` feature.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub materials: HashMap<AssetId<CuboidMaterial>, Handle<CuboidMaterial>>,
+    /// Only clipping volumes in any of these groups apply in this view, in
+    /// addition to the [`ClippingGroups`] of each entity. Defaults to all
+    /// groups.
+    pub clipping_groups: ClippingGroups,
+}
+
+impl Default for CuboidsViewOverrides {
+    fn default() -> Self {
+        Self {
+            materials: default(),
+            clipping_groups: ClippingGroups::all(),
+        }
+    }
+}
+
+impl CuboidsViewOverrides {
+    /// Draws entities with material `from` using material `to` instead.
+    pub fn with_material(
+        mut self,
+        from: impl Into<AssetId<CuboidMaterial>>,
+        to: Handle<CuboidMaterial>,
+    ) -> Self {
+        self.materials.insert(from.into(), to);
+        self
+    }
+
+    pub fn with_clipping_groups(mut self, clipping_groups: ClippingGroups) -> Self {
+        self.clipping_groups = clipping_groups;
+        self
+    }
+
+    /// Returns the material that entities with material `id` are drawn with.
+    pub fn material(&self, id: AssetId<CuboidMaterial>) -> AssetId<CuboidMaterial> {
+        self.materials.get(&id).map_or(id, Handle::id)
+    }
+}